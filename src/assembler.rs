@@ -0,0 +1,949 @@
+use crate::disasm::ORIGIN;
+use crate::instruction::Instruction;
+use crate::symbols::SymbolMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// How deep includes and constants can nest before it's assumed they refer to each other
+const MAX_DEPTH: usize = 16;
+
+/// Why a source couldn't be assembled, with the position of the offending token.
+/// Lines and columns start at 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles the source into a ROM that loads at 0x200.
+/// Includes are looked up relative to the working directory
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_with_symbols(source).map(|(rom, _)| rom)
+}
+
+/// Assembles the source like `assemble`, along with the labels and lines of the program
+pub fn assemble_with_symbols(source: &str) -> Result<(Vec<u8>, SymbolMap), AsmError> {
+    let mut lines = Vec::new();
+    read_lines(source, None, Path::new("."), 0, &mut lines)?;
+    let assembler = Assembler::new(&lines)?;
+    Ok((assembler.run()?, assembler.symbols()))
+}
+
+/// Assembles the file, includes are looked up relative to the file including them
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    assemble_file_with_symbols(path).map(|(rom, _)| rom)
+}
+
+/// Assembles the file like `assemble_file`, along with the labels and lines of the program
+pub fn assemble_file_with_symbols(path: &Path) -> Result<(Vec<u8>, SymbolMap), AsmError> {
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        file: Some(path.display().to_string()),
+        line: 0,
+        column: 0,
+        message: format!("can't read the file: {}", err),
+    })?;
+    let mut lines = Vec::new();
+    let dir = path.parent().unwrap_or(Path::new("."));
+    read_lines(
+        &source,
+        Some(Rc::from(path.display().to_string())),
+        dir,
+        0,
+        &mut lines,
+    )?;
+    let assembler = Assembler::new(&lines)?;
+    Ok((assembler.run()?, assembler.symbols()))
+}
+
+// A line of source with the file it came from
+struct SourceLine {
+    file: Option<Rc<str>>,
+    number: usize,
+    text: String,
+}
+
+impl SourceLine {
+    fn error(&self, token: Token, message: &str) -> AsmError {
+        AsmError {
+            file: self.file.as_ref().map(|file| file.to_string()),
+            line: self.number,
+            column: token.column,
+            message: message.to_string(),
+        }
+    }
+
+    // Splits the line into its labels, the mnemonic or directive and the comma separated operands
+    fn parse(&self) -> Result<Line<'_>, AsmError> {
+        let text = self.text.as_str();
+        let mut line = Line {
+            labels: Vec::new(),
+            head: None,
+            operands: Vec::new(),
+        };
+        let mut rest = Token {
+            text: strip_comment(text),
+            column: 1,
+        };
+        loop {
+            rest = rest.trim();
+            if rest.text.is_empty() {
+                return Ok(line);
+            }
+            let (word, after) = rest.split_word();
+            match word.text.strip_suffix(':') {
+                Some(label) => line.labels.push(Token {
+                    text: label,
+                    column: word.column,
+                }),
+                None => {
+                    line.head = Some(word);
+                    rest = after.trim();
+                    break;
+                }
+            }
+            rest = after;
+        }
+        if rest.text.is_empty() {
+            return Ok(line);
+        }
+        // Splits at the commas outside of strings and parentheses
+        let mut depth = 0;
+        let mut quoted = false;
+        let mut start = 0;
+        for (i, c) in rest.text.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => depth -= 1,
+                ',' if !quoted && depth == 0 => {
+                    line.operands.push(self.operand(rest.slice(start, i))?);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        if quoted {
+            return Err(self.error(rest, "unterminated string"));
+        }
+        line.operands
+            .push(self.operand(rest.slice(start, rest.text.len()))?);
+        Ok(line)
+    }
+
+    fn operand<'a>(&self, token: Token<'a>) -> Result<Token<'a>, AsmError> {
+        let token = token.trim();
+        if token.text.is_empty() {
+            return Err(self.error(token, "missing operand"));
+        }
+        Ok(token)
+    }
+}
+
+// The part of a line that isn't a comment, semicolons in strings don't start one
+fn strip_comment(text: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+// A piece of a line and the column it starts at
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+impl<'a> Token<'a> {
+    fn slice(&self, start: usize, end: usize) -> Token<'a> {
+        Token {
+            text: &self.text[start..end],
+            column: self.column + start,
+        }
+    }
+
+    fn trim(&self) -> Token<'a> {
+        let start = self.text.len() - self.text.trim_start().len();
+        let end = self.text.trim_end().len();
+        self.slice(start, end.max(start))
+    }
+
+    // The first whitespace separated word and what follows it
+    fn split_word(&self) -> (Token<'a>, Token<'a>) {
+        let end = self
+            .text
+            .find(char::is_whitespace)
+            .unwrap_or(self.text.len());
+        (self.slice(0, end), self.slice(end, self.text.len()))
+    }
+}
+
+struct Line<'a> {
+    labels: Vec<Token<'a>>,
+    head: Option<Token<'a>>,
+    operands: Vec<Token<'a>>,
+}
+
+// Reads the lines of the source, replacing the includes with the lines of the included files
+fn read_lines(
+    source: &str,
+    file: Option<Rc<str>>,
+    dir: &Path,
+    depth: usize,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), AsmError> {
+    for (i, text) in source.lines().enumerate() {
+        let line = SourceLine {
+            file: file.clone(),
+            number: i + 1,
+            text: text.to_string(),
+        };
+        let parsed = line.parse()?;
+        let head = match parsed.head {
+            Some(head) if head.text.eq_ignore_ascii_case("include") => head,
+            _ => {
+                lines.push(line);
+                continue;
+            }
+        };
+        let path = match parsed.operands[..] {
+            [path] if is_string(path.text) => dir.join(&path.text[1..path.text.len() - 1]),
+            _ => return Err(line.error(head, "include expects a quoted path")),
+        };
+        if depth == MAX_DEPTH {
+            return Err(line.error(head, "includes nested too deep"));
+        }
+        let included = fs::read_to_string(&path).map_err(|err| {
+            let message = format!("can't read {}: {}", path.display(), err);
+            line.error(parsed.operands[0], &message)
+        })?;
+        // Labels in front of the include stay behind on their own line
+        let labels = line.text[..head.column - 1].to_string();
+        let parent = path.parent().map(PathBuf::from).unwrap_or_default();
+        lines.push(SourceLine {
+            text: labels,
+            ..line
+        });
+        let file = Some(Rc::from(path.display().to_string()));
+        read_lines(&included, file, &parent, depth + 1, lines)?;
+    }
+    Ok(())
+}
+
+#[inline]
+fn is_string(text: &str) -> bool {
+    text.len() >= 2 && text.starts_with('"') && text.ends_with('"')
+}
+
+#[inline]
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Returns the number of the register V0..VF
+fn register(text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V' | 'v'), Some(digit), None) => digit.to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+// The address of a LONG operand
+fn long_operand(token: Token) -> Option<Token> {
+    let (keyword, address) = token.split_word();
+    if keyword.text.eq_ignore_ascii_case("long") {
+        Some(address.trim())
+    } else {
+        None
+    }
+}
+
+enum Statement<'a> {
+    Instruction {
+        mnemonic: Token<'a>,
+        operands: Vec<Token<'a>>,
+    },
+    Bytes(Vec<Token<'a>>),
+    Words(Vec<Token<'a>>),
+}
+
+enum Symbol<'a> {
+    Label(u16),
+    // Constants are evaluated where they're used, so they can refer to labels defined later
+    Constant(Token<'a>, &'a SourceLine),
+}
+
+struct Assembler<'a> {
+    // Every statement with its line and the address it starts at
+    statements: Vec<(&'a SourceLine, u16, Statement<'a>)>,
+    symbols: HashMap<&'a str, Symbol<'a>>,
+}
+
+impl<'a> Assembler<'a> {
+    // The first pass, lays out the statements and collects the symbols
+    fn new(lines: &'a [SourceLine]) -> Result<Self, AsmError> {
+        let mut assembler = Assembler {
+            statements: Vec::new(),
+            symbols: HashMap::new(),
+        };
+        let mut address = ORIGIN as usize;
+        for line in lines {
+            let parsed = line.parse()?;
+            for label in parsed.labels {
+                if address > 0xFFFF {
+                    return Err(line.error(label, "label past the end of memory"));
+                }
+                assembler.define(line, label, Symbol::Label(address as u16))?;
+            }
+            let head = match parsed.head {
+                Some(head) => head,
+                None => continue,
+            };
+            let operands = parsed.operands;
+            // NAME = expression and NAME equ expression
+            if let [operand] = operands[..] {
+                let (keyword, value) = operand.split_word();
+                let value = if keyword.text.eq_ignore_ascii_case("equ") {
+                    Some(value)
+                } else if operand.text.starts_with('=') {
+                    Some(operand.slice(1, operand.text.len()))
+                } else {
+                    None
+                };
+                if let Some(value) = value {
+                    let value = line.operand(value)?;
+                    assembler.define(line, head, Symbol::Constant(value, line))?;
+                    continue;
+                }
+            }
+            let (statement, size) = match head.text.to_ascii_lowercase().as_str() {
+                "db" => {
+                    let size = operands
+                        .iter()
+                        .map(|op| string_bytes(op.text).map_or(1, |bytes| bytes.len()))
+                        .sum();
+                    (Statement::Bytes(operands), size)
+                }
+                "dw" => (Statement::Words(operands.clone()), operands.len() * 2),
+                _ => {
+                    let long = operands.iter().any(|op| long_operand(*op).is_some());
+                    let statement = Statement::Instruction {
+                        mnemonic: head,
+                        operands,
+                    };
+                    (statement, if long { 4 } else { 2 })
+                }
+            };
+            if address + size > 0x10000 {
+                return Err(line.error(head, "the program doesn't fit in memory"));
+            }
+            assembler.statements.push((line, address as u16, statement));
+            address += size;
+        }
+        Ok(assembler)
+    }
+
+    fn define(
+        &mut self,
+        line: &SourceLine,
+        name: Token<'a>,
+        symbol: Symbol<'a>,
+    ) -> Result<(), AsmError> {
+        if !is_identifier(name.text) || register(name.text).is_some() {
+            return Err(line.error(name, &format!("invalid symbol name '{}'", name.text)));
+        }
+        if self.symbols.insert(name.text, symbol).is_some() {
+            return Err(line.error(name, &format!("'{}' is already defined", name.text)));
+        }
+        Ok(())
+    }
+
+    // The second pass, now that every symbol is known
+    fn run(&self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+        for (line, address, statement) in &self.statements {
+            let context = Context {
+                assembler: self,
+                line,
+                address: *address,
+                depth: 0,
+            };
+            match statement {
+                Statement::Bytes(operands) => {
+                    for operand in operands {
+                        match string_bytes(operand.text) {
+                            Some(bytes) => rom.extend_from_slice(&bytes),
+                            None => rom.push(context.byte(*operand)?),
+                        }
+                    }
+                }
+                Statement::Words(operands) => {
+                    for operand in operands {
+                        let word = context.ranged(*operand, -0x8000, 0xFFFF, "word")?;
+                        rom.extend_from_slice(&(word as u16).to_be_bytes());
+                    }
+                }
+                Statement::Instruction { mnemonic, operands } => {
+                    let (instruction, long) = context.instruction(*mnemonic, operands)?;
+                    rom.extend_from_slice(&instruction.encode().to_be_bytes());
+                    if let Some(long) = long {
+                        rom.extend_from_slice(&long.to_be_bytes());
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+}
+
+impl Assembler<'_> {
+    fn symbols(&self) -> SymbolMap {
+        let mut map = SymbolMap::new();
+        for (name, symbol) in &self.symbols {
+            if let Symbol::Label(address) = symbol {
+                map.add_label(name, *address);
+            }
+        }
+        for (line, address, _) in &self.statements {
+            map.add_line(*address, line.file.as_deref(), line.number);
+        }
+        map
+    }
+}
+
+fn string_bytes(text: &str) -> Option<Vec<u8>> {
+    if is_string(text) {
+        Some(text[1..text.len() - 1].bytes().collect())
+    } else {
+        None
+    }
+}
+
+// Everything needed to evaluate the operands of a statement
+#[derive(Clone, Copy)]
+struct Context<'a, 'b> {
+    assembler: &'b Assembler<'a>,
+    line: &'b SourceLine,
+    address: u16,
+    // How many constants deep the evaluation is, to catch constants defined by each other
+    depth: usize,
+}
+
+impl Context<'_, '_> {
+    fn error(&self, token: Token, message: &str) -> AsmError {
+        self.line.error(token, message)
+    }
+
+    fn instruction(
+        &self,
+        mnemonic: Token,
+        operands: &[Token],
+    ) -> Result<(Instruction, Option<u16>), AsmError> {
+        let name = mnemonic.text.to_ascii_uppercase();
+        let expect = |count: usize| {
+            if operands.len() == count {
+                Ok(())
+            } else {
+                let message = format!("{} expects {} operand(s)", name, count);
+                Err(self.error(mnemonic, &message))
+            }
+        };
+        let op = |i: usize| operands[i].text.to_ascii_uppercase();
+        let vx = |i: usize| self.register(operands[i]);
+        let instruction = match name.as_str() {
+            "CLS" | "RET" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "AUDIO" => {
+                expect(0)?;
+                match name.as_str() {
+                    "CLS" => Instruction::Cls,
+                    "RET" => Instruction::Ret,
+                    "SCR" => Instruction::Scr,
+                    "SCL" => Instruction::Scl,
+                    "EXIT" => Instruction::Exit,
+                    "LOW" => Instruction::Low,
+                    "HIGH" => Instruction::High,
+                    _ => Instruction::Audio,
+                }
+            }
+            "SCD" | "SCU" | "PLANE" => {
+                expect(1)?;
+                let n = self.nibble(operands[0])?;
+                match name.as_str() {
+                    "SCD" => Instruction::Scd(n),
+                    "SCU" => Instruction::Scu(n),
+                    _ => Instruction::Plane(n),
+                }
+            }
+            "CALL" => {
+                expect(1)?;
+                Instruction::Call(self.address(operands[0])?)
+            }
+            "JP" if operands.len() == 2 => {
+                if register(operands[0].text) != Some(0) {
+                    return Err(self.error(operands[0], "expected V0"));
+                }
+                Instruction::JpV0(self.address(operands[1])?)
+            }
+            "JP" => {
+                expect(1)?;
+                Instruction::Jp(self.address(operands[0])?)
+            }
+            "SE" | "SNE" => {
+                expect(2)?;
+                let x = vx(0)?;
+                match (register(operands[1].text), name == "SE") {
+                    (Some(y), true) => Instruction::SeVxVy { x, y },
+                    (Some(y), false) => Instruction::SneVxVy { x, y },
+                    (None, true) => Instruction::SeVxByte {
+                        x,
+                        byte: self.byte(operands[1])?,
+                    },
+                    (None, false) => Instruction::SneVxByte {
+                        x,
+                        byte: self.byte(operands[1])?,
+                    },
+                }
+            }
+            "SAVE" | "LOAD" => {
+                expect(2)?;
+                let (x, y) = (vx(0)?, vx(1)?);
+                if name == "SAVE" {
+                    Instruction::Save { x, y }
+                } else {
+                    Instruction::Load { x, y }
+                }
+            }
+            "OR" | "AND" | "XOR" | "SUB" | "SUBN" | "SHR" | "SHL" => {
+                // The shifts can leave out VY to shift VX in place
+                let shift = name.starts_with("SH") && operands.len() == 1;
+                if !shift {
+                    expect(2)?;
+                }
+                let x = vx(0)?;
+                let y = if shift { x } else { vx(1)? };
+                match name.as_str() {
+                    "OR" => Instruction::Or { x, y },
+                    "AND" => Instruction::And { x, y },
+                    "XOR" => Instruction::Xor { x, y },
+                    "SUB" => Instruction::Sub { x, y },
+                    "SUBN" => Instruction::Subn { x, y },
+                    "SHR" => Instruction::Shr { x, y },
+                    _ => Instruction::Shl { x, y },
+                }
+            }
+            "RND" => {
+                expect(2)?;
+                Instruction::Rnd {
+                    x: vx(0)?,
+                    byte: self.byte(operands[1])?,
+                }
+            }
+            "DRW" => {
+                expect(3)?;
+                Instruction::Drw {
+                    x: vx(0)?,
+                    y: vx(1)?,
+                    n: self.nibble(operands[2])?,
+                }
+            }
+            "SKP" | "SKNP" | "PITCH" => {
+                expect(1)?;
+                let x = vx(0)?;
+                match name.as_str() {
+                    "SKP" => Instruction::Skp(x),
+                    "SKNP" => Instruction::Sknp(x),
+                    _ => Instruction::Pitch(x),
+                }
+            }
+            "ADD" => {
+                expect(2)?;
+                if op(0) == "I" {
+                    Instruction::AddIVx(vx(1)?)
+                } else {
+                    let x = vx(0)?;
+                    match register(operands[1].text) {
+                        Some(y) => Instruction::AddVxVy { x, y },
+                        None => Instruction::AddVxByte {
+                            x,
+                            byte: self.byte(operands[1])?,
+                        },
+                    }
+                }
+            }
+            "LD" => {
+                expect(2)?;
+                match (op(0).as_str(), op(1).as_str()) {
+                    ("I", _) => match long_operand(operands[1]) {
+                        Some(address) => {
+                            let long = self.ranged(address, 0, 0xFFFF, "address")?;
+                            return Ok((Instruction::LdILong, Some(long as u16)));
+                        }
+                        None => Instruction::LdI(self.address(operands[1])?),
+                    },
+                    ("DT", _) => Instruction::LdDtVx(vx(1)?),
+                    ("ST", _) => Instruction::LdStVx(vx(1)?),
+                    ("F", _) => Instruction::LdFVx(vx(1)?),
+                    ("HF", _) => Instruction::LdHfVx(vx(1)?),
+                    ("B", _) => Instruction::LdBVx(vx(1)?),
+                    ("[I]", _) => Instruction::LdIVx(vx(1)?),
+                    ("R", _) => Instruction::LdRVx(vx(1)?),
+                    (_, "DT") => Instruction::LdVxDt(vx(0)?),
+                    (_, "K") => Instruction::LdVxK(vx(0)?),
+                    (_, "[I]") => Instruction::LdVxI(vx(0)?),
+                    (_, "R") => Instruction::LdVxR(vx(0)?),
+                    _ => {
+                        let x = vx(0)?;
+                        match register(operands[1].text) {
+                            Some(y) => Instruction::LdVxVy { x, y },
+                            None => Instruction::LdVxByte {
+                                x,
+                                byte: self.byte(operands[1])?,
+                            },
+                        }
+                    }
+                }
+            }
+            _ => {
+                let message = format!("unknown instruction '{}'", mnemonic.text);
+                return Err(self.error(mnemonic, &message));
+            }
+        };
+        Ok((instruction, None))
+    }
+
+    fn register(&self, token: Token) -> Result<usize, AsmError> {
+        register(token.text).ok_or_else(|| self.error(token, "expected a register V0..VF"))
+    }
+
+    fn address(&self, token: Token) -> Result<u16, AsmError> {
+        Ok(self.ranged(token, 0, 0xFFF, "address")? as u16)
+    }
+
+    // Bytes can be written as negative numbers too
+    fn byte(&self, token: Token) -> Result<u8, AsmError> {
+        Ok(self.ranged(token, -0x80, 0xFF, "byte")? as u8)
+    }
+
+    fn nibble(&self, token: Token) -> Result<u8, AsmError> {
+        Ok(self.ranged(token, 0, 0xF, "nibble")? as u8)
+    }
+
+    fn ranged(&self, token: Token, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        let value = self.evaluate(token)?;
+        if value < min || value > max {
+            let message = format!("{} {} is out of range", what, value);
+            return Err(self.error(token, &message));
+        }
+        Ok(value)
+    }
+
+    fn evaluate(&self, token: Token) -> Result<i64, AsmError> {
+        let mut parser = Parser {
+            context: *self,
+            token,
+            position: 0,
+        };
+        let value = parser.expression(0)?;
+        parser.skip_whitespace();
+        if parser.position < token.text.len() {
+            return Err(parser.error("unexpected character in expression"));
+        }
+        Ok(value)
+    }
+
+    fn symbol(&self, token: Token) -> Result<i64, AsmError> {
+        if token.text == "$" {
+            return Ok(self.address as i64);
+        }
+        match self.assembler.symbols.get(token.text) {
+            Some(Symbol::Label(address)) => Ok(*address as i64),
+            Some(Symbol::Constant(value, line)) => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error(token, "constant defined in terms of itself"));
+                }
+                // Errors inside the constant point at its definition
+                let context = Context {
+                    line,
+                    depth: self.depth + 1,
+                    ..*self
+                };
+                context.evaluate(*value)
+            }
+            None => Err(self.error(token, &format!("undefined symbol '{}'", token.text))),
+        }
+    }
+}
+
+// Binary operators from the loosest to the tightest binding
+const OPERATORS: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// Evaluates an expression by precedence climbing
+struct Parser<'a, 'b, 'c> {
+    context: Context<'a, 'b>,
+    token: Token<'c>,
+    position: usize,
+}
+
+impl Parser<'_, '_, '_> {
+    fn error(&self, message: &str) -> AsmError {
+        let token = Token {
+            column: self.token.column + self.position,
+            ..self.token
+        };
+        self.context.error(token, message)
+    }
+
+    fn rest(&self) -> &str {
+        &self.token.text[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    fn expression(&mut self, level: usize) -> Result<i64, AsmError> {
+        if level == OPERATORS.len() {
+            return self.unary();
+        }
+        let mut value = self.expression(level + 1)?;
+        loop {
+            self.skip_whitespace();
+            let operator = match OPERATORS[level]
+                .iter()
+                .find(|op| self.rest().starts_with(**op))
+            {
+                Some(operator) => *operator,
+                None => return Ok(value),
+            };
+            let start = self.position;
+            self.position += operator.len();
+            let rhs = self.expression(level + 1)?;
+            value = match operator {
+                "|" => value | rhs,
+                "^" => value ^ rhs,
+                "&" => value & rhs,
+                "<<" => value.wrapping_shl(rhs as u32),
+                ">>" => value.wrapping_shr(rhs as u32),
+                "+" => value.wrapping_add(rhs),
+                "-" => value.wrapping_sub(rhs),
+                "*" => value.wrapping_mul(rhs),
+                _ => {
+                    let quotient = match operator {
+                        "/" => value.checked_div(rhs),
+                        _ => value.checked_rem(rhs),
+                    };
+                    match quotient {
+                        Some(quotient) => quotient,
+                        None => {
+                            self.position = start;
+                            let message = if rhs == 0 {
+                                "division by zero"
+                            } else {
+                                "division overflows"
+                            };
+                            return Err(self.error(message));
+                        }
+                    }
+                }
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, AsmError> {
+        self.skip_whitespace();
+        match self.rest().chars().next() {
+            Some('-') => {
+                self.position += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            Some('~') => {
+                self.position += 1;
+                Ok(!self.unary()?)
+            }
+            Some('(') => {
+                self.position += 1;
+                let value = self.expression(0)?;
+                self.skip_whitespace();
+                if !self.rest().starts_with(')') {
+                    return Err(self.error("expected ')'"));
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            Some(_) => self.atom(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    // A number, a symbol or $ for the address of the statement
+    fn atom(&mut self) -> Result<i64, AsmError> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.error("expected a value"));
+        }
+        let word = Token {
+            text: &rest[..len],
+            column: self.token.column + self.position,
+        };
+        let lower = word.text.to_ascii_lowercase();
+        let number = if let Some(hex) = lower.strip_prefix("0x") {
+            Some(i64::from_str_radix(hex, 16))
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            Some(i64::from_str_radix(binary, 2))
+        } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+            Some(lower.parse())
+        } else {
+            None
+        };
+        let value = match number {
+            Some(Ok(value)) => value,
+            Some(Err(_)) => {
+                let message = format!("invalid number '{}'", word.text);
+                return Err(self.context.error(word, &message));
+            }
+            None => self.context.symbol(word)?,
+        };
+        self.position += len;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    #[test]
+    fn instructions() {
+        let source = "
+            start:  CLS
+                    LD V1, 0x10     ; a comment
+                    ld va, vb
+                    LD I, LONG end
+                    DRW V0, V1, 5
+                    SHR V3
+                    JP V0, start
+                    LD [I], V3
+            end:    JP start
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            [
+                0x00, 0xE0, 0x61, 0x10, 0x8A, 0xB0, 0xF0, 0x00, 0x02, 0x12, 0xD0, 0x15, 0x83, 0x36,
+                0xB2, 0x00, 0xF3, 0x55, 0x12, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn data_and_expressions() {
+        let source = "
+            WIDTH = 8
+            HEIGHT equ WIDTH * 2 - (1 << 2)
+                    LD V0, HEIGHT | 0x80
+                    LD V1, -1
+                    LD I, sprite + 1
+            sprite: db 0b11110000, \"AB\", WIDTH % 3
+                    dw $, 0x1234
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            [0x60, 0x8C, 0x61, 0xFF, 0xA2, 0x07, 0xF0, 0x41, 0x42, 0x02, 0x02, 0x0A, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| {
+            let error = assemble(source).unwrap_err();
+            (error.line, error.column, error.message)
+        };
+        assert_eq!(
+            error("CLS\n  JP nowhere"),
+            (2, 6, "undefined symbol 'nowhere'".to_string())
+        );
+        assert_eq!(
+            error("LD V0, 0x100"),
+            (1, 8, "byte 256 is out of range".to_string())
+        );
+        assert_eq!(error("  FOO V0").1, 3);
+        assert_eq!(error("DRW V0, VG, 1").1, 9);
+        assert_eq!(error("a: CLS\na: CLS").0, 2);
+        assert_eq!(
+            error("A = B\nB = A\nLD V0, A").2,
+            "constant defined in terms of itself"
+        );
+        assert_eq!(error("LD V0, (1 + 2").1, 14);
+        assert_eq!(error("LD V0, 1 / 0").1, 10);
+        assert_eq!(error("LD V0, (1 << 63) / -1").2, "division overflows");
+        assert_eq!(error("LD V0, (1 << 63) % -1").2, "division overflows");
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("chip8-asm-includes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.asm"), "JP data\ndata: include \"data.asm\"").unwrap();
+        fs::write(dir.join("data.asm"), "db 1, 2\nLD I, data").unwrap();
+        let (rom, symbols) = assemble_file_with_symbols(&dir.join("main.asm")).unwrap();
+        assert_eq!(rom, [0x12, 0x02, 0x01, 0x02, 0xA2, 0x02]);
+        assert_eq!(symbols.label("data"), Some(0x202));
+        let location = symbols.location(0x204).unwrap();
+        assert!(location.file.as_ref().unwrap().ends_with("data.asm"));
+        assert_eq!(location.line, 2);
+        fs::write(dir.join("data.asm"), "db 1, 2\nLD I, nothing").unwrap();
+        let error = assemble_file(&dir.join("main.asm")).unwrap_err();
+        assert!(error.file.unwrap().ends_with("data.asm"));
+        assert_eq!((error.line, error.column), (2, 7));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        for entry in fs::read_dir("roms").unwrap() {
+            let rom = fs::read(entry.unwrap().path()).unwrap();
+            assert_eq!(assemble(&disassemble(&rom)).unwrap(), rom);
+        }
+        // Bytes that don't decode and code running into data
+        let rom = [
+            0x30, 0x00, 0x01, 0x23, 0xF0, 0x00, 0x02, 0x00, 0xB2, 0x00, 0xFF,
+        ];
+        assert_eq!(assemble(&disassemble(&rom)).unwrap(), rom);
+    }
+}
@@ -0,0 +1,41 @@
+use chip_8::assembler::assemble_file_with_symbols;
+use chip_8::symbols;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+// Usage: chip8-asm [--symbols] <source> [output], the output defaults to the source with a
+// .ch8 extension. --symbols also writes the symbol map for debuggers next to the output
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let write_symbols = args.len() > 1 && args[1] == "--symbols";
+    if write_symbols {
+        args.remove(1);
+    }
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("Usage: chip8-asm [--symbols] <source> [output]");
+        process::exit(1);
+    }
+    let source = Path::new(&args[1]);
+    let output = match args.get(2) {
+        Some(output) => PathBuf::from(output),
+        None => source.with_extension("ch8"),
+    };
+    let (rom, symbol_map) = match assemble_file_with_symbols(source) {
+        Ok(assembled) => assembled,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = fs::write(&output, rom) {
+        eprintln!("Can't write {}: {}", output.display(), err);
+        process::exit(1);
+    }
+    if write_symbols {
+        let path = symbols::path_for(&output);
+        if let Err(err) = fs::write(&path, symbol_map.to_string()) {
+            eprintln!("Can't write {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use chip_8::dap::{self, Session};
+use chip_8::timing::{FrameClock, FRAME};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Instant;
+use std::{env, process, thread};
+
+// Usage: chip8-dap [--port <n>], without a port the protocol runs over stdin and stdout.
+// The ROM to debug comes with the client's launch request
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let port = match &args[..] {
+        [] => None,
+        [flag, port] if flag == "--port" => match port.parse::<u16>() {
+            Ok(port) => Some(port),
+            Err(_) => {
+                eprintln!("Invalid port '{}'", port);
+                process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("Usage: chip8-dap [--port <n>]");
+            process::exit(1);
+        }
+    };
+    let (mut input, mut output): (Box<dyn BufRead + Send>, Box<dyn Write>) = match port {
+        Some(port) => {
+            let stream = TcpListener::bind(("127.0.0.1", port))
+                .and_then(|listener| {
+                    eprintln!("Waiting for a client on port {}", port);
+                    listener.accept()
+                })
+                .and_then(|(stream, _)| stream.try_clone().map(|input| (input, stream)));
+            match stream {
+                Ok((input, stream)) => (Box::new(BufReader::new(input)), Box::new(stream)),
+                Err(err) => {
+                    eprintln!("Couldn't listen on port {}: {}", port, err);
+                    process::exit(1);
+                }
+            }
+        }
+        None => (
+            Box::new(BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        ),
+    };
+
+    // Requests are read on their own thread so the machine keeps running between them
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        match dap::read_message(&mut input) {
+            Ok(Some(message)) => {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new();
+    let mut clock = FrameClock::default();
+    let mut last_frame = Instant::now();
+    while !session.is_finished() {
+        match receiver.recv_timeout(FRAME / 4) {
+            Ok(message) => session.handle(&message),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let now = Instant::now();
+        for _ in 0..clock.advance(now.duration_since(last_frame)) {
+            session.run_frame();
+        }
+        last_frame = now;
+        for message in session.take_output() {
+            if let Err(err) = dap::write_message(&mut output, &message) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use chip_8::disasm::disassemble;
+use std::{env, fs, process};
+
+// Usage: chip8-disasm <rom>
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: chip8-disasm <rom>");
+            process::exit(1);
+        }
+    };
+    match fs::read(&path) {
+        Ok(rom) => print!("{}", disassemble(&rom)),
+        Err(err) => {
+            eprintln!("Can't read {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use chip_8::chip8::Chip8;
+use chip_8::instruction::Instruction;
+use chip_8::monitor::Monitor;
+use chip_8::movie::{Movie, Playback, PlaybackError};
+use chip_8::quirks::Quirks;
+use chip_8::rng::{self, MemoryRng, VipRng, XorShift};
+use chip_8::timing::{TimingMode, DEFAULT_IPS};
+use chip_8::trace::{self, Divergence, MemoryHash, Trace, TraceLine, HEADER};
+use chip_8::{assembler, octo};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::rc::Rc;
+use std::{env, iter, process};
+
+const USAGE: &str = "Usage: chip8-tracediff [--context <n>] [--ignore-timers] <expected> (<trace> | --rom <rom> [--quirks <preset>] [--seed <n>] [--vip-rng | --memory-rng] [--ips <n>] [--vip-timing] [--play <movie>])";
+
+struct Options {
+    expected: String,
+    actual: Option<String>,
+    rom: Option<String>,
+    // Lines shown before the difference
+    context: usize,
+    timers: bool,
+    // How the ROM runs, the same as the frontend's options
+    quirks: Quirks,
+    seed: u64,
+    // The id of the generator for CXNN
+    rng: u8,
+    ips: u32,
+    timing: TimingMode,
+    play: Option<String>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            expected: String::new(),
+            actual: None,
+            rom: None,
+            context: 10,
+            timers: true,
+            quirks: Quirks::default(),
+            seed: 0,
+            rng: XorShift::ID,
+            ips: DEFAULT_IPS,
+            timing: TimingMode::Instructions,
+            play: None,
+        };
+        let mut traces = Vec::new();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "--context" => {
+                    let context = value("--context")?;
+                    options.context = context
+                        .parse()
+                        .map_err(|_| format!("Invalid number of lines '{}'", context))?;
+                }
+                "--ignore-timers" => options.timers = false,
+                "--rom" => options.rom = Some(value("--rom")?),
+                "--quirks" => {
+                    let name = value("--quirks")?;
+                    options.quirks = Quirks::PRESETS
+                        .iter()
+                        .find(|(preset, _)| preset.eq_ignore_ascii_case(&name))
+                        .map(|(_, quirks)| *quirks)
+                        .ok_or_else(|| format!("Unknown quirks '{}'", name))?;
+                }
+                "--seed" => {
+                    let seed = value("--seed")?;
+                    options.seed = seed
+                        .parse()
+                        .map_err(|_| format!("Invalid seed '{}'", seed))?;
+                }
+                "--vip-rng" => options.rng = VipRng::ID,
+                "--memory-rng" => options.rng = MemoryRng::ID,
+                "--ips" => {
+                    let ips = value("--ips")?;
+                    options.ips = ips
+                        .parse()
+                        .ok()
+                        .filter(|ips| *ips > 0)
+                        .ok_or_else(|| format!("Invalid instructions per second '{}'", ips))?;
+                }
+                "--vip-timing" => options.timing = TimingMode::Vip,
+                "--play" => options.play = Some(value("--play")?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+                _ => traces.push(arg),
+            }
+        }
+        let mut traces = traces.into_iter();
+        options.expected = traces.next().ok_or("No expected trace")?;
+        options.actual = traces.next();
+        if traces.next().is_some() || options.actual.is_some() == options.rom.is_some() {
+            return Err("Give either a second trace or a ROM to run".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn read_trace(path: &str) -> Result<impl Iterator<Item = Result<TraceLine, String>>, String> {
+    let file = File::open(path).map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+    let path = path.to_string();
+    Ok(trace::read(BufReader::new(file))
+        .map(move |line| line.map_err(|err| format!("{}: {}", path, err))))
+}
+
+fn load_rom(path: &Path) -> Result<Vec<u8>, String> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("8o") => octo::compile_file(path).map_err(|err| err.to_string()),
+        Some("asm") => assembler::assemble_file(path).map_err(|err| err.to_string()),
+        _ => fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err)),
+    }
+}
+
+// Keeps the lines of the instructions as they run, for the live trace to hand out
+struct Collector {
+    lines: Rc<RefCell<VecDeque<TraceLine>>>,
+    cycle: u64,
+    memory_hash: MemoryHash,
+}
+
+impl Trace for Collector {
+    fn trace(&mut self, chip8: &Chip8, instruction: Instruction) {
+        let line = TraceLine::new(self.cycle, chip8, instruction, &mut self.memory_hash);
+        self.lines.borrow_mut().push_back(line);
+        self.cycle += 1;
+    }
+}
+
+// Runs the ROM a frame at a time as its lines are asked for, with no keys pressed once
+// the movie is over. It ends when the program exits or the machine fails
+fn run_live(
+    options: &Options,
+    rom: &[u8],
+) -> Result<impl Iterator<Item = Result<TraceLine, String>>, String> {
+    let mut chip8 = Chip8::new(Monitor::new_default(), options.quirks);
+    chip8.load_sprites();
+    chip8.load_program(rom);
+    chip8.set_rng(rng::from_id(options.rng, options.seed).unwrap());
+    chip8.set_ips(options.ips);
+    chip8.set_timing(options.timing);
+    let mut playback = match &options.play {
+        Some(path) => {
+            let bytes = fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+            let movie = Movie::from_bytes(&bytes)
+                .and_then(|movie| movie.apply(&mut chip8).map(|_| movie))
+                .map_err(|err| format!("Couldn't play {}: {}", path, err))?;
+            Some(Playback::new(movie))
+        }
+        None => None,
+    };
+    let lines = Rc::new(RefCell::new(VecDeque::new()));
+    chip8.set_tracer(Some(Box::new(Collector {
+        lines: lines.clone(),
+        cycle: 0,
+        memory_hash: MemoryHash::new(),
+    })));
+    let mut done = false;
+    Ok(iter::from_fn(move || loop {
+        if let Some(line) = lines.borrow_mut().pop_front() {
+            return Some(Ok(line));
+        }
+        if done || chip8.exit_requested() {
+            return None;
+        }
+        let frame = match playback
+            .as_mut()
+            .and_then(|playback| playback.step(&mut chip8))
+        {
+            // The movie has its own check, a desync is what this is looking for anyway
+            Some(Ok(())) | Some(Err(PlaybackError::Desync { .. })) => Ok(()),
+            Some(Err(PlaybackError::Machine(err))) => Err(err),
+            None => {
+                chip8.set_keys(0);
+                chip8.cycle()
+            }
+        };
+        // The trace ends with the instruction that failed
+        if let Err(err) = frame {
+            eprintln!("The ROM stopped: {}", err);
+            done = true;
+        }
+    }))
+}
+
+fn report(divergence: &Divergence) {
+    println!("{}", HEADER);
+    for line in &divergence.context {
+        println!("  {}", line);
+    }
+    println!("- {}", divergence.expected);
+    match &divergence.actual {
+        Some(actual) if actual.cycle != divergence.expected.cycle => {
+            println!("+ {}", actual);
+            println!("The trace has no cycle {}", divergence.expected.cycle);
+        }
+        Some(actual) => {
+            println!("+ {}", actual);
+            println!(
+                "First difference at cycle {}, in {}",
+                divergence.expected.cycle,
+                divergence.fields.join(", ")
+            );
+            if divergence.fields.contains(&"mem") {
+                println!("Memory already differs, an earlier instruction wrote something else");
+            }
+        }
+        None => println!("The trace ends before cycle {}", divergence.expected.cycle),
+    }
+}
+
+// Usage: see USAGE. Exits with 1 when the traces differ and 2 when they can't be compared
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let compared = read_trace(&options.expected).and_then(|expected| {
+        let actual: Box<dyn Iterator<Item = Result<TraceLine, String>>> =
+            match (&options.actual, &options.rom) {
+                (Some(path), _) => Box::new(read_trace(path)?),
+                (None, Some(path)) => Box::new(run_live(&options, &load_rom(Path::new(path))?)?),
+                (None, None) => unreachable!(),
+            };
+        trace::compare(expected, actual, options.context, options.timers)
+    });
+    match compared {
+        Ok(None) => println!("The traces match"),
+        Ok(Some(divergence)) => {
+            report(&divergence);
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Where the frontend looks for the bindings unless told otherwise
+pub const DEFAULT_PATH: &str = "./chip8.toml";
+/// How far a stick has to be pushed, as a fraction of its range, before it counts
+pub const DEFAULT_DEADZONE: f32 = 0.3;
+/// Controller inputs are named like this prefix followed by SDL's name for the button,
+/// e.g. "Pad a" or "Pad dpup", or for the axis with the direction, e.g. "Pad leftx-"
+pub const PAD_PREFIX: &str = "Pad ";
+// Every preset puts the D-pad and the left stick on 2/4/6/8, A on 5 and B on A
+const PAD_LAYOUT: [(usize, &[&str]); 6] = [
+    (0x2, &["Pad dpup", "Pad lefty-"]),
+    (0x4, &["Pad dpleft", "Pad leftx-"]),
+    (0x6, &["Pad dpright", "Pad leftx+"]),
+    (0x8, &["Pad dpdown", "Pad lefty+"]),
+    (0x5, &["Pad a"]),
+    (0xA, &["Pad b"]),
+];
+
+/// A layout to start from, the keys in the config are changed on top of it
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// Every hex key on the key with the same character, 0-9 and A-F
+    Vip,
+    /// The 4x4 block 1234/QWER/ASDF/ZXCV, laid out like the VIP's keypad
+    #[default]
+    Qwerty,
+    /// The numeric keypad, with the arrows on 2/4/6/8
+    Numpad,
+}
+
+/// The emulator's own keys
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hotkey {
+    Quit,
+    Pause,
+    Reset,
+    /// Shows or hides the memory viewer
+    Memory,
+    /// Shows or hides the register panel
+    Registers,
+}
+
+/// Why the bindings couldn't be read
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindingsError {
+    // Not valid TOML, or not shaped like a config
+    Parse(String),
+    // A [keys] entry that isn't a hex digit
+    UnknownKeypadKey(String),
+}
+
+impl fmt::Display for BindingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingsError::Parse(message) => write!(f, "Invalid key bindings: {}", message),
+            BindingsError::UnknownKeypadKey(key) => {
+                write!(f, "'{}' is not a keypad key, expected 0-F", key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BindingsError {}
+
+/// The keys bound to each keypad key and hotkey. Keys are named the way the frontend
+/// names them, for SDL the scancode names like "Q", "Keypad 7" or "Up", and controller
+/// inputs start with `PAD_PREFIX`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bindings {
+    keypad: [Vec<String>; 16],
+    hotkeys: BTreeMap<Hotkey, Vec<String>>,
+    deadzone: f32,
+}
+
+impl Bindings {
+    pub fn from_preset(preset: Preset) -> Self {
+        // Each row holds the keys for keypad keys 0 to F
+        let layout: [&[&str]; 16] = match preset {
+            Preset::Vip => [
+                &["0"],
+                &["1"],
+                &["2"],
+                &["3"],
+                &["4"],
+                &["5"],
+                &["6"],
+                &["7"],
+                &["8"],
+                &["9"],
+                &["A"],
+                &["B"],
+                &["C"],
+                &["D"],
+                &["E"],
+                &["F"],
+            ],
+            Preset::Qwerty => [
+                &["X"],
+                &["1"],
+                &["2"],
+                &["3"],
+                &["Q"],
+                &["W"],
+                &["E"],
+                &["A"],
+                &["S"],
+                &["D"],
+                &["Z"],
+                &["C"],
+                &["4"],
+                &["R"],
+                &["F"],
+                &["V"],
+            ],
+            Preset::Numpad => [
+                &["Keypad 0"],
+                &["Keypad 7"],
+                &["Keypad 8", "Up"],
+                &["Keypad 9"],
+                &["Keypad 4", "Left"],
+                &["Keypad 5"],
+                &["Keypad 6", "Right"],
+                &["Keypad 1"],
+                &["Keypad 2", "Down"],
+                &["Keypad 3"],
+                &["Keypad ."],
+                &["Keypad Enter"],
+                &["Keypad /"],
+                &["Keypad *"],
+                &["Keypad -"],
+                &["Keypad +"],
+            ],
+        };
+        let mut keypad: [Vec<String>; 16] =
+            layout.map(|keys| keys.iter().map(|key| key.to_string()).collect());
+        for (key, names) in PAD_LAYOUT {
+            keypad[key].extend(names.iter().map(|name| name.to_string()));
+        }
+        let hotkeys: [(Hotkey, &[&str]); 5] = [
+            (Hotkey::Quit, &["Escape"]),
+            (Hotkey::Pause, &["P", "Pad start"]),
+            (Hotkey::Reset, &["F8"]),
+            (Hotkey::Memory, &["Tab"]),
+            (Hotkey::Registers, &["I"]),
+        ];
+        Self {
+            keypad,
+            hotkeys: hotkeys
+                .into_iter()
+                .map(|(hotkey, keys)| (hotkey, keys.iter().map(|key| key.to_string()).collect()))
+                .collect(),
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+
+    /// Reads a config and picks the bindings for the ROM with the given file name.
+    ///
+    /// ```toml
+    /// preset = "qwerty"        # vip, qwerty or numpad
+    /// deadzone = 0.3           # of the controller sticks, 0 to 1
+    ///
+    /// [keys]                   # replaces the preset's keys for a keypad key, and its
+    /// 5 = ["W", "Up"]          # controller bindings if it names any
+    ///
+    /// [hotkeys]                # quit, pause, reset, memory and registers
+    /// pause = ["Space", "Pad start"]
+    ///
+    /// [rom."Pong2.ch8"]        # applied on top of the rest for that ROM
+    /// preset = "numpad"
+    /// keys = { 1 = "Q", C = "P" }
+    /// ```
+    ///
+    /// A ROM's preset replaces the whole keypad, its keys and hotkeys only the ones
+    /// they name. Hotkeys keep their controller bindings the same way keys do
+    pub fn parse(source: &str, rom: &str) -> Result<Self, BindingsError> {
+        let config: Config =
+            toml::from_str(source).map_err(|err| BindingsError::Parse(err.message().into()))?;
+        let mut bindings = Self::from_preset(config.preset.unwrap_or_default());
+        bindings.apply(Layer {
+            preset: config.preset,
+            keys: config.keys,
+            hotkeys: config.hotkeys,
+            deadzone: config.deadzone,
+        })?;
+        if let Some(layer) = config.rom.get(rom).cloned() {
+            if let Some(preset) = layer.preset {
+                bindings.keypad = Self::from_preset(preset).keypad;
+            }
+            bindings.apply(layer)?;
+        }
+        Ok(bindings)
+    }
+
+    fn apply(&mut self, layer: Layer) -> Result<(), BindingsError> {
+        for (key, names) in layer.keys {
+            let index = u8::from_str_radix(&key, 16)
+                .ok()
+                .filter(|index| *index < 16 && key.len() == 1)
+                .ok_or(BindingsError::UnknownKeypadKey(key))?;
+            replace(&mut self.keypad[index as usize], names.into_vec());
+        }
+        for (hotkey, names) in layer.hotkeys {
+            replace(self.hotkeys.entry(hotkey).or_default(), names.into_vec());
+        }
+        if let Some(deadzone) = layer.deadzone {
+            if !(0.0..1.0).contains(&deadzone) {
+                return Err(BindingsError::Parse(format!(
+                    "deadzone {} is not between 0 and 1",
+                    deadzone
+                )));
+            }
+            self.deadzone = deadzone;
+        }
+        Ok(())
+    }
+
+    /// The keys bound to each keypad key, 0 to F
+    #[inline]
+    pub fn keypad(&self) -> &[Vec<String>; 16] {
+        &self.keypad
+    }
+
+    #[inline]
+    pub fn hotkeys(&self) -> &BTreeMap<Hotkey, Vec<String>> {
+        &self.hotkeys
+    }
+
+    /// The stick deadzone, as a fraction of the stick's range
+    #[inline]
+    pub fn deadzone(&self) -> f32 {
+        self.deadzone
+    }
+
+    /// The keypad keys a key is bound to, bit N is keypad key N
+    pub fn keypad_mask(&self, name: &str) -> u16 {
+        let mut mask = 0;
+        for (key, names) in self.keypad.iter().enumerate() {
+            if names.iter().any(|bound| bound.eq_ignore_ascii_case(name)) {
+                mask |= 1 << key;
+            }
+        }
+        mask
+    }
+
+    pub fn hotkey(&self, name: &str) -> Option<Hotkey> {
+        self.hotkeys.iter().find_map(|(hotkey, names)| {
+            names
+                .iter()
+                .any(|bound| bound.eq_ignore_ascii_case(name))
+                .then_some(*hotkey)
+        })
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self::from_preset(Preset::default())
+    }
+}
+
+// The top of the config is a layer of its own, with the per-ROM layers next to it
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    preset: Option<Preset>,
+    #[serde(default)]
+    keys: BTreeMap<String, Names>,
+    #[serde(default)]
+    hotkeys: BTreeMap<Hotkey, Names>,
+    deadzone: Option<f32>,
+    #[serde(default)]
+    rom: BTreeMap<String, Layer>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    preset: Option<Preset>,
+    #[serde(default)]
+    keys: BTreeMap<String, Names>,
+    #[serde(default)]
+    hotkeys: BTreeMap<Hotkey, Names>,
+    deadzone: Option<f32>,
+}
+
+// One key or a list of them
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum Names {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Names {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Names::One(name) => vec![name],
+            Names::Many(names) => names,
+        }
+    }
+}
+
+// Controller buttons are only replaced when the names include some, so rebinding the
+// keyboard keeps what the preset gave the controller
+fn replace(bound: &mut Vec<String>, names: Vec<String>) {
+    let is_pad = |name: &String| name.starts_with(PAD_PREFIX);
+    let pad = names.iter().any(is_pad);
+    bound.retain(|name| is_pad(name) && !pad);
+    bound.extend(names);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let qwerty = Bindings::default();
+        assert_eq!(qwerty.keypad_mask("x"), 1 << 0x0);
+        assert_eq!(qwerty.keypad_mask("V"), 1 << 0xF);
+        assert_eq!(qwerty.keypad_mask("Escape"), 0);
+        assert_eq!(qwerty.hotkey("escape"), Some(Hotkey::Quit));
+        let numpad = Bindings::from_preset(Preset::Numpad);
+        assert_eq!(numpad.keypad_mask("Up"), 1 << 0x2);
+        assert_eq!(numpad.keypad_mask("Keypad 8"), 1 << 0x2);
+        let vip = Bindings::from_preset(Preset::Vip);
+        assert_eq!(vip.keypad_mask("B"), 1 << 0xB);
+        // Controllers work the same with every preset
+        assert_eq!(vip.keypad_mask("Pad dpup"), 1 << 0x2);
+        assert_eq!(numpad.keypad_mask("Pad leftx+"), 1 << 0x6);
+        assert_eq!(qwerty.hotkey("Pad start"), Some(Hotkey::Pause));
+        assert_eq!(qwerty.hotkey("Tab"), Some(Hotkey::Memory));
+        assert_eq!(qwerty.hotkey("I"), Some(Hotkey::Registers));
+    }
+
+    #[test]
+    fn config() {
+        let source = r#"
+            preset = "vip"
+            deadzone = 0.5
+            [keys]
+            5 = ["W", "Up"]
+            a = "Space"
+            [hotkeys]
+            pause = "Return"
+            [rom."Pong2.ch8"]
+            preset = "numpad"
+            keys = { 1 = "Q" }
+        "#;
+        let bindings = Bindings::parse(source, "Other.ch8").unwrap();
+        assert_eq!(bindings.keypad_mask("Up"), 1 << 0x5);
+        assert_eq!(bindings.keypad_mask("5"), 0);
+        assert_eq!(bindings.keypad_mask("Space"), 1 << 0xA);
+        assert_eq!(bindings.keypad_mask("3"), 1 << 0x3);
+        assert_eq!(bindings.hotkey("Return"), Some(Hotkey::Pause));
+        assert_eq!(bindings.hotkey("P"), None);
+        assert_eq!(bindings.hotkey("Escape"), Some(Hotkey::Quit));
+        assert_eq!(bindings.deadzone(), 0.5);
+        // The keyboard keys are replaced, the controller's stay unless the layer names some
+        assert_eq!(bindings.keypad_mask("Pad a"), 1 << 0x5);
+        assert_eq!(bindings.hotkey("Pad start"), Some(Hotkey::Pause));
+        let bindings = Bindings::parse("[keys]\n5 = [\"T\", \"Pad x\"]", "").unwrap();
+        assert_eq!(bindings.keypad_mask("Pad x"), 1 << 0x5);
+        assert_eq!(bindings.keypad_mask("Pad a"), 0);
+        assert_eq!(bindings.keypad_mask("W"), 0);
+
+        let pong = Bindings::parse(source, "Pong2.ch8").unwrap();
+        assert_eq!(pong.keypad_mask("Q"), 1 << 0x1);
+        assert_eq!(pong.keypad_mask("Up"), 1 << 0x2);
+        assert_eq!(pong.keypad_mask("Keypad 9"), 1 << 0x3);
+        assert_eq!(pong.hotkey("Return"), Some(Hotkey::Pause));
+
+        assert_eq!(
+            Bindings::parse("[keys]\nG = \"G\"", ""),
+            Err(BindingsError::UnknownKeypadKey("G".into()))
+        );
+        assert!(matches!(
+            Bindings::parse("preset = \"azerty\"", ""),
+            Err(BindingsError::Parse(_))
+        ));
+        // The example shipped with the emulator
+        let example = Bindings::parse(include_str!("../chip8.toml"), "Pong2.ch8").unwrap();
+        assert_eq!(example.keypad_mask("Up"), 1 << 0x1);
+        assert!(matches!(
+            Bindings::parse("deadzone = 1.5", ""),
+            Err(BindingsError::Parse(_))
+        ));
+        assert!(matches!(
+            Bindings::parse("keypad = 1", ""),
+            Err(BindingsError::Parse(_))
+        ));
+    }
+}
@@ -1,12 +1,23 @@
+use crate::error::Chip8Error;
+use crate::instruction::{decode, Instruction};
 use crate::keyboard::*;
 use crate::monitor::Monitor;
-use rand::*;
-use sdl2::keyboard::Scancode;
-use sdl2::EventPump;
+use crate::quirks::{IndexIncrement, Quirks};
+use crate::rng::{self, Rng, XorShift};
+use crate::state::{self, StateError, StateReader, StateWriter};
+use crate::timing::{self, InstructionBudget, TimingMode};
+use crate::trace::Trace;
+use std::ops::Range;
 
-const SPEED: u8 = 5;
-const MEMORY_SIZE: usize = 4096;
+// Used until the frontend picks one, so runs are repeatable by default
+const DEFAULT_SEED: u64 = 0;
+// The XO-CHIP address space, plain CHIP-8 programs only use the first 4 KiB
+const MEMORY_SIZE: usize = 0x10000;
 const NUM_REGISTERS: usize = 16;
+// Where programs are loaded and start running
+const PROGRAM_START: usize = 0x200;
+// The pitch at which XO-CHIP audio patterns play at 4000 samples per second
+const DEFAULT_PITCH: u8 = 64;
 const SPRITES: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -25,6 +36,33 @@ const SPRITES: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
+// The SUPER-CHIP 8x10 font, stored right after the small one
+const BIG_SPRITES_ADDR: usize = SPRITES.len();
+const BIG_SPRITES: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+    0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// A memory access an instruction makes, see `Chip8::memory_access`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub write: bool,
+    pub range: Range<usize>,
+}
 
 pub struct Chip8 {
     pub monitor: Monitor,
@@ -37,51 +75,178 @@ pub struct Chip8 {
     stack_pointer: u8,
     delay_timer: u8,
     sound_timer: u8,
-    speed: u8,
+    rpl_flags: [u8; NUM_REGISTERS], // SUPER-CHIP persistent user flags
+    audio_pattern: Option<[u8; 16]>, // XO-CHIP 1-bit audio samples
+    pitch: u8,
+    // How many instructions each 60 Hz frame runs
+    budget: InstructionBudget,
+    timing: TimingMode,
+    // The VIP machine cycles left in the frame, negative when the last instruction of
+    // a frame ran over into the next one
+    cycle_credit: i32,
+    // Identifies the loaded program in save states
+    rom_hash: u64,
+    // Where the loaded program ends in memory
+    program_end: usize,
+    // The source of CXNN's random bytes
+    rng: Box<dyn Rng>,
+    // Shown every instruction before it runs
+    tracer: Option<Box<dyn Trace>>,
+    pub quirks: Quirks,
     pub kill_flag: bool,
 }
 
 impl Chip8 {
-    pub fn new(monitor: Monitor) -> Self {
+    pub fn new(monitor: Monitor, quirks: Quirks) -> Self {
         Self {
             monitor,
             memory: [0; MEMORY_SIZE],
             registers: [0; NUM_REGISTERS],
             index: 0,
-            pc: 0x200,
+            pc: PROGRAM_START as u16,
             stack: [0; 16],
             stack_pointer: 0,
             delay_timer: 0,
             sound_timer: 0,
-            speed: SPEED,
+            rpl_flags: [0; NUM_REGISTERS],
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
+            budget: InstructionBudget::default(),
+            timing: TimingMode::default(),
+            cycle_credit: 0,
+            rom_hash: state::fnv1a(&[]),
+            program_end: PROGRAM_START,
+            rng: Box::new(XorShift::new(DEFAULT_SEED)),
+            tracer: None,
+            quirks,
             keyboard: Keyboard::new(),
             kill_flag: false,
         }
     }
 
-    #[inline]
-    pub fn get_metrics(&self) -> String {
-        format!(
-            "PC:{} I:{} SP:{} DT:{} ST:{} Key:{:?}",
-            self.pc,
-            self.index,
-            self.stack_pointer,
-            self.delay_timer,
-            self.sound_timer,
-            self.keyboard.pressed_key
-        )
-    }
-
     pub fn load_sprites(&mut self) {
         for (i, sprite_byte) in SPRITES.iter().enumerate() {
             self.memory[i] = *sprite_byte;
         }
+        for (i, sprite_byte) in BIG_SPRITES.iter().enumerate() {
+            self.memory[BIG_SPRITES_ADDR + i] = *sprite_byte;
+        }
     }
 
     pub fn load_program(&mut self, program: &[u8]) {
         for (i, byte) in program.iter().enumerate() {
-            self.memory[0x200 + i] = *byte;
+            self.memory[PROGRAM_START + i] = *byte;
         }
+        self.rom_hash = state::fnv1a(program);
+        self.program_end = PROGRAM_START + program.len();
+    }
+
+    /// Replaces the random number generator, e.g. with one seeded by the user
+    #[inline]
+    pub fn set_rng(&mut self, rng: Box<dyn Rng>) {
+        self.rng = rng;
+    }
+
+    /// Starts or stops tracing the instructions that run, and returns the tracer that
+    /// was set before so it can be finished
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Trace>>) -> Option<Box<dyn Trace>> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    #[inline]
+    pub fn get_seed(&self) -> u64 {
+        self.rng.seed()
+    }
+
+    /// Which generator is in use, see `rng::from_id`
+    #[inline]
+    pub fn get_rng_id(&self) -> u8 {
+        self.rng.id()
+    }
+
+    #[inline]
+    pub fn get_rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    /// Captures the whole machine, see `state` for the layout around it
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes(&self.memory);
+        writer.bytes(&self.registers);
+        writer.u16(self.index);
+        writer.u16(self.pc);
+        for address in self.stack {
+            writer.u16(address);
+        }
+        writer.u8(self.stack_pointer);
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+        writer.bytes(&self.rpl_flags);
+        writer.u8(self.audio_pattern.is_some() as u8);
+        writer.bytes(&self.audio_pattern.unwrap_or_default());
+        writer.u8(self.pitch);
+        writer.u8(self.budget.remainder() as u8);
+        writer.u64(self.cycle_credit as i64 as u64);
+        self.keyboard.save_state(&mut writer);
+        self.monitor.save_state(&mut writer);
+        writer.u8(self.rng.id());
+        writer.u64(self.rng.seed());
+        writer.u64(self.rng.state());
+        state::seal(self.rom_hash, &writer.bytes)
+    }
+
+    /// Restores a state saved with the same ROM loaded. Nothing changes if it fails
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(state::open(state, self.rom_hash)?);
+        let memory = reader.array()?;
+        let registers = reader.array()?;
+        let index = reader.u16()?;
+        let pc = reader.u16()?;
+        let mut stack = [0; 16];
+        for address in stack.iter_mut() {
+            *address = reader.u16()?;
+        }
+        let stack_pointer = reader.u8()?;
+        if stack_pointer as usize > stack.len() {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let rpl_flags = reader.array()?;
+        let has_pattern = reader.u8()? != 0;
+        let pattern = reader.array()?;
+        let pitch = reader.u8()?;
+        let remainder = reader.u8()?;
+        let cycle_credit =
+            i32::try_from(reader.u64()? as i64).map_err(|_| StateError::Invalid("cycle count"))?;
+        let keyboard = Keyboard::load_state(&mut reader)?;
+        let monitor = Monitor::load_state(&mut reader)?;
+        let rng_id = reader.u8()?;
+        let seed = reader.u64()?;
+        let mut rng =
+            rng::from_id(rng_id, seed).ok_or(StateError::Invalid("random number generator"))?;
+        rng.restore(seed, reader.u64()?);
+        if !reader.is_empty() {
+            return Err(StateError::Invalid("length"));
+        }
+        self.memory = memory;
+        self.registers = registers;
+        self.index = index;
+        self.pc = pc;
+        self.stack = stack;
+        self.stack_pointer = stack_pointer;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.rpl_flags = rpl_flags;
+        self.audio_pattern = if has_pattern { Some(pattern) } else { None };
+        self.pitch = pitch;
+        self.budget.set_remainder(remainder as u32);
+        self.cycle_credit = cycle_credit;
+        self.keyboard = keyboard;
+        self.monitor = monitor;
+        self.rng = rng;
+        Ok(())
     }
 
     #[inline]
@@ -92,6 +257,13 @@ impl Chip8 {
         if self.sound_timer > 0 {
             self.sound_timer -= 1;
         }
+        // The VIP's interrupt moves its random state along at the same time
+        self.rng.tick();
+    }
+
+    #[inline]
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
     }
 
     #[inline]
@@ -100,227 +272,521 @@ impl Chip8 {
     }
 
     #[inline]
-    pub fn cycle(&mut self, event_pump: &EventPump) {
-        for _ in 0..self.speed {
-            self.check_input(event_pump);
-            // Shift from the current location in memory 8 bits to the left,
-            // e.g. 0xFF << 8 = 0xFF00
-            let shifted: u16 = (self.memory[self.pc as usize] as u16) << 8;
-            // Or it with the next instruction
-            // e.g. 0xFF00 | 0x12 = 0xFF12
-            self.interpret_instruction(shifted | self.memory[self.pc as usize + 1] as u16);
-            self.update_timers();
-            self.keyboard.press_key(Chip8Key::None);
-        }
-    }
-
-    #[inline]
-    pub fn check_input(&mut self, event_pump: &EventPump) {
-        for key in event_pump.keyboard_state().pressed_scancodes() {
-            match key {
-                // Chip 8 related keys
-                Scancode::Kp0 => self.keyboard.press_key(Chip8Key::Zero),
-                Scancode::Kp1 => self.keyboard.press_key(Chip8Key::Seven),
-                Scancode::Kp2 | Scancode::Down => self.keyboard.press_key(Chip8Key::Eight),
-                Scancode::Kp3 => self.keyboard.press_key(Chip8Key::Nine),
-                Scancode::Kp4 | Scancode::Left => self.keyboard.press_key(Chip8Key::Four),
-                Scancode::Kp5 | Scancode::Q => self.keyboard.press_key(Chip8Key::Five),
-                Scancode::Kp6 | Scancode::Right => self.keyboard.press_key(Chip8Key::Six),
-                Scancode::Kp7 => self.keyboard.press_key(Chip8Key::One),
-                Scancode::Kp8 | Scancode::Up => self.keyboard.press_key(Chip8Key::Two),
-                Scancode::Kp9 => self.keyboard.press_key(Chip8Key::Three),
-                Scancode::A => self.keyboard.press_key(Chip8Key::A),
-                Scancode::B => self.keyboard.press_key(Chip8Key::B),
-                Scancode::C => self.keyboard.press_key(Chip8Key::C),
-                Scancode::D => self.keyboard.press_key(Chip8Key::D),
-                Scancode::E => self.keyboard.press_key(Chip8Key::E),
-                Scancode::F => self.keyboard.press_key(Chip8Key::F),
-                Scancode::Escape => self.kill_flag = true,
-                _ => self.keyboard.press_key(Chip8Key::None),
+    pub fn get_audio_pattern(&self) -> Option<[u8; 16]> {
+        self.audio_pattern
+    }
+
+    #[inline]
+    pub fn get_pitch(&self) -> u8 {
+        self.pitch
+    }
+
+    /// Sets the keys the frontend has held down, bit N is key N
+    #[inline]
+    pub fn set_keys(&mut self, keys: u16) {
+        self.keyboard.set_keys(keys);
+    }
+
+    /// Set when the program asked to exit, with 00FD
+    #[inline]
+    pub fn exit_requested(&self) -> bool {
+        self.kill_flag
+    }
+
+    /// Sets how many instructions run per second, independently of the 60 Hz timers
+    #[inline]
+    pub fn set_ips(&mut self, ips: u32) {
+        self.budget = InstructionBudget::new(ips);
+    }
+
+    #[inline]
+    pub fn get_ips(&self) -> u32 {
+        self.budget.ips()
+    }
+
+    /// Switches between a fixed instruction rate and the VIP's real instruction timings
+    #[inline]
+    pub fn set_timing(&mut self, timing: TimingMode) {
+        self.timing = timing;
+        self.cycle_credit = 0;
+    }
+
+    #[inline]
+    pub fn get_timing(&self) -> TimingMode {
+        self.timing
+    }
+
+    /// Runs one 60 Hz frame, then a single tick of the delay and sound timers.
+    /// How much of the program runs depends on the timing mode: the frame's share of the
+    /// instructions per second, or as many instructions as fit in the VIP machine cycles
+    /// the 60 Hz interrupt leaves over
+    pub fn cycle(&mut self) -> Result<(), Chip8Error> {
+        self.cycle_until(|_, _| false).map(|_| ())
+    }
+
+    /// Runs a frame like `cycle`, but first shows every instruction to `stop`. When it
+    /// returns true the frame ends before that instruction, without ticking the timers,
+    /// and true is returned. A debugger uses this to break in the middle of a frame
+    pub fn cycle_until(
+        &mut self,
+        mut stop: impl FnMut(&Chip8, Instruction) -> bool,
+    ) -> Result<bool, Chip8Error> {
+        match self.timing {
+            TimingMode::Instructions => {
+                for _ in 0..self.budget.next_frame() {
+                    if stop(self, self.peek()?) {
+                        return Ok(true);
+                    }
+                    let instruction = self.step()?;
+                    if self.ends_frame(instruction) {
+                        break;
+                    }
+                }
+            }
+            TimingMode::Vip => {
+                self.cycle_credit +=
+                    (timing::VIP_FRAME_CYCLES - timing::VIP_INTERRUPT_CYCLES) as i32;
+                while self.cycle_credit > 0 {
+                    if stop(self, self.peek()?) {
+                        // The frame starts over when the machine carries on
+                        self.cycle_credit = 0;
+                        return Ok(true);
+                    }
+                    let registers = self.registers;
+                    let pc = self.pc;
+                    let instruction = self.step()?;
+                    let skipped = self.pc == pc.wrapping_add(4);
+                    self.cycle_credit -=
+                        timing::vip_cycles(instruction, &registers, skipped) as i32;
+                    if self.ends_frame(instruction) {
+                        // The rest of the frame is spent waiting
+                        self.cycle_credit = self.cycle_credit.min(0);
+                        break;
+                    }
+                }
             }
         }
+        self.update_timers();
+        self.keyboard.end_frame();
+        Ok(false)
     }
 
     #[inline]
-    pub fn interpret_instruction(&mut self, instruction: u16) {
-        self.pc += 2;
-        let x = ((instruction & 0x0F00) >> 8) as usize;
-        let y = ((instruction & 0x00F0) >> 4) as usize;
-        match instruction & 0xF000 {
-            0x0000 => match instruction {
-                0x00E0 => {
-                    self.monitor.clear();
-                }
-                0x00EE => {
-                    self.pc = self.stack[self.stack_pointer as usize];
-                    self.stack_pointer -= 1;
+    fn ends_frame(&self, instruction: Instruction) -> bool {
+        // The VIP waits for the vertical blank before drawing, so nothing runs after
+        // a draw until the next frame. Nothing runs after 00FD either
+        self.quirks.display_wait && matches!(instruction, Instruction::Drw { .. }) || self.kill_flag
+    }
+
+    /// Runs a single instruction and returns it. The timers are left alone
+    pub fn step(&mut self) -> Result<Instruction, Chip8Error> {
+        let instruction = self.peek()?;
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.trace(self, instruction);
+            self.tracer = Some(tracer);
+        }
+        self.execute(instruction)?;
+        Ok(instruction)
+    }
+
+    /// The instruction at the PC, the one that runs next
+    #[inline]
+    pub fn peek(&self) -> Result<Instruction, Chip8Error> {
+        self.fetch().map(decode)
+    }
+
+    /// The memory an instruction would read or write if it ran now
+    pub fn memory_access(&self, instruction: Instruction) -> Option<MemoryAccess> {
+        let index = self.index as usize;
+        let (write, start, len) = match instruction {
+            Instruction::Drw { n, .. } => (false, index, self.sprite_size(n as usize)),
+            Instruction::LdVxI(x) => (false, index, x + 1),
+            Instruction::Load { x, y } => (false, index, x.abs_diff(y) + 1),
+            Instruction::Audio => (false, index, 16),
+            Instruction::LdILong => (false, self.pc as usize + 2, 2),
+            Instruction::LdIVx(x) => (true, index, x + 1),
+            Instruction::Save { x, y } => (true, index, x.abs_diff(y) + 1),
+            Instruction::LdBVx(_) => (true, index, 3),
+            _ => return None,
+        };
+        Some(MemoryAccess {
+            write,
+            range: start..start + len,
+        })
+    }
+
+    #[inline]
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    #[inline]
+    pub fn get_index(&self) -> u16 {
+        self.index
+    }
+
+    #[inline]
+    pub fn get_registers(&self) -> &[u8; NUM_REGISTERS] {
+        &self.registers
+    }
+
+    /// The return addresses on the stack, the innermost last
+    #[inline]
+    pub fn get_stack(&self) -> &[u16] {
+        &self.stack[..self.stack_pointer as usize]
+    }
+
+    #[inline]
+    pub fn get_delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    #[inline]
+    pub fn get_sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    #[inline]
+    pub fn get_memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Changes a byte of memory from outside, e.g. in a debugger
+    pub fn write_memory(&mut self, address: usize, byte: u8) {
+        if let Some(cell) = self.memory.get_mut(address) {
+            *cell = byte;
+        }
+    }
+
+    /// Where `load_sprites` puts the fonts
+    #[inline]
+    pub fn font_range(&self) -> Range<usize> {
+        0..BIG_SPRITES_ADDR + BIG_SPRITES.len()
+    }
+
+    /// Where `load_program` put the program
+    #[inline]
+    pub fn program_range(&self) -> Range<usize> {
+        PROGRAM_START..self.program_end
+    }
+
+    #[inline]
+    fn fetch(&self) -> Result<u16, Chip8Error> {
+        let pc = self.pc as usize;
+        // Shift from the current location in memory 8 bits to the left,
+        // e.g. 0xFF << 8 = 0xFF00
+        let shifted: u16 = (self.memory[pc] as u16) << 8;
+        if pc + 1 >= MEMORY_SIZE {
+            return Err(Chip8Error::PcOutOfBounds {
+                pc: self.pc,
+                opcode: shifted,
+            });
+        }
+        // Or it with the next instruction
+        // e.g. 0xFF00 | 0x12 = 0xFF12
+        Ok(shifted | self.memory[pc + 1] as u16)
+    }
+
+    #[inline]
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            // Skip over the whole next instruction, the XO-CHIP F000 NNNN takes 4 bytes
+            let size = self.fetch().map_or(2, |opcode| decode(opcode).size());
+            self.pc = self.pc.wrapping_add(size);
+        }
+    }
+
+    #[inline]
+    pub fn interpret_instruction(&mut self, instruction: u16) -> Result<(), Chip8Error> {
+        self.execute(decode(instruction))
+    }
+
+    // On an error the PC is left on the faulting instruction
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), Chip8Error> {
+        let pc = self.pc;
+        let result = self.execute_at(pc, instruction);
+        if result.is_err() {
+            self.pc = pc;
+        }
+        result
+    }
+
+    fn execute_at(&mut self, pc: u16, instruction: Instruction) -> Result<(), Chip8Error> {
+        self.pc = self.pc.wrapping_add(2);
+        let opcode = instruction.encode();
+        let memory_range = |start: u16, len: usize| memory_range(start, len, pc, opcode);
+        match instruction {
+            Instruction::Cls => self.monitor.clear(),
+            Instruction::Ret => {
+                if self.stack_pointer == 0 {
+                    return Err(Chip8Error::StackUnderflow { pc, opcode });
                 }
-                _ => {}
-            },
-            0x1000 => {
-                self.pc = instruction & 0xFFF;
+                self.stack_pointer -= 1;
+                self.pc = self.stack[self.stack_pointer as usize];
             }
-            0x2000 => {
-                self.stack_pointer += 1;
+            Instruction::Scd(n) => self.monitor.scroll_down(n as usize),
+            Instruction::Scu(n) => self.monitor.scroll_up(n as usize),
+            Instruction::Scr => self.monitor.scroll_right(4),
+            Instruction::Scl => self.monitor.scroll_left(4),
+            Instruction::Exit => self.kill_flag = true,
+            Instruction::Low => self.monitor.set_hires(false),
+            Instruction::High => self.monitor.set_hires(true),
+            Instruction::Jp(addr) => self.pc = addr,
+            Instruction::Call(addr) => {
+                if self.stack_pointer as usize == self.stack.len() {
+                    return Err(Chip8Error::StackOverflow { pc, opcode });
+                }
                 self.stack[self.stack_pointer as usize] = self.pc;
-                self.pc = instruction & 0xFFF;
+                self.stack_pointer += 1;
+                self.pc = addr;
             }
-            0x3000 => {
-                if self.registers[x] == instruction as u8 {
-                    self.pc += 2;
+            Instruction::SeVxByte { x, byte } => self.skip_if(self.registers[x] == byte),
+            Instruction::SneVxByte { x, byte } => self.skip_if(self.registers[x] != byte),
+            Instruction::SeVxVy { x, y } => self.skip_if(self.registers[x] == self.registers[y]),
+            Instruction::Save { x, y } => {
+                let start = memory_range(self.index, x.abs_diff(y) + 1)?.start;
+                for (i, register) in register_range(x, y).enumerate() {
+                    self.memory[start + i] = self.registers[register];
                 }
             }
-            0x4000 => {
-                if self.registers[x] != instruction as u8 {
-                    self.pc += 2;
+            Instruction::Load { x, y } => {
+                let start = memory_range(self.index, x.abs_diff(y) + 1)?.start;
+                for (i, register) in register_range(x, y).enumerate() {
+                    self.registers[register] = self.memory[start + i];
                 }
             }
-            0x5000 => {
-                if self.registers[x] == self.registers[y] {
-                    self.pc += 2;
-                }
+            Instruction::LdVxByte { x, byte } => self.registers[x] = byte,
+            Instruction::AddVxByte { x, byte } => {
+                self.registers[x] = self.registers[x].wrapping_add(byte)
             }
-            0x6000 => {
-                self.registers[x] = instruction as u8;
+            Instruction::LdVxVy { x, y } => self.registers[x] = self.registers[y],
+            Instruction::Or { x, y } => {
+                self.registers[x] |= self.registers[y];
+                self.reset_vf();
             }
-            0x7000 => {
-                self.registers[x] = (self.registers[x] as u16 + (instruction & 0xFF)) as u8;
+            Instruction::And { x, y } => {
+                self.registers[x] &= self.registers[y];
+                self.reset_vf();
             }
-            0x8000 => match instruction & 0xF {
-                0x0 => self.registers[x] = self.registers[y],
-                0x1 => self.registers[x] |= self.registers[y],
-                0x2 => self.registers[x] &= self.registers[y],
-                0x3 => self.registers[x] ^= self.registers[y],
-                0x4 => {
-                    self.registers[0xF] =
-                        (self.registers[x] as u16 + self.registers[y] as u16 > 255) as u8;
-                    self.registers[x] = (self.registers[x] as u16 + self.registers[y] as u16) as u8;
-                }
-                0x5 => {
-                    self.registers[0xF] = (self.registers[x] > self.registers[y]) as u8;
-                    self.registers[x] = (self.registers[x] as i16 - self.registers[y] as i16) as u8;
-                }
-                0x6 => {
-                    self.registers[0xF] = self.registers[x] & 1;
-                    self.registers[x] = self.registers[y] >> 1;
-                }
-                0x7 => {
-                    self.registers[0xF] = (self.registers[x] < self.registers[y]) as u8;
-                    self.registers[x] = (self.registers[y] as i16 - self.registers[x] as i16) as u8;
-                }
-                0xE => {
-                    self.registers[0xF] = self.registers[x] & 0x80;
-                    self.registers[x] = self.registers[y] << 1;
-                }
-                _ => {}
-            },
-            0x9000 => {
-                if self.registers[x] != self.registers[y] {
-                    self.pc += 2;
-                }
+            Instruction::Xor { x, y } => {
+                self.registers[x] ^= self.registers[y];
+                self.reset_vf();
             }
-            0xA000 => {
-                self.index = instruction & 0xFFF;
+            // The flag is written after the result, so it wins when X is F
+            Instruction::AddVxVy { x, y } => {
+                let (sum, carry) = self.registers[x].overflowing_add(self.registers[y]);
+                self.registers[x] = sum;
+                self.registers[0xF] = carry as u8;
             }
-            0xB000 => {
-                self.pc += (instruction & 0xFFF) + self.registers[0] as u16;
+            Instruction::Sub { x, y } => {
+                let no_borrow = self.registers[x] >= self.registers[y];
+                self.registers[x] = self.registers[x].wrapping_sub(self.registers[y]);
+                self.registers[0xF] = no_borrow as u8;
             }
-            0xC000 => {
-                let rnd: u8 = thread_rng().gen_range(0..=255);
-                self.registers[x] = rnd & instruction as u8;
+            Instruction::Shr { x, y } => {
+                let source = self.shift_source(x, y);
+                self.registers[x] = source >> 1;
+                self.registers[0xF] = source & 1;
             }
-            0xD000 => {
-                // Set carry flag to 0
-                self.registers[0xF] = 0;
-                // Fetch the y coordinate
-                let mut c_y = self.registers[y] % 32;
-                // The last nibble (n) will dictate how much bytes we read from the memory
-                let n = instruction & 0xF;
-                // Iterate through n bytes of memory
-                for current_byte in 0..n {
-                    // Fetch the x coordinate
-                    let mut c_x = self.registers[x] % 64;
-                    // Read the byte the index is pointing to incremented by the number of bytes already read
-                    let mut sprite_byte = self.memory[(self.index + current_byte) as usize];
-                    // Iterate through the bits of the sprite_byte
-                    for _ in 0..8 {
-                        // If the MSB of the sprite byte is 1 we set/unset the pixel and toggle the flag accordingly
-                        if (sprite_byte & 0x80) > 0 {
-                            // And if the pixel is togled to 1, set carry to 1
-                            self.registers[0xF] =
-                                self.monitor.toggle_pixel(c_x as usize, c_y as usize) as u8;
-                        }
-                        // Shift the byte 1 bit to the left so we can read the next bit
-                        sprite_byte <<= 1;
-                        c_x += 1;
-                        if c_x > 63 {
-                            break;
-                        }
-                    }
-                    c_y += 1;
-                    if c_y > 31 {
-                        break;
-                    }
-                }
+            Instruction::Subn { x, y } => {
+                let no_borrow = self.registers[y] >= self.registers[x];
+                self.registers[x] = self.registers[y].wrapping_sub(self.registers[x]);
+                self.registers[0xF] = no_borrow as u8;
             }
-            0xE000 => match instruction & 0xFF {
-                0x9E => {
-                    if self.registers[x] == self.keyboard.check_key() as u8 {
-                        self.pc += 2;
-                    }
-                }
-                0xA1 => {
-                    if self.registers[x] != self.keyboard.check_key() as u8 {
-                        self.pc += 2;
-                    }
+            Instruction::Shl { x, y } => {
+                let source = self.shift_source(x, y);
+                self.registers[x] = source << 1;
+                self.registers[0xF] = source >> 7;
+            }
+            Instruction::SneVxVy { x, y } => self.skip_if(self.registers[x] != self.registers[y]),
+            Instruction::LdI(addr) => self.index = addr,
+            Instruction::JpV0(addr) => {
+                let offset = if self.quirks.jump_vx {
+                    self.registers[(addr >> 8) as usize]
+                } else {
+                    self.registers[0]
+                };
+                self.pc = addr + offset as u16;
+            }
+            Instruction::Rnd { x, byte } => {
+                let rnd = self.rng.next_byte(&self.memory);
+                self.registers[x] = rnd & byte;
+            }
+            Instruction::Drw { x, y, n } => {
+                memory_range(self.index, self.sprite_size(n as usize))?;
+                self.draw_sprite(x, y, n as usize);
+            }
+            Instruction::Skp(x) => self.skip_if(self.keyboard.is_down(self.registers[x])),
+            Instruction::Sknp(x) => self.skip_if(!self.keyboard.is_down(self.registers[x])),
+            Instruction::LdILong => {
+                // The address is the whole next word
+                if pc as usize + 4 > MEMORY_SIZE {
+                    return Err(Chip8Error::PcOutOfBounds { pc, opcode });
                 }
-                _ => {}
+                self.index = (self.memory[self.pc as usize] as u16) << 8
+                    | self.memory[self.pc as usize + 1] as u16;
+                self.pc += 2;
+            }
+            Instruction::Plane(n) => self.monitor.select_planes(n),
+            Instruction::Audio => {
+                let mut pattern = [0; 16];
+                pattern.copy_from_slice(&self.memory[memory_range(self.index, 16)?]);
+                self.audio_pattern = Some(pattern);
+            }
+            Instruction::LdVxDt(x) => self.registers[x] = self.delay_timer,
+            // Runs again until a key has been pressed and released
+            Instruction::LdVxK(x) => match self.keyboard.wait_for_key() {
+                Some(key) => self.registers[x] = key,
+                None => self.pc = pc,
             },
-            0xF000 => match instruction & 0xFF {
-                0x07 => self.registers[x] = self.delay_timer,
-                0x0A => {
-                    let key = self.keyboard.check_key();
-                    if self.keyboard.check_key() == Chip8Key::None {
-                        self.pc -= 2;
-                    } else {
-                        self.registers[x] = key as u8;
-                    }
-                }
-                0x15 => self.delay_timer = self.registers[x],
-                0x18 => self.sound_timer = self.registers[x],
-                0x1E => self.index += self.registers[x] as u16,
-                0x29 => {
-                    for i in 0..16 {
-                        if self.registers[x] == i {
-                            self.index = (i as usize * 5) as u16;
-                            break;
-                        }
-                    }
+            Instruction::LdDtVx(x) => self.delay_timer = self.registers[x],
+            Instruction::LdStVx(x) => self.sound_timer = self.registers[x],
+            Instruction::AddIVx(x) => {
+                self.index = self.index.wrapping_add(self.registers[x] as u16)
+            }
+            Instruction::LdFVx(x) => self.index = (self.registers[x] & 0xF) as u16 * 5,
+            Instruction::LdHfVx(x) => {
+                self.index = (BIG_SPRITES_ADDR + (self.registers[x] & 0xF) as usize * 10) as u16
+            }
+            Instruction::LdBVx(x) => {
+                let start = memory_range(self.index, 3)?.start;
+                let mut digit = self.registers[x];
+                for i in 0..3 {
+                    self.memory[start + 2 - i] = digit % 10;
+                    digit /= 10;
                 }
-                0x33 => {
-                    let mut digit = self.registers[x];
-                    for i in 0..3 {
-                        self.memory[(self.index + 2 - i) as usize] = digit % 10;
-                        digit /= 10;
-                    }
+            }
+            Instruction::Pitch(x) => self.pitch = self.registers[x],
+            Instruction::LdIVx(x) => {
+                self.memory[memory_range(self.index, x + 1)?]
+                    .copy_from_slice(&self.registers[..=x]);
+                self.increment_index(x);
+            }
+            Instruction::LdVxI(x) => {
+                self.registers[..=x]
+                    .copy_from_slice(&self.memory[memory_range(self.index, x + 1)?]);
+                self.increment_index(x);
+            }
+            Instruction::LdRVx(x) => self.rpl_flags[..=x].copy_from_slice(&self.registers[..=x]),
+            Instruction::LdVxR(x) => self.registers[..=x].copy_from_slice(&self.rpl_flags[..=x]),
+            Instruction::Unknown(_) => return Err(Chip8Error::UnknownOpcode { pc, opcode }),
+        }
+        Ok(())
+    }
+
+    // The number of bytes DXYN reads, one sprite for every selected plane
+    #[inline]
+    fn sprite_size(&self, n: usize) -> usize {
+        let planes = self.monitor.get_planes().count_ones() as usize;
+        if n == 0 {
+            planes * 32
+        } else {
+            planes * n
+        }
+    }
+
+    // DXYN, a height of 0 draws a 16x16 SUPER-CHIP sprite
+    fn draw_sprite(&mut self, x: usize, y: usize, n: usize) {
+        let (cols, rows) = self.monitor.get_res();
+        let (width, height) = if n == 0 { (16, 16) } else { (8, n) };
+        // Fetch the coordinates, the origin always wraps around
+        let origin_x = self.registers[x] as usize % cols;
+        let origin_y = self.registers[y] as usize % rows;
+        let planes = self.monitor.get_planes();
+        let mut address = self.index as usize;
+        let mut collided = [false; 16];
+        let mut clipped_rows = 0;
+        // XO-CHIP draws one sprite per selected plane, stored one after the other
+        for plane in [1, 2].into_iter().filter(|plane| planes & plane != 0) {
+            for (row, collided) in collided.iter_mut().enumerate().take(height) {
+                let c_y = origin_y + row;
+                if c_y >= rows && self.quirks.clip_sprites {
+                    clipped_rows = height - row;
+                    break;
                 }
-                0x55 => {
-                    for i in 0..=x {
-                        self.memory[self.index as usize + i] = self.registers[i];
+                // Read the row from memory, 16 pixel wide sprites take two bytes per row
+                let row_address = address + row * width / 8;
+                let mut sprite_row = if width == 16 {
+                    (self.memory[row_address] as u16) << 8 | self.memory[row_address + 1] as u16
+                } else {
+                    (self.memory[row_address] as u16) << 8
+                };
+                for col in 0..width {
+                    let c_x = origin_x + col;
+                    // Clipped sprites stop at the edge, otherwise the monitor wraps them around
+                    if c_x >= cols && self.quirks.clip_sprites {
+                        break;
                     }
-                }
-                0x65 => {
-                    for i in 0..=x {
-                        self.registers[i] = self.memory[self.index as usize + i];
+                    // If the MSB of the row is 1 we flip the pixel and note if it was turned off
+                    if sprite_row & 0x8000 > 0 {
+                        *collided |= self.monitor.toggle_plane_pixel(c_x, c_y, plane);
                     }
+                    sprite_row <<= 1;
                 }
-                _ => {}
-            },
-            _ => {}
+            }
+            address += height * width / 8;
+        }
+        let collided_rows = collided.iter().filter(|collided| **collided).count();
+        // SUPER-CHIP counts the rows that collided in hires mode, along with the ones
+        // clipped at the bottom, otherwise VF is a flag
+        self.registers[0xF] = if self.monitor.is_hires() && self.quirks.collision_rows {
+            (collided_rows + clipped_rows) as u8
+        } else {
+            (collided_rows > 0) as u8
+        };
+    }
+
+    // The VIP logic instructions clobber VF as a side effect
+    #[inline]
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+    }
+
+    // The register 8XY6/8XYE shifts, depending on the quirks
+    #[inline]
+    fn shift_source(&self, x: usize, y: usize) -> u8 {
+        if self.quirks.shift_vy {
+            self.registers[y]
+        } else {
+            self.registers[x]
+        }
+    }
+
+    // Moves I past the registers FX55/FX65 just stored or loaded
+    #[inline]
+    fn increment_index(&mut self, x: usize) {
+        match self.quirks.index_increment {
+            IndexIncrement::XPlusOne => self.index = self.index.wrapping_add(x as u16 + 1),
+            IndexIncrement::X => self.index = self.index.wrapping_add(x as u16),
+            IndexIncrement::Unchanged => {}
         }
     }
 }
 
+// The memory an instruction accesses, as long as all of it fits
+fn memory_range(start: u16, len: usize, pc: u16, opcode: u16) -> Result<Range<usize>, Chip8Error> {
+    let start = start as usize;
+    if start + len > MEMORY_SIZE {
+        return Err(Chip8Error::MemoryOutOfBounds {
+            pc,
+            opcode,
+            address: start,
+        });
+    }
+    Ok(start..start + len)
+}
+
+// The registers 5XY2/5XY3 go through, in order, which is descending if X > Y
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -335,6 +801,7 @@ mod tests {
         assert_eq!(d, 14);
     }
     use super::*;
+    use rand::{thread_rng, Rng as _};
     #[test]
     fn bool_sanity() {
         assert_eq!(true as u8, 1);
@@ -473,4 +940,395 @@ mod tests {
         }
         assert_eq!(monitor.get_buffer()[234], 1);
     }
+
+    #[test]
+    fn shift_quirk() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.registers[1] = 0b0000_0011;
+        chip8.registers[2] = 0b1000_0000;
+        chip8.interpret_instruction(0x812E).unwrap();
+        assert_eq!(chip8.registers[1], 0);
+        assert_eq!(chip8.registers[0xF], 1);
+
+        chip8.set_quirks(Quirks::SUPER_CHIP);
+        chip8.registers[1] = 0b0000_0011;
+        chip8.interpret_instruction(0x8126).unwrap();
+        assert_eq!(chip8.registers[1], 1);
+        assert_eq!(chip8.registers[0xF], 1);
+    }
+
+    #[test]
+    fn load_store_quirk() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.index = 0x300;
+        chip8.interpret_instruction(0xF355).unwrap();
+        assert_eq!(chip8.index, 0x304);
+
+        chip8.set_quirks(Quirks::CHIP_48);
+        chip8.interpret_instruction(0xF365).unwrap();
+        assert_eq!(chip8.index, 0x307);
+
+        chip8.set_quirks(Quirks::SUPER_CHIP);
+        chip8.interpret_instruction(0xF355).unwrap();
+        assert_eq!(chip8.index, 0x307);
+    }
+
+    #[test]
+    fn jump_quirk() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.registers[0] = 0x10;
+        chip8.registers[3] = 0x20;
+        chip8.interpret_instruction(0xB300).unwrap();
+        assert_eq!(chip8.pc, 0x310);
+
+        chip8.set_quirks(Quirks::CHIP_48);
+        chip8.interpret_instruction(0xB300).unwrap();
+        assert_eq!(chip8.pc, 0x320);
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        // Equal operands don't borrow
+        chip8.registers[1] = 10;
+        chip8.registers[2] = 10;
+        chip8.interpret_instruction(0x8125).unwrap();
+        assert_eq!((chip8.registers[1], chip8.registers[0xF]), (0, 1));
+        chip8.registers[1] = 10;
+        chip8.interpret_instruction(0x8127).unwrap();
+        assert_eq!((chip8.registers[1], chip8.registers[0xF]), (0, 1));
+        chip8.registers[1] = 3;
+        chip8.interpret_instruction(0x8125).unwrap();
+        assert_eq!((chip8.registers[1], chip8.registers[0xF]), (249, 0));
+
+        // With VF as the destination the flag is what's left in it
+        chip8.registers[0xF] = 3;
+        chip8.registers[2] = 10;
+        chip8.interpret_instruction(0x8F25).unwrap();
+        assert_eq!(chip8.registers[0xF], 0);
+        chip8.registers[0xF] = 3;
+        chip8.interpret_instruction(0x8F27).unwrap();
+        assert_eq!(chip8.registers[0xF], 1);
+        chip8.registers[0xF] = 0xFF;
+        chip8.interpret_instruction(0x8F24).unwrap();
+        assert_eq!(chip8.registers[0xF], 1);
+        chip8.registers[0xF] = 1;
+        chip8.interpret_instruction(0x8F24).unwrap();
+        assert_eq!(chip8.registers[0xF], 0);
+    }
+
+    #[test]
+    fn sprite_wrapping_quirk() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.load_sprites();
+        chip8.registers[0] = 62;
+        chip8.registers[1] = 0;
+        // The sprite for 0 is 4 pixels wide, so it crosses the right edge
+        chip8.interpret_instruction(0xD015).unwrap();
+        assert_eq!(chip8.monitor.get_buffer()[0], 0);
+
+        chip8.monitor.clear();
+        chip8.set_quirks(Quirks::XO_CHIP);
+        chip8.interpret_instruction(0xD015).unwrap();
+        assert_eq!(chip8.monitor.get_buffer()[0], 1);
+    }
+
+    #[test]
+    fn super_chip_sprites() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::SUPER_CHIP);
+        chip8.load_sprites();
+        chip8.interpret_instruction(0x00FF).unwrap();
+        assert!(chip8.monitor.is_hires());
+        // A 16x16 sprite of solid rows
+        chip8.index = 0x300;
+        for i in 0..32 {
+            chip8.memory[0x300 + i] = 0xFF;
+        }
+        chip8.registers[0] = 120;
+        chip8.registers[1] = 60;
+        chip8.interpret_instruction(0xD010).unwrap();
+        assert_eq!(chip8.monitor.buffer[127 + 63 * 128], 1);
+        assert_eq!(chip8.registers[0xF], 12);
+        // Drawing it again collides on the 4 visible rows and the 12 clipped ones
+        chip8.interpret_instruction(0xD010).unwrap();
+        assert_eq!(chip8.registers[0xF], 16);
+        assert_eq!(chip8.monitor.buffer[127 + 63 * 128], 0);
+    }
+
+    #[test]
+    fn xo_chip_collision_flag() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::XO_CHIP);
+        chip8.interpret_instruction(0x00FF).unwrap();
+        chip8.index = 0x300;
+        for i in 0..32 {
+            chip8.memory[0x300 + i] = 0xFF;
+        }
+        chip8.registers[1] = 60;
+        chip8.interpret_instruction(0xD010).unwrap();
+        assert_eq!(chip8.registers[0xF], 0);
+        // The rows that wrapped around collide too, but VF is only a flag
+        chip8.interpret_instruction(0xD010).unwrap();
+        assert_eq!(chip8.registers[0xF], 1);
+    }
+
+    #[test]
+    fn super_chip_flags_and_font() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::SUPER_CHIP);
+        chip8.load_sprites();
+        chip8.registers[..4].copy_from_slice(&[1, 2, 3, 4]);
+        chip8.interpret_instruction(0xF375).unwrap();
+        chip8.registers = [0; NUM_REGISTERS];
+        chip8.interpret_instruction(0xF285).unwrap();
+        assert_eq!(chip8.registers[..4], [1, 2, 3, 0]);
+        chip8.interpret_instruction(0xF130).unwrap();
+        assert_eq!(chip8.index as usize, BIG_SPRITES_ADDR + 20);
+        assert_eq!(chip8.memory[chip8.index as usize], 0x3E);
+        chip8.interpret_instruction(0x00FD).unwrap();
+        assert!(chip8.kill_flag);
+    }
+
+    #[test]
+    fn xo_chip_long_index_and_skip() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::XO_CHIP);
+        chip8.load_program(&[0x30, 0x00, 0xF0, 0x00, 0xAB, 0xCD, 0xF0, 0x00, 0x12, 0x34]);
+        // V0 == 0 so the whole of the long load is skipped
+        chip8.interpret_instruction(0x3000).unwrap();
+        assert_eq!(chip8.pc, 0x206);
+        chip8.interpret_instruction(0xF000).unwrap();
+        assert_eq!(chip8.index, 0x1234);
+        assert_eq!(chip8.pc, 0x20A);
+    }
+
+    #[test]
+    fn xo_chip_register_ranges() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::XO_CHIP);
+        chip8.index = 0x400;
+        chip8.registers[2..6].copy_from_slice(&[1, 2, 3, 4]);
+        chip8.interpret_instruction(0x5252).unwrap();
+        assert_eq!(chip8.memory[0x400..0x404], [1, 2, 3, 4]);
+        chip8.interpret_instruction(0x5A73).unwrap();
+        assert_eq!(chip8.registers[7..=0xA], [4, 3, 2, 1]);
+        assert_eq!(chip8.index, 0x400);
+    }
+
+    #[test]
+    fn xo_chip_planes_and_audio() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::XO_CHIP);
+        chip8.index = 0x400;
+        chip8.memory[0x400] = 0x80;
+        chip8.memory[0x401] = 0x80;
+        chip8.interpret_instruction(0xF301).unwrap();
+        chip8.interpret_instruction(0xD011).unwrap();
+        assert_eq!(chip8.monitor.buffer[0], 3);
+        chip8.interpret_instruction(0xF201).unwrap();
+        chip8.interpret_instruction(0xD011).unwrap();
+        assert_eq!(chip8.monitor.buffer[0], 1);
+        assert_eq!(chip8.registers[0xF], 1);
+
+        chip8.interpret_instruction(0xF002).unwrap();
+        assert_eq!(chip8.get_audio_pattern().unwrap()[..2], [0x80, 0x80]);
+        chip8.registers[4] = 112;
+        chip8.interpret_instruction(0xF43A).unwrap();
+        assert_eq!(chip8.get_pitch(), 112);
+    }
+
+    #[test]
+    fn execution_errors() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        assert_eq!(
+            chip8.interpret_instruction(0x00EE),
+            Err(Chip8Error::StackUnderflow {
+                pc: 0x200,
+                opcode: 0x00EE
+            })
+        );
+        for _ in 0..16 {
+            chip8.interpret_instruction(0x2200).unwrap();
+        }
+        assert_eq!(
+            chip8.interpret_instruction(0x2200),
+            Err(Chip8Error::StackOverflow {
+                pc: 0x200,
+                opcode: 0x2200
+            })
+        );
+        assert_eq!(
+            chip8.interpret_instruction(0x5121),
+            Err(Chip8Error::UnknownOpcode {
+                pc: 0x200,
+                opcode: 0x5121
+            })
+        );
+        // The PC stays on the faulting instruction
+        assert_eq!(chip8.pc, 0x200);
+        chip8.index = 0xFFFE;
+        assert_eq!(
+            chip8.interpret_instruction(0xF255),
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: 0x200,
+                opcode: 0xF255,
+                address: 0xFFFE
+            })
+        );
+        chip8.pc = 0xFFFF;
+        assert_eq!(
+            chip8.fetch(),
+            Err(Chip8Error::PcOutOfBounds {
+                pc: 0xFFFF,
+                opcode: 0
+            })
+        );
+    }
+
+    #[test]
+    fn save_states() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.load_program(&[0x60, 0x05, 0xD0, 0x05]);
+        chip8.interpret_instruction(0x6A07).unwrap();
+        chip8.interpret_instruction(0x2300).unwrap();
+        chip8.monitor.set_hires(true);
+        chip8.monitor.toggle_pixel(100, 50);
+        let state = chip8.save_state();
+
+        chip8.interpret_instruction(0x6A00).unwrap();
+        chip8.monitor.set_hires(false);
+        chip8.load_state(&state).unwrap();
+        assert_eq!(chip8.registers[0xA], 7);
+        assert_eq!(chip8.pc, 0x300);
+        assert_eq!(chip8.stack_pointer, 1);
+        assert!(chip8.monitor.is_hires());
+        assert_eq!(chip8.monitor.buffer[100 + 50 * 128], 1);
+
+        // A state of another ROM leaves the machine alone
+        let mut other = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        other.load_program(&[0x12, 0x00]);
+        assert!(matches!(
+            other.load_state(&state),
+            Err(StateError::RomMismatch { .. })
+        ));
+        assert_eq!(other.pc, 0x200);
+    }
+
+    #[test]
+    fn seeded_random() {
+        let run = |seed| {
+            let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+            chip8.set_rng(Box::new(XorShift::new(seed)));
+            (0..16)
+                .map(|_| {
+                    chip8.interpret_instruction(0xC0FF).unwrap();
+                    chip8.registers[0]
+                })
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+
+        // The generator continues where it was when the state was saved
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.set_rng(Box::new(rng::MemoryRng::new(99)));
+        let state = chip8.save_state();
+        chip8.interpret_instruction(0xC0FF).unwrap();
+        let first = chip8.registers[0];
+        chip8.set_rng(Box::new(XorShift::new(1)));
+        chip8.load_state(&state).unwrap();
+        assert_eq!(chip8.get_seed(), 99);
+        chip8.interpret_instruction(0xC0FF).unwrap();
+        assert_eq!(chip8.registers[0], first);
+    }
+
+    #[test]
+    fn memory_regions() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        chip8.load_sprites();
+        chip8.load_program(&[0x12, 0x00, 0xAA]);
+        assert_eq!(chip8.font_range(), 0..0xF0);
+        assert_eq!(chip8.program_range(), 0x200..0x203);
+        assert_eq!(chip8.get_memory()[0xEF], BIG_SPRITES[159]);
+        chip8.write_memory(0x202, 0xBB);
+        chip8.write_memory(MEMORY_SIZE, 0xBB);
+        assert_eq!(chip8.get_memory()[0x202], 0xBB);
+    }
+
+    #[test]
+    fn headless_cycle() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::CHIP_48);
+        chip8.load_sprites();
+        // Wait for key 5 into V0, draw its digit at 0,0 and exit
+        chip8.load_program(&[0xF0, 0x0A, 0xF0, 0x29, 0xD1, 0x15, 0x00, 0xFD]);
+        chip8.cycle().unwrap();
+        assert_eq!(chip8.pc, 0x200);
+        chip8.set_keys(1 << 5);
+        chip8.cycle().unwrap();
+        // FX0A waits for the key to be released as well
+        assert_eq!(chip8.pc, 0x200);
+        chip8.set_keys(0);
+        chip8.cycle().unwrap();
+        assert!(chip8.exit_requested());
+        assert_eq!(chip8.registers[0], 5);
+        // The top row of the 5 is 0xF0
+        assert_eq!(chip8.monitor.get_buffer()[..5], [1, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn timers_run_at_60_hz() {
+        for ips in [120, 700, 2000] {
+            let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::CHIP_48);
+            chip8.set_ips(ips);
+            // Set DT to 60 and spin forever
+            chip8.load_program(&[0x60, 0x3C, 0xF0, 0x15, 0x12, 0x04]);
+            chip8.cycle().unwrap();
+            assert_eq!(chip8.delay_timer, 59);
+            for _ in 0..59 {
+                chip8.cycle().unwrap();
+            }
+            assert_eq!(chip8.delay_timer, 0);
+        }
+        // Steps don't touch the timers
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::CHIP_48);
+        chip8.load_program(&[0x60, 0x3C, 0xF0, 0x15, 0x12, 0x04]);
+        for _ in 0..10 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.delay_timer, 60);
+    }
+
+    #[test]
+    fn vip_timing() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::CHIP_48);
+        chip8.set_timing(TimingMode::Vip);
+        // Count in V0 forever
+        chip8.load_program(&[0x70, 0x01, 0x12, 0x00]);
+        chip8.cycle().unwrap();
+        let add = timing::vip_cycles(decode(0x7001), &[0; 16], false);
+        let jump = timing::vip_cycles(decode(0x1200), &[0; 16], false);
+        let free = timing::VIP_FRAME_CYCLES - timing::VIP_INTERRUPT_CYCLES;
+        assert_eq!(chip8.registers[0] as u32, free.div_ceil(add + jump));
+
+        // A draw waits for the next frame
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::COSMAC_VIP);
+        chip8.set_timing(TimingMode::Vip);
+        chip8.load_program(&[0x70, 0x01, 0xD0, 0x01, 0x12, 0x00]);
+        chip8.cycle().unwrap();
+        assert_eq!(chip8.registers[0], 1);
+        assert_eq!(chip8.pc, 0x204);
+    }
+
+    #[test]
+    fn multiple_keys() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::CHIP_48);
+        chip8.registers[1] = 0x4;
+        chip8.registers[2] = 0x6;
+        chip8.set_keys(1 << 0x4 | 1 << 0x6);
+        for (opcode, skips) in [(0xE19E, true), (0xE29E, true), (0xE1A1, false)] {
+            chip8.pc = 0x200;
+            chip8.interpret_instruction(opcode).unwrap();
+            assert_eq!(chip8.pc, if skips { 0x204 } else { 0x202 });
+        }
+        // Held keys stay held from one frame to the next
+        chip8.load_program(&[0x12, 0x00]);
+        chip8.pc = 0x200;
+        chip8.cycle().unwrap();
+        assert_eq!(chip8.keyboard.get_keys(), 1 << 0x4 | 1 << 0x6);
+    }
 }
@@ -0,0 +1,625 @@
+use crate::assembler;
+use crate::chip8::Chip8;
+use crate::debugger::{self, Condition, Debugger, StopReason};
+use crate::error::Chip8Error;
+use crate::monitor::Monitor;
+use crate::octo;
+use crate::quirks::Quirks;
+use crate::rng::XorShift;
+use crate::symbols::{self, SourceLocation, SymbolMap};
+use crate::timing::DEFAULT_IPS;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::path::Path;
+
+// The machine has a single flow of control
+const THREAD_ID: u64 = 1;
+// The variable references of the scopes, the same for every frame
+const REGISTERS: u64 = 1;
+const STACK: u64 = 2;
+const TIMERS: u64 = 3;
+/// The exception breakpoint filter that stops on opcodes that don't decode
+pub const UNKNOWN_OPCODE_FILTER: &str = "unknown-opcode";
+
+/// Reads a message of the Debug Adapter Protocol, a JSON body behind a Content-Length
+/// header. Returns None at the end of the input
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length =
+        length.ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Writes a message of the Debug Adapter Protocol
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// Reads the program with its symbols. Octo and assembler sources are compiled, a ROM
+// gets the symbol map next to it when there is one
+fn load_program(path: &Path) -> Result<(Vec<u8>, Option<SymbolMap>), String> {
+    let compiled = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("8o") => Some(octo::compile_file_with_symbols(path)),
+        Some("asm") => Some(assembler::assemble_file_with_symbols(path)),
+        _ => None,
+    };
+    if let Some(compiled) = compiled {
+        return compiled
+            .map(|(rom, symbols)| (rom, Some(symbols)))
+            .map_err(|err| err.to_string());
+    }
+    let rom = fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+    let map_path = symbols::path_for(path);
+    let symbols = match fs::read_to_string(&map_path) {
+        Ok(text) => Some(
+            text.parse()
+                .map_err(|err| format!("{}: {}", map_path.display(), err))?,
+        ),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(format!("Couldn't read {}: {}", map_path.display(), err)),
+    };
+    Ok((rom, symbols))
+}
+
+// A breakpoint as the client set it, a source line or an address
+#[derive(Clone, Debug)]
+struct Breakpoint {
+    address: u16,
+    condition: Option<Condition>,
+}
+
+/// One debugging session of a ROM for a client speaking the Debug Adapter Protocol.
+/// Requests go into `handle` and the machine runs a frame per `run_frame`, the
+/// responses and events pile up until `take_output`. The transport is up to the caller
+pub struct Session {
+    chip8: Option<Chip8>,
+    debugger: Debugger,
+    symbols: Option<SymbolMap>,
+    // The breakpoints of each source file and the ones set by address, all of them
+    // are handed to the debugger whenever one set changes
+    source_breakpoints: HashMap<String, Vec<Breakpoint>>,
+    instruction_breakpoints: Vec<Breakpoint>,
+    stop_on_entry: bool,
+    // The machine only runs once the client is done setting breakpoints
+    configured: bool,
+    // Set when the machine halts, it can't go on from there
+    error: Option<Chip8Error>,
+    exited: bool,
+    finished: bool,
+    seq: u64,
+    output: Vec<Value>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            chip8: None,
+            debugger: Debugger::new(),
+            symbols: None,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            configured: false,
+            error: None,
+            exited: false,
+            finished: false,
+            seq: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Whether the client has disconnected
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    #[inline]
+    pub fn chip8(&self) -> Option<&Chip8> {
+        self.chip8.as_ref()
+    }
+
+    /// The responses and events to send, in order
+    pub fn take_output(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        self.output.push(message);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn stopped(&mut self, reason: &str, description: String) {
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    /// Answers a request. Events it causes follow the response
+    pub fn handle(&mut self, request: &Value) {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => {
+                let filters = arguments["filters"].as_array();
+                let unknown = filters.is_some_and(|filters| {
+                    filters.iter().any(|filter| filter == UNKNOWN_OPCODE_FILTER)
+                });
+                self.debugger.set_break_on_unknown(unknown);
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                    { "name": "Timers", "variablesReference": TIMERS, "expensive": false },
+                ]
+            })),
+            "variables" => self.variables(arguments),
+            "continue" | "next" | "stepIn" | "stepOut" => self.resume(command),
+            "pause" => {
+                self.debugger.pause();
+                Ok(json!({}))
+            }
+            "disconnect" | "terminate" => {
+                self.finished = true;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported command '{}'", command)),
+        };
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+        // The client learns about the machine stopping after it hears back
+        match command {
+            "launch" if self.chip8.is_some() => self.event("initialized", json!({})),
+            "configurationDone" if self.stop_on_entry => {
+                self.debugger.pause();
+                self.stopped("entry", "Stopped on entry".to_string());
+            }
+            "pause" => self.stopped("pause", StopReason::Paused.to_string()),
+            "terminate" => self.event("terminated", json!({})),
+            _ => {}
+        }
+    }
+
+    /// Runs a frame if the machine is running, and tells the client when it stops
+    pub fn run_frame(&mut self) {
+        if !self.configured || self.exited || self.error.is_some() {
+            return;
+        }
+        let Some(chip8) = &mut self.chip8 else {
+            return;
+        };
+        match self.debugger.cycle(chip8) {
+            Ok(true) => {
+                let reason = self
+                    .debugger
+                    .stopped()
+                    .cloned()
+                    .unwrap_or(StopReason::Paused);
+                let kind = match reason {
+                    StopReason::Breakpoint(_) => "breakpoint",
+                    StopReason::Watchpoint(_) => "data breakpoint",
+                    StopReason::Step => "step",
+                    StopReason::UnknownOpcode(_) => "exception",
+                    StopReason::Paused => "pause",
+                };
+                self.stopped(kind, reason.to_string());
+            }
+            Ok(false) if chip8.exit_requested() => {
+                self.exited = true;
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            }
+            Ok(false) => {}
+            Err(err) => {
+                self.stopped("exception", err.to_string());
+                self.error = Some(err);
+            }
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program = arguments["program"]
+            .as_str()
+            .ok_or("launch needs the path of a program")?;
+        let quirks = match arguments["quirks"].as_str() {
+            Some(name) => Quirks::PRESETS
+                .iter()
+                .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+                .map(|(_, quirks)| *quirks)
+                .ok_or_else(|| format!("Unknown quirks '{}'", name))?,
+            None => Quirks::default(),
+        };
+        let (rom, symbols) = load_program(Path::new(program))?;
+        let mut chip8 = Chip8::new(Monitor::new_default(), quirks);
+        chip8.load_sprites();
+        chip8.load_program(&rom);
+        // Every run of a session makes the same random numbers unless told otherwise
+        chip8.set_rng(Box::new(XorShift::new(
+            arguments["seed"].as_u64().unwrap_or(0),
+        )));
+        let ips = arguments["ips"].as_u64().unwrap_or(DEFAULT_IPS as u64);
+        chip8.set_ips(ips.clamp(1, u32::MAX as u64) as u32);
+        self.chip8 = Some(chip8);
+        self.symbols = symbols;
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["source"]["path"]
+            .as_str()
+            .ok_or("setBreakpoints needs a source path")?;
+        let mut set = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let address = self
+                .symbols
+                .as_ref()
+                .ok_or("The program has no symbol map")
+                .and_then(|map| map.address(Some(path), line).ok_or("No code on this line"));
+            let result = address.map_err(str::to_string).and_then(|address| {
+                let condition = parse_condition(&breakpoint["condition"])?;
+                set.push(Breakpoint { address, condition });
+                Ok(address)
+            });
+            results.push(match result {
+                Ok(address) => {
+                    let location = self.symbols.as_ref().and_then(|map| map.location(address));
+                    json!({
+                        "verified": true,
+                        "line": location.map_or(line, |location| location.line),
+                        "instructionReference": format!("{:#05X}", address),
+                    })
+                }
+                Err(message) => json!({ "verified": false, "line": line, "message": message }),
+            });
+        }
+        self.source_breakpoints.insert(path.to_string(), set);
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let mut set = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let reference = breakpoint["instructionReference"].as_str().unwrap_or("");
+            let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+            let result = debugger::parse_number(reference)
+                .map(|address| address as i64 + offset)
+                .filter(|address| (0..=0xFFFF).contains(address))
+                .ok_or(format!("Invalid address '{}'", reference))
+                .and_then(|address| {
+                    let condition = parse_condition(&breakpoint["condition"])?;
+                    set.push(Breakpoint {
+                        address: address as u16,
+                        condition,
+                    });
+                    Ok(address)
+                });
+            results.push(match result {
+                Ok(address) => json!({
+                    "verified": true,
+                    "instructionReference": format!("{:#05X}", address),
+                }),
+                Err(message) => json!({ "verified": false, "message": message }),
+            });
+        }
+        self.instruction_breakpoints = set;
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn sync_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+        let sets = self.source_breakpoints.values().flatten();
+        for breakpoint in sets.chain(&self.instruction_breakpoints) {
+            self.debugger
+                .add_breakpoint(breakpoint.address, breakpoint.condition);
+        }
+    }
+
+    fn resume(&mut self, command: &str) -> Result<Value, String> {
+        let chip8 = self.chip8.as_ref().ok_or("No program is running")?;
+        if let Some(err) = &self.error {
+            return Err(format!("The machine halted: {}", err));
+        }
+        match command {
+            "next" => self.debugger.step_over(chip8),
+            "stepIn" => self.debugger.step_into(),
+            "stepOut" => self.debugger.step_out(chip8),
+            _ => self.debugger.resume(),
+        }
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    // The PC, then the call of every subroutine on the stack, innermost first
+    fn stack_trace(&self) -> Result<Value, String> {
+        let chip8 = self.chip8.as_ref().ok_or("No program is running")?;
+        let stack = chip8.get_stack();
+        let mut addresses = vec![chip8.get_pc()];
+        addresses.extend(stack.iter().rev().map(|address| address.wrapping_sub(2)));
+        let frames: Vec<Value> = addresses
+            .iter()
+            .enumerate()
+            .map(|(id, address)| self.frame(id, *address))
+            .collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    // A frame is named after the label the code is under, usually its subroutine
+    fn frame(&self, id: usize, address: u16) -> Value {
+        let name = self
+            .symbols
+            .as_ref()
+            .and_then(|map| map.label_before(address))
+            .map_or_else(|| format!("{:#05X}", address), str::to_string);
+        let location = self.symbols.as_ref().and_then(|map| map.location(address));
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": location.map_or(0, |location| location.line),
+            "column": if location.is_some() { 1 } else { 0 },
+            "instructionPointerReference": format!("{:#05X}", address),
+        });
+        if let Some(source) = location.and_then(source) {
+            frame["source"] = source;
+        }
+        frame
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let chip8 = self.chip8.as_ref().ok_or("No program is running")?;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS) => {
+                let registers = chip8.get_registers().iter().enumerate();
+                let mut variables: Vec<Value> = registers
+                    .map(|(x, value)| variable(format!("V{:X}", x), format!("{:#04X}", value)))
+                    .collect();
+                variables.push(variable("I".to_string(), self.address(chip8.get_index())));
+                variables.push(variable("PC".to_string(), self.address(chip8.get_pc())));
+                variables
+            }
+            Some(STACK) => chip8
+                .get_stack()
+                .iter()
+                .enumerate()
+                .map(|(depth, address)| variable(depth.to_string(), self.address(*address)))
+                .collect(),
+            Some(TIMERS) => vec![
+                variable("Delay".to_string(), chip8.get_delay_timer().to_string()),
+                variable("Sound".to_string(), chip8.get_sound_timer().to_string()),
+            ],
+            _ => return Err("Unknown variables reference".to_string()),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    // An address with its label when it has one
+    fn address(&self, address: u16) -> String {
+        match self.label(address) {
+            Some(label) => format!("{:#05X} ({})", address, label),
+            None => format!("{:#05X}", address),
+        }
+    }
+
+    fn label(&self, address: u16) -> Option<String> {
+        let map = self.symbols.as_ref()?;
+        map.label_at(address).map(str::to_string)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsConditionalBreakpoints": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsTerminateRequest": true,
+        "exceptionBreakpointFilters": [{
+            "filter": UNKNOWN_OPCODE_FILTER,
+            "label": "Unknown opcodes",
+            "default": false,
+        }],
+    })
+}
+
+// A missing or empty condition is no condition
+fn parse_condition(condition: &Value) -> Result<Option<Condition>, String> {
+    match condition.as_str().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(condition) => condition.parse().map(Some),
+    }
+}
+
+// The source of a location, clients want absolute paths
+fn source(location: &SourceLocation) -> Option<Value> {
+    let file = location.file.as_ref()?;
+    let path = fs::canonicalize(file).unwrap_or_else(|_| file.into());
+    let name = path.file_name()?.to_string_lossy().to_string();
+    Some(json!({ "name": name, "path": path.display().to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "
+: main
+    v0 := 0
+    add-one
+    add-one
+    exit
+
+: add-one
+    v0 += 1
+    return
+";
+
+    fn request(session: &mut Session, command: &str, arguments: Value) -> Vec<Value> {
+        session.handle(&json!({
+            "seq": 1,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        }));
+        session.take_output()
+    }
+
+    fn run_until_stopped(session: &mut Session) -> Value {
+        for _ in 0..10 {
+            session.run_frame();
+            let output = session.take_output();
+            if let Some(event) = output.into_iter().find(|event| event["event"] == "stopped") {
+                return event;
+            }
+        }
+        panic!("the machine didn't stop");
+    }
+
+    #[test]
+    fn framing() {
+        let message = json!({ "seq": 1, "type": "request", "command": "threads" });
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &message).unwrap();
+        write_message(&mut bytes, &message).unwrap();
+        let mut input = &bytes[..];
+        assert_eq!(read_message(&mut input).unwrap(), Some(message.clone()));
+        assert_eq!(read_message(&mut input).unwrap(), Some(message));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+        let mut input = &b"Content-Type: json\r\n\r\n{}"[..];
+        assert!(read_message(&mut input).is_err());
+    }
+
+    #[test]
+    fn session() {
+        let dir = std::env::temp_dir().join(format!("chip8-dap-session-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.8o");
+        fs::write(&path, SOURCE).unwrap();
+        let path = path.display().to_string();
+
+        let mut session = Session::new();
+        let output = request(&mut session, "initialize", json!({}));
+        assert_eq!(output[0]["body"]["supportsConditionalBreakpoints"], true);
+        let output = request(&mut session, "launch", json!({ "program": path }));
+        assert_eq!(output[0]["success"], true);
+        assert_eq!(output[1]["event"], "initialized");
+        let arguments = json!({
+            "source": { "path": path },
+            "breakpoints": [{ "line": 8, "condition": "V0 == 1" }, { "line": 100 }],
+        });
+        let output = request(&mut session, "setBreakpoints", arguments);
+        let breakpoints = &output[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        // The label has no code, the breakpoint moves to the line after it
+        assert_eq!(breakpoints[0]["line"], 9);
+        assert_eq!(breakpoints[1]["verified"], false);
+        request(&mut session, "configurationDone", json!({}));
+
+        // The condition passes over the first call
+        let stopped = run_until_stopped(&mut session);
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        let output = request(&mut session, "stackTrace", json!({ "threadId": 1 }));
+        let frames = &output[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "add-one");
+        assert_eq!(frames[0]["line"], 9);
+        assert_eq!(frames[1]["name"], "main");
+        assert_eq!(frames[1]["line"], 5);
+        assert!(frames[0]["source"]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("game.8o"));
+        let output = request(
+            &mut session,
+            "variables",
+            json!({ "variablesReference": 1 }),
+        );
+        assert_eq!(output[0]["body"]["variables"][0]["value"], "0x01");
+        let output = request(
+            &mut session,
+            "variables",
+            json!({ "variablesReference": 2 }),
+        );
+        assert_eq!(output[0]["body"]["variables"].as_array().unwrap().len(), 1);
+
+        request(&mut session, "stepOut", json!({ "threadId": 1 }));
+        let stopped = run_until_stopped(&mut session);
+        assert_eq!(stopped["body"]["reason"], "step");
+        assert_eq!(session.chip8().unwrap().get_registers()[0], 2);
+
+        request(&mut session, "continue", json!({ "threadId": 1 }));
+        session.run_frame();
+        let output = session.take_output();
+        assert_eq!(output[0]["event"], "exited");
+        assert_eq!(output[1]["event"], "terminated");
+
+        let output = request(&mut session, "evaluate", json!({}));
+        assert_eq!(output[0]["success"], false);
+        request(&mut session, "disconnect", json!({}));
+        assert!(session.is_finished());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,611 @@
+use crate::chip8::{Chip8, MemoryAccess};
+use crate::error::Chip8Error;
+use crate::instruction::Instruction;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Reads a number the way the assembler writes them: decimal, 0x hex or 0b binary
+pub fn parse_number(text: &str) -> Option<u16> {
+    let lower = text.trim().to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+/// What a condition looks at
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(usize),
+    Index,
+    Memory(u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Compare {
+    const SYMBOLS: [(&'static str, Compare); 6] = [
+        ("==", Compare::Eq),
+        ("!=", Compare::Ne),
+        ("<=", Compare::Le),
+        (">=", Compare::Ge),
+        ("<", Compare::Lt),
+        (">", Compare::Gt),
+    ];
+
+    fn holds(self, left: u16, right: u16) -> bool {
+        match self {
+            Compare::Eq => left == right,
+            Compare::Ne => left != right,
+            Compare::Lt => left < right,
+            Compare::Le => left <= right,
+            Compare::Gt => left > right,
+            Compare::Ge => left >= right,
+        }
+    }
+}
+
+/// A test on the machine a breakpoint only stops for when it holds, written like
+/// `V3 == 0x10`, `I >= 0x300` or `[0x300] != 0`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub compare: Compare,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn holds(&self, chip8: &Chip8) -> bool {
+        let left = match self.operand {
+            Operand::Register(x) => chip8.get_registers()[x] as u16,
+            Operand::Index => chip8.get_index(),
+            Operand::Memory(address) => chip8
+                .get_memory()
+                .get(address as usize)
+                .map_or(0, |byte| *byte as u16),
+        };
+        self.compare.holds(left, self.value)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid condition '{}'", text);
+        let (at, symbol, compare) = Compare::SYMBOLS
+            .iter()
+            .filter_map(|(symbol, compare)| text.find(symbol).map(|at| (at, *symbol, *compare)))
+            .min_by_key(|(at, symbol, _)| (*at, usize::MAX - symbol.len()))
+            .ok_or_else(invalid)?;
+        let left = text[..at].trim();
+        let value = parse_number(&text[at + symbol.len()..]).ok_or_else(invalid)?;
+        let operand = if left.eq_ignore_ascii_case("i") {
+            Operand::Index
+        } else if let Some(address) = left
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            Operand::Memory(parse_number(address).ok_or_else(invalid)?)
+        } else {
+            let register = left
+                .strip_prefix(['V', 'v'])
+                .filter(|x| x.len() == 1)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .ok_or_else(invalid)?;
+            Operand::Register(register as usize)
+        };
+        Ok(Condition {
+            operand,
+            compare,
+            value,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operand {
+            Operand::Register(x) => write!(f, "V{:X}", x)?,
+            Operand::Index => write!(f, "I")?,
+            Operand::Memory(address) => write!(f, "[{:#05X}]", address)?,
+        }
+        let symbol = Compare::SYMBOLS
+            .iter()
+            .find(|(_, compare)| *compare == self.compare)
+            .map_or("", |(symbol, _)| symbol);
+        write!(f, " {} {:#X}", symbol, self.value)
+    }
+}
+
+/// The kind of access a watchpoint stops for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Stops before an instruction that touches a range of memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<usize>,
+    pub watch: Watch,
+}
+
+impl Watchpoint {
+    fn triggers(&self, access: &MemoryAccess) -> bool {
+        let kind = match self.watch {
+            Watch::Read => !access.write,
+            Watch::Write => access.write,
+            Watch::ReadWrite => true,
+        };
+        kind && access.range.start < self.range.end && self.range.start < access.range.end
+    }
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    /// Reads `<start>[-<end>][:r|w|rw]`, the end is inclusive and reads and writes are
+    /// both watched unless one is picked
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid watchpoint '{}'", text);
+        let (range, watch) = match text.rsplit_once(':') {
+            Some((range, "r")) => (range, Watch::Read),
+            Some((range, "w")) => (range, Watch::Write),
+            Some((range, "rw")) => (range, Watch::ReadWrite),
+            Some(_) => return Err(invalid()),
+            None => (text, Watch::ReadWrite),
+        };
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start = parse_number(start).ok_or_else(invalid)? as usize;
+        let end = parse_number(end).ok_or_else(invalid)? as usize;
+        if end < start {
+            return Err(invalid());
+        }
+        Ok(Watchpoint {
+            range: start..end + 1,
+            watch,
+        })
+    }
+}
+
+/// Why the debugger stopped the machine. It always stops before the instruction at the
+/// PC runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Watchpoint(MemoryAccess),
+    /// A step or a run to an address finished
+    Step,
+    UnknownOpcode(u16),
+    Paused,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint(address) => write!(f, "Breakpoint at {:#05X}", address),
+            StopReason::Watchpoint(access) => write!(
+                f,
+                "{} {:#05X}-{:#05X}",
+                if access.write { "Write to" } else { "Read of" },
+                access.range.start,
+                access.range.end.saturating_sub(1)
+            ),
+            StopReason::Step => write!(f, "Stepped"),
+            StopReason::UnknownOpcode(opcode) => write!(f, "Unknown opcode {:#06X}", opcode),
+            StopReason::Paused => write!(f, "Paused"),
+        }
+    }
+}
+
+// Where the machine runs to after a resume
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Mode {
+    Run,
+    StepInto,
+    // Until the subroutine called at depth returns to the address
+    StepOver { depth: usize, address: u16 },
+    // Until the stack is shallower than depth
+    StepOut { depth: usize },
+    RunTo(u16),
+}
+
+/// Breakpoints, watchpoints and stepping on top of `Chip8::cycle_until`. While the
+/// debugger is stopped, `cycle` leaves the machine alone, timers included
+pub struct Debugger {
+    breakpoints: BTreeMap<u16, Option<Condition>>,
+    watchpoints: Vec<Watchpoint>,
+    break_on_unknown: bool,
+    mode: Mode,
+    stopped: Option<StopReason>,
+    // The first instruction after a resume always runs, even from a breakpoint
+    resumed: bool,
+    // Whether that instruction is the one it stopped at, so its breakpoint is passed
+    skip_checks: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            break_on_unknown: false,
+            mode: Mode::Run,
+            stopped: None,
+            resumed: false,
+            skip_checks: false,
+        }
+    }
+
+    /// Adds a breakpoint, or replaces the one at the address
+    pub fn add_breakpoint(&mut self, address: u16, condition: Option<Condition>) {
+        self.breakpoints.insert(address, condition);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    /// Adds an unconditional breakpoint, or removes the one at the address
+    pub fn toggle_breakpoint(&mut self, address: u16) {
+        if !self.remove_breakpoint(address) {
+            self.add_breakpoint(address, None);
+        }
+    }
+
+    #[inline]
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains_key(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (u16, Option<&Condition>)> {
+        self.breakpoints
+            .iter()
+            .map(|(address, condition)| (*address, condition.as_ref()))
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    #[inline]
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    /// Stops before instructions that don't decode instead of letting them halt the machine
+    #[inline]
+    pub fn set_break_on_unknown(&mut self, enabled: bool) {
+        self.break_on_unknown = enabled;
+    }
+
+    #[inline]
+    pub fn stopped(&self) -> Option<&StopReason> {
+        self.stopped.as_ref()
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.is_some()
+    }
+
+    /// Stops between frames
+    pub fn pause(&mut self) {
+        if self.stopped.is_none() {
+            self.stopped = Some(StopReason::Paused);
+        }
+    }
+
+    /// Runs until something stops it
+    pub fn resume(&mut self) {
+        self.start(Mode::Run);
+    }
+
+    /// Runs one instruction
+    pub fn step_into(&mut self) {
+        self.start(Mode::StepInto);
+    }
+
+    /// Runs one instruction, or a whole subroutine when it's a call
+    pub fn step_over(&mut self, chip8: &Chip8) {
+        let mode = match chip8.peek() {
+            Ok(instruction @ Instruction::Call(_)) => Mode::StepOver {
+                depth: chip8.get_stack().len(),
+                address: chip8.get_pc().wrapping_add(instruction.size()),
+            },
+            _ => Mode::StepInto,
+        };
+        self.start(mode);
+    }
+
+    /// Runs until the current subroutine returns. Outside of one it's a resume
+    pub fn step_out(&mut self, chip8: &Chip8) {
+        self.start(Mode::StepOut {
+            depth: chip8.get_stack().len(),
+        });
+    }
+
+    /// Runs until the PC gets to the address
+    pub fn run_to(&mut self, address: u16) {
+        self.start(Mode::RunTo(address));
+    }
+
+    fn start(&mut self, mode: Mode) {
+        self.mode = mode;
+        // A pause happens between instructions, nothing stopped the one at the PC
+        if self
+            .stopped
+            .take()
+            .is_some_and(|reason| reason != StopReason::Paused)
+        {
+            self.skip_checks = true;
+        }
+        self.resumed = true;
+    }
+
+    /// Runs a frame of the machine unless the debugger is stopped. Returns true when it
+    /// stopped during the frame, which is then left unfinished
+    pub fn cycle(&mut self, chip8: &mut Chip8) -> Result<bool, Chip8Error> {
+        if self.stopped.is_some() {
+            return Ok(false);
+        }
+        let stopped = chip8.cycle_until(|chip8, instruction| {
+            let first = std::mem::take(&mut self.resumed);
+            let skip = first && std::mem::take(&mut self.skip_checks);
+            self.stopped = self.check(chip8, instruction, first, skip);
+            self.stopped.is_some()
+        })?;
+        Ok(stopped)
+    }
+
+    // Whether to stop before the instruction
+    fn check(
+        &mut self,
+        chip8: &Chip8,
+        instruction: Instruction,
+        first: bool,
+        skip: bool,
+    ) -> Option<StopReason> {
+        let pc = chip8.get_pc();
+        if !skip {
+            if let Instruction::Unknown(opcode) = instruction {
+                if self.break_on_unknown {
+                    return Some(StopReason::UnknownOpcode(opcode));
+                }
+            }
+            if let Some(condition) = self.breakpoints.get(&pc) {
+                if condition.is_none_or(|condition| condition.holds(chip8)) {
+                    return Some(StopReason::Breakpoint(pc));
+                }
+            }
+            if let Some(access) = chip8.memory_access(instruction) {
+                if self.watchpoints.iter().any(|watch| watch.triggers(&access)) {
+                    return Some(StopReason::Watchpoint(access));
+                }
+            }
+        }
+        if first {
+            return None;
+        }
+        let depth = chip8.get_stack().len();
+        let done = match self.mode {
+            Mode::Run => false,
+            Mode::StepInto => true,
+            Mode::StepOver {
+                depth: call,
+                address,
+            } => pc == address && depth <= call,
+            Mode::StepOut { depth: call } => depth < call,
+            Mode::RunTo(address) => pc == address,
+        };
+        if done {
+            self.mode = Mode::Run;
+            return Some(StopReason::Step);
+        }
+        None
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The instructions around an address for a disassembly view, `before` of them ahead of
+/// it. Going backwards assumes every instruction is two bytes
+pub fn listing(chip8: &Chip8, address: u16, before: u16, count: usize) -> Vec<(u16, Instruction)> {
+    let memory = chip8.get_memory();
+    let mut address = address.saturating_sub(before.saturating_mul(2));
+    let mut lines = Vec::with_capacity(count);
+    while lines.len() < count && (address as usize) + 1 < memory.len() {
+        let at = address as usize;
+        let instruction =
+            crate::instruction::decode(u16::from_be_bytes([memory[at], memory[at + 1]]));
+        lines.push((address, instruction));
+        // The listing ends with the end of memory
+        match address.checked_add(instruction.size()) {
+            Some(next) => address = next,
+            None => break,
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::Monitor;
+    use crate::quirks::Quirks;
+
+    const ROM: [u8; 16] = [
+        0x60, 0x00, // 200: V0 = 0
+        0x22, 0x0A, // 202: call 20A
+        0xA3, 0x00, // 204: I = 300
+        0xF0, 0x55, // 206: save V0
+        0x12, 0x08, // 208: jump 208
+        0x70, 0x01, // 20A: V0 += 1
+        0x00, 0xEE, // 20C: return
+        0xFF, 0xFF, // 20E: unknown
+    ];
+
+    fn machine() -> Chip8 {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        chip8.load_program(&ROM);
+        chip8
+    }
+
+    #[test]
+    fn conditions() {
+        let condition: Condition = "V3 == 0x10".parse().unwrap();
+        assert_eq!(condition.operand, Operand::Register(3));
+        assert_eq!(condition.compare, Compare::Eq);
+        assert_eq!(condition.value, 0x10);
+        let condition: Condition = "i>=768".parse().unwrap();
+        assert_eq!(
+            (condition.operand, condition.compare),
+            (Operand::Index, Compare::Ge)
+        );
+        let condition: Condition = "[0x300] != 0".parse().unwrap();
+        assert_eq!(condition.operand, Operand::Memory(0x300));
+        assert_eq!(condition.to_string(), "[0x300] != 0x0");
+        assert_eq!("VA < 2".parse::<Condition>().unwrap().compare, Compare::Lt);
+        assert!("VG == 1".parse::<Condition>().is_err());
+        assert!("V0 = 1".parse::<Condition>().is_err());
+
+        let watchpoint: Watchpoint = "0x300-0x30F:w".parse().unwrap();
+        assert_eq!(watchpoint.range, 0x300..0x310);
+        assert_eq!(watchpoint.watch, Watch::Write);
+        assert_eq!("768".parse::<Watchpoint>().unwrap().range, 0x300..0x301);
+        assert!("0x310-0x300".parse::<Watchpoint>().is_err());
+        assert!("0x300:x".parse::<Watchpoint>().is_err());
+
+        let chip8 = machine();
+        assert!("[0x200] == 0x60"
+            .parse::<Condition>()
+            .unwrap()
+            .holds(&chip8));
+        assert!(!"V0 > 0".parse::<Condition>().unwrap().holds(&chip8));
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let mut chip8 = machine();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x20A, None);
+        assert!(debugger.cycle(&mut chip8).unwrap());
+        assert_eq!(debugger.stopped(), Some(&StopReason::Breakpoint(0x20A)));
+        assert_eq!(chip8.get_pc(), 0x20A);
+        // Nothing runs while stopped
+        assert!(!debugger.cycle(&mut chip8).unwrap());
+        assert_eq!(chip8.get_pc(), 0x20A);
+
+        debugger.step_out(&chip8);
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(debugger.stopped(), Some(&StopReason::Step));
+        assert_eq!((chip8.get_pc(), chip8.get_registers()[0]), (0x204, 1));
+        debugger.step_into();
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(chip8.get_pc(), 0x206);
+
+        debugger.add_watchpoint(Watchpoint {
+            range: 0x300..0x301,
+            watch: Watch::Read,
+        });
+        debugger.add_watchpoint(Watchpoint {
+            range: 0x2FF..0x301,
+            watch: Watch::Write,
+        });
+        // The watchpoint at the PC is passed when resuming
+        debugger.run_to(0x208);
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(debugger.stopped(), Some(&StopReason::Step));
+        assert_eq!(chip8.get_memory()[0x300], 1);
+
+        // A call is stepped over as a whole, a condition that doesn't hold is passed
+        let mut chip8 = machine();
+        debugger.clear_watchpoints();
+        debugger.add_breakpoint(0x20A, Some("V0 == 1".parse().unwrap()));
+        debugger.step_into();
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(chip8.get_pc(), 0x202);
+        debugger.step_over(&chip8);
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(debugger.stopped(), Some(&StopReason::Step));
+        assert_eq!((chip8.get_pc(), chip8.get_stack().len()), (0x204, 0));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut chip8 = machine();
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watchpoint {
+            range: 0x300..0x310,
+            watch: Watch::ReadWrite,
+        });
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(
+            debugger.stopped(),
+            Some(&StopReason::Watchpoint(MemoryAccess {
+                write: true,
+                range: 0x300..0x301
+            }))
+        );
+        assert_eq!(chip8.get_pc(), 0x206);
+        // A pause happens between frames and stops nothing
+        debugger.resume();
+        debugger.pause();
+        assert_eq!(debugger.stopped(), Some(&StopReason::Paused));
+        debugger.resume();
+        debugger.cycle(&mut chip8).unwrap();
+        assert!(!debugger.is_stopped());
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        let mut chip8 = machine();
+        chip8.load_program(&[0x12, 0x0E]);
+        let mut debugger = Debugger::new();
+        debugger.set_break_on_unknown(true);
+        debugger.cycle(&mut chip8).unwrap();
+        assert_eq!(debugger.stopped(), Some(&StopReason::UnknownOpcode(0xFFFF)));
+        assert_eq!(chip8.get_pc(), 0x20E);
+
+        let lines = listing(&chip8, 0x20E, 2, 3);
+        assert_eq!(
+            lines,
+            vec![
+                (0x20A, Instruction::AddVxByte { x: 0, byte: 1 }),
+                (0x20C, Instruction::Ret),
+                (0x20E, Instruction::Unknown(0xFFFF)),
+            ]
+        );
+        let lines = listing(&chip8, 0xFFF8, 0, 8);
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3].0, 0xFFFE);
+    }
+}
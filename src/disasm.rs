@@ -0,0 +1,342 @@
+use crate::instruction::{decode, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Where programs are loaded and start executing
+pub const ORIGIN: u16 = 0x200;
+// The most of a ROM that fits between the origin and the end of memory
+const MAX_LEN: usize = 0x10000 - ORIGIN as usize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ByteKind {
+    // Never reached by following the code
+    Data,
+    // The first byte of an instruction
+    Code,
+    // The rest of an instruction
+    Operand,
+    // Read by a DXYN, width in pixels
+    Sprite(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LabelKind {
+    Jump,
+    Subroutine,
+    Data,
+    Sprite,
+}
+
+/// What every byte of a ROM is, found by following jumps and calls from the origin
+pub struct Analysis {
+    pub kinds: Vec<ByteKind>,
+    pub labels: BTreeMap<u16, LabelKind>,
+}
+
+impl Analysis {
+    pub fn new(rom: &[u8]) -> Self {
+        let rom = &rom[..rom.len().min(MAX_LEN)];
+        let mut analysis = Analysis {
+            kinds: vec![ByteKind::Data; rom.len()],
+            labels: BTreeMap::new(),
+        };
+        let mut sprites = Vec::new();
+        let mut pending = vec![ORIGIN];
+        let mut visited = BTreeSet::new();
+        while let Some(start) = pending.pop() {
+            if !visited.insert(start) {
+                continue;
+            }
+            // Walk the block until the flow of control leaves it
+            let mut address = start;
+            // The I register as far as it's known in this block
+            let mut index = None;
+            while let Some(instruction) = analysis.claim(rom, address) {
+                // None when the instruction ends memory
+                let next = address.checked_add(instruction.size());
+                match instruction {
+                    Instruction::Jp(target) => {
+                        analysis.label(target, LabelKind::Jump);
+                        pending.push(target);
+                        break;
+                    }
+                    Instruction::Call(target) => {
+                        analysis.label(target, LabelKind::Subroutine);
+                        pending.push(target);
+                    }
+                    // The target depends on a register, so only the base is known
+                    Instruction::JpV0(target) => {
+                        analysis.label(target, LabelKind::Jump);
+                        pending.push(target);
+                        break;
+                    }
+                    Instruction::Ret | Instruction::Exit => break,
+                    // Both the next and the one after it can run
+                    Instruction::SeVxByte { .. }
+                    | Instruction::SneVxByte { .. }
+                    | Instruction::SeVxVy { .. }
+                    | Instruction::SneVxVy { .. }
+                    | Instruction::Skp(_)
+                    | Instruction::Sknp(_) => {
+                        if let Some(next) = next {
+                            let skipped = analysis.peek(rom, next).map_or(2, |i| i.size());
+                            pending.extend(next.checked_add(skipped));
+                        }
+                    }
+                    Instruction::LdI(target) => {
+                        analysis.label(target, LabelKind::Data);
+                        index = Some(target);
+                    }
+                    Instruction::LdILong => {
+                        let target = word(rom, address.wrapping_add(2)).unwrap_or(0);
+                        analysis.label(target, LabelKind::Data);
+                        index = Some(target);
+                    }
+                    Instruction::AddIVx(_)
+                    | Instruction::LdFVx(_)
+                    | Instruction::LdHfVx(_)
+                    | Instruction::LdIVx(_)
+                    | Instruction::LdVxI(_) => index = None,
+                    Instruction::Drw { n, .. } => {
+                        if let Some(target) = index {
+                            sprites.push((target, n));
+                        }
+                    }
+                    _ => {}
+                }
+                match next {
+                    Some(next) => address = next,
+                    None => break,
+                }
+            }
+        }
+        // Mark the sprites last, the code found takes precedence
+        for (target, n) in sprites {
+            let (width, len) = if n == 0 { (16, 32) } else { (8, n as u16) };
+            analysis.label(target, LabelKind::Sprite);
+            for address in (0..len).map_while(|row| target.checked_add(row)) {
+                if let Some(kind) = analysis.kind_mut(address) {
+                    if *kind == ByteKind::Data {
+                        *kind = ByteKind::Sprite(width);
+                    }
+                }
+            }
+        }
+        analysis
+    }
+
+    // Marks the instruction at the address as code, unless it overlaps other code or
+    // doesn't look like an instruction
+    fn claim(&mut self, rom: &[u8], address: u16) -> Option<Instruction> {
+        if self.kind(address) == Some(ByteKind::Code) {
+            return None;
+        }
+        let instruction = self.peek(rom, address)?;
+        if let Instruction::Unknown(_) = instruction {
+            return None;
+        }
+        let offset = (address - ORIGIN) as usize;
+        let bytes = self
+            .kinds
+            .get_mut(offset..offset + instruction.size() as usize)?;
+        if bytes.iter().any(|kind| *kind != ByteKind::Data) {
+            return None;
+        }
+        bytes[0] = ByteKind::Code;
+        bytes[1..].fill(ByteKind::Operand);
+        Some(instruction)
+    }
+
+    fn peek(&self, rom: &[u8], address: u16) -> Option<Instruction> {
+        word(rom, address).map(decode)
+    }
+
+    fn label(&mut self, address: u16, kind: LabelKind) {
+        // Subroutines win over jumps, code wins over sprites and sprites over plain data
+        let rank = |kind: LabelKind| match kind {
+            LabelKind::Data => 0,
+            LabelKind::Sprite => 1,
+            LabelKind::Jump => 2,
+            LabelKind::Subroutine => 3,
+        };
+        let label = self.labels.entry(address).or_insert(kind);
+        if rank(kind) > rank(*label) {
+            *label = kind;
+        }
+    }
+
+    pub fn kind(&self, address: u16) -> Option<ByteKind> {
+        let offset = address.checked_sub(ORIGIN)? as usize;
+        self.kinds.get(offset).copied()
+    }
+
+    fn kind_mut(&mut self, address: u16) -> Option<&mut ByteKind> {
+        let offset = address.checked_sub(ORIGIN)? as usize;
+        self.kinds.get_mut(offset)
+    }
+
+    /// The name of the label at the address, as long as a line of the listing starts there
+    pub fn label_name(&self, address: u16) -> Option<String> {
+        let kind = self.labels.get(&address)?;
+        if !matches!(
+            self.kind(address)?,
+            ByteKind::Code | ByteKind::Data | ByteKind::Sprite(_)
+        ) {
+            return None;
+        }
+        let prefix = match kind {
+            LabelKind::Jump => "label",
+            LabelKind::Subroutine => "sub",
+            LabelKind::Data => "data",
+            LabelKind::Sprite => "sprite",
+        };
+        Some(format!("{}_{:03X}", prefix, address))
+    }
+}
+
+fn word(rom: &[u8], address: u16) -> Option<u16> {
+    let offset = address.checked_sub(ORIGIN)? as usize;
+    let bytes = rom.get(offset..offset + 2)?;
+    Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+/// Formats the instruction with the addresses it refers to replaced by labels
+pub fn format_instruction(instruction: Instruction, operand: u16, analysis: &Analysis) -> String {
+    let target = |address: u16| {
+        analysis
+            .label_name(address)
+            .unwrap_or_else(|| format!("{:#05X}", address))
+    };
+    match instruction {
+        Instruction::Jp(address) => format!("JP {}", target(address)),
+        Instruction::Call(address) => format!("CALL {}", target(address)),
+        Instruction::LdI(address) => format!("LD I, {}", target(address)),
+        Instruction::JpV0(address) => format!("JP V0, {}", target(address)),
+        Instruction::LdILong => match analysis.label_name(operand) {
+            Some(label) => format!("LD I, LONG {}", label),
+            None => format!("LD I, LONG {:#06X}", operand),
+        },
+        _ => instruction.to_string(),
+    }
+}
+
+/// Disassembles the ROM into a listing the assembler reads back into the same bytes.
+/// Every line is commented with its address and raw bytes
+pub fn disassemble(rom: &[u8]) -> String {
+    // Whatever is past the end of memory can't be loaded
+    let rom = &rom[..rom.len().min(MAX_LEN)];
+    let analysis = Analysis::new(rom);
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = ORIGIN + offset as u16;
+        if let Some(label) = analysis.label_name(address) {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        let (text, size, comment) = match analysis.kinds[offset] {
+            ByteKind::Code => {
+                let instruction = decode(word(rom, address).unwrap());
+                let operand = word(rom, address.wrapping_add(2)).unwrap_or(0);
+                let raw = if instruction.size() == 4 {
+                    format!("{:04X} {:04X}", instruction.encode(), operand)
+                } else {
+                    format!("{:04X}", instruction.encode())
+                };
+                let text = format_instruction(instruction, operand, &analysis);
+                (text, instruction.size(), raw)
+            }
+            // Sprites are drawn as bitmaps, one row per line
+            ByteKind::Sprite(_) => {
+                let byte = rom[offset];
+                let bitmap: String = (0..8)
+                    .map(|bit| if byte & (0x80 >> bit) > 0 { '#' } else { '.' })
+                    .collect();
+                (format!("db {:#010b}", byte), 1, bitmap)
+            }
+            // Anything else is grouped into lines of up to 8 bytes
+            _ => {
+                let mut len = 1;
+                while len < 8
+                    && offset + len < rom.len()
+                    && analysis.kinds[offset + len] == ByteKind::Data
+                    && analysis.label_name(address + len as u16).is_none()
+                {
+                    len += 1;
+                }
+                let bytes: Vec<String> = rom[offset..offset + len]
+                    .iter()
+                    .map(|byte| format!("{:#04X}", byte))
+                    .collect();
+                let raw: String = rom[offset..offset + len]
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect();
+                (format!("db {}", bytes.join(", ")), len as u16, raw)
+            }
+        };
+        writeln!(out, "    {:<32} ; {:03X}  {}", text, address, comment).unwrap();
+        offset += size as usize;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CLS, LD I sprite, DRW, CALL sub, JP to itself, the sub returns, then the sprite
+    const ROM: [u8; 14] = [
+        0x00, 0xE0, 0xA2, 0x0C, 0xD0, 0x12, 0x22, 0x0A, 0x12, 0x08, 0x00, 0xEE, 0xF0, 0x90,
+    ];
+
+    #[test]
+    fn code_and_data() {
+        let analysis = Analysis::new(&ROM);
+        assert_eq!(analysis.kind(0x200), Some(ByteKind::Code));
+        assert_eq!(analysis.kind(0x201), Some(ByteKind::Operand));
+        assert_eq!(analysis.kind(0x20A), Some(ByteKind::Code));
+        assert_eq!(analysis.kind(0x20C), Some(ByteKind::Sprite(8)));
+        assert_eq!(analysis.kind(0x20D), Some(ByteKind::Sprite(8)));
+        assert_eq!(analysis.label_name(0x20A).unwrap(), "sub_20A");
+        assert_eq!(analysis.label_name(0x208).unwrap(), "label_208");
+        assert_eq!(analysis.label_name(0x20C).unwrap(), "sprite_20C");
+    }
+
+    #[test]
+    fn listing() {
+        let listing = disassemble(&ROM);
+        let lines: Vec<&str> = listing.lines().map(|line| line.trim()).collect();
+        assert!(lines[1].starts_with("LD I, sprite_20C"));
+        assert!(lines.contains(&"label_208:"));
+        assert!(lines.iter().any(|line| line.starts_with("JP label_208")));
+        assert!(lines.iter().any(|line| line.ends_with("####....")));
+    }
+
+    #[test]
+    fn skips_follow_both_paths() {
+        // SE V0, 0 then a long I load that's only reached when not skipped
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xEE];
+        let analysis = Analysis::new(&rom);
+        assert_eq!(analysis.kind(0x202), Some(ByteKind::Code));
+        assert_eq!(analysis.kind(0x204), Some(ByteKind::Operand));
+        assert_eq!(analysis.kind(0x206), Some(ByteKind::Code));
+    }
+
+    #[test]
+    fn end_of_memory() {
+        // A sprite that runs past the end of memory, LD V0, 0 all the way up to a skip
+        // and a CLS in the last two bytes
+        let mut rom = [0x60, 0x00].repeat(MAX_LEN / 2);
+        rom[..6].copy_from_slice(&[0xF0, 0x00, 0xFF, 0xFE, 0xD0, 0x05]);
+        rom[MAX_LEN - 4..].copy_from_slice(&[0x30, 0x00, 0x00, 0xE0]);
+        let analysis = Analysis::new(&rom);
+        assert_eq!(analysis.kind(0xFFFC), Some(ByteKind::Code));
+        assert_eq!(analysis.kind(0xFFFE), Some(ByteKind::Code));
+        assert_eq!(analysis.kind(0xFFFF), Some(ByteKind::Operand));
+        let listing = disassemble(&rom);
+        assert!(listing.lines().last().unwrap().contains("; FFFE  00E0"));
+        // Bytes past the end of memory are left out
+        rom.push(0xFF);
+        assert_eq!(disassemble(&rom), listing);
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+
+/// Why the machine had to stop, with the address and opcode of the offending instruction
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Chip8Error {
+    // 2NNN with all 16 stack entries in use
+    StackOverflow {
+        pc: u16,
+        opcode: u16,
+    },
+    // 00EE with an empty stack
+    StackUnderflow {
+        pc: u16,
+        opcode: u16,
+    },
+    // The instruction, or the operand of a long one, runs past the end of memory.
+    // The opcode holds whatever part of it could still be read
+    PcOutOfBounds {
+        pc: u16,
+        opcode: u16,
+    },
+    // The access starting at address runs past the end of memory
+    MemoryOutOfBounds {
+        pc: u16,
+        opcode: u16,
+        address: usize,
+    },
+    UnknownOpcode {
+        pc: u16,
+        opcode: u16,
+    },
+}
+
+impl Chip8Error {
+    pub fn pc(&self) -> u16 {
+        match *self {
+            Chip8Error::StackOverflow { pc, .. }
+            | Chip8Error::StackUnderflow { pc, .. }
+            | Chip8Error::PcOutOfBounds { pc, .. }
+            | Chip8Error::MemoryOutOfBounds { pc, .. }
+            | Chip8Error::UnknownOpcode { pc, .. } => pc,
+        }
+    }
+
+    pub fn opcode(&self) -> u16 {
+        match *self {
+            Chip8Error::StackOverflow { opcode, .. }
+            | Chip8Error::StackUnderflow { opcode, .. }
+            | Chip8Error::PcOutOfBounds { opcode, .. }
+            | Chip8Error::MemoryOutOfBounds { opcode, .. }
+            | Chip8Error::UnknownOpcode { opcode, .. } => opcode,
+        }
+    }
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Chip8Error::StackOverflow { .. } => write!(f, "Stack overflow")?,
+            Chip8Error::StackUnderflow { .. } => write!(f, "Stack underflow")?,
+            Chip8Error::PcOutOfBounds { .. } => write!(f, "PC out of bounds")?,
+            Chip8Error::MemoryOutOfBounds { address, .. } => {
+                write!(f, "Memory access out of bounds at {:#06X}", address)?
+            }
+            Chip8Error::UnknownOpcode { .. } => write!(f, "Unknown opcode")?,
+        }
+        write!(f, " (PC:{:#05X} opcode:{:04X})", self.pc(), self.opcode())
+    }
+}
+
+impl std::error::Error for Chip8Error {}
@@ -0,0 +1,298 @@
+use std::fmt;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction.
+/// X and Y are register numbers, addresses are the 12 bits of NNN
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,                               // 00E0
+    Ret,                               // 00EE
+    Scd(u8),                           // 00CN
+    Scu(u8),                           // 00DN
+    Scr,                               // 00FB
+    Scl,                               // 00FC
+    Exit,                              // 00FD
+    Low,                               // 00FE
+    High,                              // 00FF
+    Jp(u16),                           // 1NNN
+    Call(u16),                         // 2NNN
+    SeVxByte { x: usize, byte: u8 },   // 3XNN
+    SneVxByte { x: usize, byte: u8 },  // 4XNN
+    SeVxVy { x: usize, y: usize },     // 5XY0
+    Save { x: usize, y: usize },       // 5XY2
+    Load { x: usize, y: usize },       // 5XY3
+    LdVxByte { x: usize, byte: u8 },   // 6XNN
+    AddVxByte { x: usize, byte: u8 },  // 7XNN
+    LdVxVy { x: usize, y: usize },     // 8XY0
+    Or { x: usize, y: usize },         // 8XY1
+    And { x: usize, y: usize },        // 8XY2
+    Xor { x: usize, y: usize },        // 8XY3
+    AddVxVy { x: usize, y: usize },    // 8XY4
+    Sub { x: usize, y: usize },        // 8XY5
+    Shr { x: usize, y: usize },        // 8XY6
+    Subn { x: usize, y: usize },       // 8XY7
+    Shl { x: usize, y: usize },        // 8XYE
+    SneVxVy { x: usize, y: usize },    // 9XY0
+    LdI(u16),                          // ANNN
+    JpV0(u16),                         // BNNN
+    Rnd { x: usize, byte: u8 },        // CXNN
+    Drw { x: usize, y: usize, n: u8 }, // DXYN
+    Skp(usize),                        // EX9E
+    Sknp(usize),                       // EXA1
+    LdILong,                           // F000 NNNN, the address is the next word
+    Plane(u8),                         // FN01
+    Audio,                             // F002
+    LdVxDt(usize),                     // FX07
+    LdVxK(usize),                      // FX0A
+    LdDtVx(usize),                     // FX15
+    LdStVx(usize),                     // FX18
+    AddIVx(usize),                     // FX1E
+    LdFVx(usize),                      // FX29
+    LdHfVx(usize),                     // FX30
+    LdBVx(usize),                      // FX33
+    Pitch(usize),                      // FX3A
+    LdIVx(usize),                      // FX55
+    LdVxI(usize),                      // FX65
+    LdRVx(usize),                      // FX75
+    LdVxR(usize),                      // FX85
+    Unknown(u16),
+}
+
+pub fn decode(opcode: u16) -> Instruction {
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let n = (opcode & 0xF) as u8;
+    let byte = opcode as u8;
+    let addr = opcode & 0xFFF;
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            0x00C0..=0x00CF => Instruction::Scd(n),
+            0x00D0..=0x00DF => Instruction::Scu(n),
+            0x00FB => Instruction::Scr,
+            0x00FC => Instruction::Scl,
+            0x00FD => Instruction::Exit,
+            0x00FE => Instruction::Low,
+            0x00FF => Instruction::High,
+            _ => Instruction::Unknown(opcode),
+        },
+        0x1000 => Instruction::Jp(addr),
+        0x2000 => Instruction::Call(addr),
+        0x3000 => Instruction::SeVxByte { x, byte },
+        0x4000 => Instruction::SneVxByte { x, byte },
+        0x5000 => match n {
+            0x0 => Instruction::SeVxVy { x, y },
+            0x2 => Instruction::Save { x, y },
+            0x3 => Instruction::Load { x, y },
+            _ => Instruction::Unknown(opcode),
+        },
+        0x6000 => Instruction::LdVxByte { x, byte },
+        0x7000 => Instruction::AddVxByte { x, byte },
+        0x8000 => match n {
+            0x0 => Instruction::LdVxVy { x, y },
+            0x1 => Instruction::Or { x, y },
+            0x2 => Instruction::And { x, y },
+            0x3 => Instruction::Xor { x, y },
+            0x4 => Instruction::AddVxVy { x, y },
+            0x5 => Instruction::Sub { x, y },
+            0x6 => Instruction::Shr { x, y },
+            0x7 => Instruction::Subn { x, y },
+            0xE => Instruction::Shl { x, y },
+            _ => Instruction::Unknown(opcode),
+        },
+        0x9000 if n == 0 => Instruction::SneVxVy { x, y },
+        0xA000 => Instruction::LdI(addr),
+        0xB000 => Instruction::JpV0(addr),
+        0xC000 => Instruction::Rnd { x, byte },
+        0xD000 => Instruction::Drw { x, y, n },
+        0xE000 => match byte {
+            0x9E => Instruction::Skp(x),
+            0xA1 => Instruction::Sknp(x),
+            _ => Instruction::Unknown(opcode),
+        },
+        0xF000 => match byte {
+            0x00 if x == 0 => Instruction::LdILong,
+            0x01 => Instruction::Plane(x as u8),
+            0x02 if x == 0 => Instruction::Audio,
+            0x07 => Instruction::LdVxDt(x),
+            0x0A => Instruction::LdVxK(x),
+            0x15 => Instruction::LdDtVx(x),
+            0x18 => Instruction::LdStVx(x),
+            0x1E => Instruction::AddIVx(x),
+            0x29 => Instruction::LdFVx(x),
+            0x30 => Instruction::LdHfVx(x),
+            0x33 => Instruction::LdBVx(x),
+            0x3A => Instruction::Pitch(x),
+            0x55 => Instruction::LdIVx(x),
+            0x65 => Instruction::LdVxI(x),
+            0x75 => Instruction::LdRVx(x),
+            0x85 => Instruction::LdVxR(x),
+            _ => Instruction::Unknown(opcode),
+        },
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+impl Instruction {
+    /// The opcode the instruction decodes from, the inverse of `decode`
+    pub fn encode(&self) -> u16 {
+        let xy = |base: u16, x: usize, y: usize| base | (x as u16) << 8 | (y as u16) << 4;
+        let xb = |base: u16, x: usize, byte: u8| base | (x as u16) << 8 | byte as u16;
+        let fx = |x: usize, low: u16| 0xF000 | (x as u16) << 8 | low;
+        match *self {
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::Scd(n) => 0x00C0 | n as u16,
+            Instruction::Scu(n) => 0x00D0 | n as u16,
+            Instruction::Scr => 0x00FB,
+            Instruction::Scl => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Jp(addr) => 0x1000 | addr,
+            Instruction::Call(addr) => 0x2000 | addr,
+            Instruction::SeVxByte { x, byte } => xb(0x3000, x, byte),
+            Instruction::SneVxByte { x, byte } => xb(0x4000, x, byte),
+            Instruction::SeVxVy { x, y } => xy(0x5000, x, y),
+            Instruction::Save { x, y } => xy(0x5002, x, y),
+            Instruction::Load { x, y } => xy(0x5003, x, y),
+            Instruction::LdVxByte { x, byte } => xb(0x6000, x, byte),
+            Instruction::AddVxByte { x, byte } => xb(0x7000, x, byte),
+            Instruction::LdVxVy { x, y } => xy(0x8000, x, y),
+            Instruction::Or { x, y } => xy(0x8001, x, y),
+            Instruction::And { x, y } => xy(0x8002, x, y),
+            Instruction::Xor { x, y } => xy(0x8003, x, y),
+            Instruction::AddVxVy { x, y } => xy(0x8004, x, y),
+            Instruction::Sub { x, y } => xy(0x8005, x, y),
+            Instruction::Shr { x, y } => xy(0x8006, x, y),
+            Instruction::Subn { x, y } => xy(0x8007, x, y),
+            Instruction::Shl { x, y } => xy(0x800E, x, y),
+            Instruction::SneVxVy { x, y } => xy(0x9000, x, y),
+            Instruction::LdI(addr) => 0xA000 | addr,
+            Instruction::JpV0(addr) => 0xB000 | addr,
+            Instruction::Rnd { x, byte } => xb(0xC000, x, byte),
+            Instruction::Drw { x, y, n } => xy(0xD000, x, y) | n as u16,
+            Instruction::Skp(x) => xb(0xE000, x, 0x9E),
+            Instruction::Sknp(x) => xb(0xE000, x, 0xA1),
+            Instruction::LdILong => 0xF000,
+            Instruction::Plane(n) => fx(n as usize, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LdVxDt(x) => fx(x, 0x07),
+            Instruction::LdVxK(x) => fx(x, 0x0A),
+            Instruction::LdDtVx(x) => fx(x, 0x15),
+            Instruction::LdStVx(x) => fx(x, 0x18),
+            Instruction::AddIVx(x) => fx(x, 0x1E),
+            Instruction::LdFVx(x) => fx(x, 0x29),
+            Instruction::LdHfVx(x) => fx(x, 0x30),
+            Instruction::LdBVx(x) => fx(x, 0x33),
+            Instruction::Pitch(x) => fx(x, 0x3A),
+            Instruction::LdIVx(x) => fx(x, 0x55),
+            Instruction::LdVxI(x) => fx(x, 0x65),
+            Instruction::LdRVx(x) => fx(x, 0x75),
+            Instruction::LdVxR(x) => fx(x, 0x85),
+            Instruction::Unknown(opcode) => opcode,
+        }
+    }
+
+    /// The number of bytes the instruction takes up in memory
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LdILong => 4,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Scd(n) => write!(f, "SCD {}", n),
+            Instruction::Scu(n) => write!(f, "SCU {}", n),
+            Instruction::Scr => write!(f, "SCR"),
+            Instruction::Scl => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Jp(addr) => write!(f, "JP {:#05X}", addr),
+            Instruction::Call(addr) => write!(f, "CALL {:#05X}", addr),
+            Instruction::SeVxByte { x, byte } => write!(f, "SE V{:X}, {:#04X}", x, byte),
+            Instruction::SneVxByte { x, byte } => write!(f, "SNE V{:X}, {:#04X}", x, byte),
+            Instruction::SeVxVy { x, y } => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::Save { x, y } => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::Load { x, y } => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LdVxByte { x, byte } => write!(f, "LD V{:X}, {:#04X}", x, byte),
+            Instruction::AddVxByte { x, byte } => write!(f, "ADD V{:X}, {:#04X}", x, byte),
+            Instruction::LdVxVy { x, y } => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or { x, y } => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And { x, y } => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor { x, y } => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddVxVy { x, y } => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub { x, y } => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr { x, y } => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn { x, y } => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl { x, y } => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneVxVy { x, y } => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD I, {:#05X}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP V0, {:#05X}", addr),
+            Instruction::Rnd { x, byte } => write!(f, "RND V{:X}, {:#04X}", x, byte),
+            Instruction::Drw { x, y, n } => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::Skp(x) => write!(f, "SKP V{:X}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdILong => write!(f, "LD I, LONG"),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdHfVx(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Unknown(opcode) => write!(f, "DW {:#06X}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode(0x00E0), Instruction::Cls);
+        assert_eq!(decode(0x1234), Instruction::Jp(0x234));
+        assert_eq!(decode(0x3A1F), Instruction::SeVxByte { x: 0xA, byte: 0x1F });
+        assert_eq!(decode(0xD7C5), Instruction::Drw { x: 7, y: 0xC, n: 5 });
+        assert_eq!(decode(0x8AB6), Instruction::Shr { x: 0xA, y: 0xB });
+        assert_eq!(decode(0xF000), Instruction::LdILong);
+        assert_eq!(decode(0xF201), Instruction::Plane(2));
+        assert_eq!(decode(0x5121), Instruction::Unknown(0x5121));
+        assert_eq!(decode(0x0123), Instruction::Unknown(0x0123));
+        assert_eq!(decode(0xF100), Instruction::Unknown(0xF100));
+    }
+
+    #[test]
+    fn encoding_round_trip() {
+        for opcode in 0..=u16::MAX {
+            assert_eq!(decode(opcode).encode(), opcode);
+        }
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(decode(0x00EE).to_string(), "RET");
+        assert_eq!(decode(0x22A4).to_string(), "CALL 0x2A4");
+        assert_eq!(decode(0x6A05).to_string(), "LD VA, 0x05");
+        assert_eq!(decode(0xD015).to_string(), "DRW V0, V1, 5");
+        assert_eq!(decode(0xF355).to_string(), "LD [I], V3");
+        assert_eq!(decode(0x0123).to_string(), "DW 0x0123");
+    }
+}
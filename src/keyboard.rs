@@ -1,29 +1,146 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// The 16 key hex keypad. Bit N of every mask is key N
 pub struct Keyboard {
-    pub pressed_key: Chip8Key,
+    // The keys held down, as reported by the frontend
+    keys: u16,
+    // The keys that went down and up during the current frame. A key tapped between two
+    // frames shows up in both while it never looks held
+    pressed: u16,
+    released: u16,
+    // The key FX0A saw go down, it finishes when the key comes back up
+    waiting_key: Option<u8>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Keyboard {
     pub fn new() -> Self {
         Keyboard {
-            pressed_key: Chip8Key::None,
+            keys: 0,
+            pressed: 0,
+            released: 0,
+            waiting_key: None,
         }
     }
 
+    /// Replaces the held keys, the edges are worked out from the ones held before
     #[inline]
-    pub fn check_key(&mut self) -> Chip8Key {
-        self.pressed_key
+    pub fn set_keys(&mut self, keys: u16) {
+        self.pressed |= keys & !self.keys;
+        self.released |= !keys & self.keys;
+        self.keys = keys;
     }
 
     #[inline]
-    pub fn press_key(&mut self, key: Chip8Key) {
+    pub fn press(&mut self, key: Chip8Key) {
         if key != Chip8Key::None {
-            self.pressed_key = key;
-        } else {
-            self.pressed_key = Chip8Key::None;
+            self.set_keys(self.keys | 1 << key as u8);
+        }
+    }
+
+    #[inline]
+    pub fn release(&mut self, key: Chip8Key) {
+        if key != Chip8Key::None {
+            self.set_keys(self.keys & !(1 << key as u8));
+        }
+    }
+
+    #[inline]
+    pub fn get_keys(&self) -> u16 {
+        self.keys
+    }
+
+    /// Whether the key is held, only the low nibble is looked at like on the VIP
+    #[inline]
+    pub fn is_down(&self, key: u8) -> bool {
+        self.keys & 1 << (key & 0xF) != 0
+    }
+
+    /// The keys that went down during the current frame
+    #[inline]
+    pub fn pressed(&self) -> u16 {
+        self.pressed
+    }
+
+    /// The keys that came up during the current frame
+    #[inline]
+    pub fn released(&self) -> u16 {
+        self.released
+    }
+
+    /// Replaces the held keys and the edges of the current frame at once, used to play
+    /// back recorded input
+    #[inline]
+    pub fn set_input(&mut self, keys: u16, pressed: u16, released: u16) {
+        self.keys = keys;
+        self.pressed = pressed;
+        self.released = released;
+    }
+
+    /// Forgets the edges, called once the machine has run a frame
+    #[inline]
+    pub fn end_frame(&mut self) {
+        self.pressed = 0;
+        self.released = 0;
+    }
+
+    /// FX0A: waits for a key to go down and then come back up, and returns it once it
+    /// has. Until then it returns None and FX0A runs again
+    pub fn wait_for_key(&mut self) -> Option<u8> {
+        let key = match self.waiting_key {
+            Some(key) => key,
+            None => {
+                let down = self.keys | self.pressed;
+                if down == 0 {
+                    return None;
+                }
+                let key = down.trailing_zeros() as u8;
+                self.waiting_key = Some(key);
+                key
+            }
+        };
+        if self.keys & 1 << key != 0 {
+            return None;
         }
+        // So the same press doesn't finish the next FX0A too
+        self.waiting_key = None;
+        self.pressed &= !(1 << key);
+        Some(key)
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.keys);
+        writer.u16(self.pressed);
+        writer.u16(self.released);
+        writer.u8(self.waiting_key.unwrap_or(NO_KEY));
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> Result<Keyboard, StateError> {
+        let keys = reader.u16()?;
+        let pressed = reader.u16()?;
+        let released = reader.u16()?;
+        let waiting_key = match reader.u8()? {
+            NO_KEY => None,
+            key @ 0..=0xF => Some(key),
+            _ => return Err(StateError::Invalid("key")),
+        };
+        Ok(Keyboard {
+            keys,
+            pressed,
+            released,
+            waiting_key,
+        })
     }
 }
 
+// Stands for no key in save states
+const NO_KEY: u8 = 0xFF;
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Chip8Key {
@@ -45,3 +162,65 @@ pub enum Chip8Key {
     F = 0xF,
     None,
 }
+
+impl Chip8Key {
+    // The key with the given number, 16 and up are no key
+    pub fn from_u8(value: u8) -> Chip8Key {
+        const KEYS: [Chip8Key; 16] = [
+            Chip8Key::Zero,
+            Chip8Key::One,
+            Chip8Key::Two,
+            Chip8Key::Three,
+            Chip8Key::Four,
+            Chip8Key::Five,
+            Chip8Key::Six,
+            Chip8Key::Seven,
+            Chip8Key::Eight,
+            Chip8Key::Nine,
+            Chip8Key::A,
+            Chip8Key::B,
+            Chip8Key::C,
+            Chip8Key::D,
+            Chip8Key::E,
+            Chip8Key::F,
+        ];
+        KEYS.get(value as usize).copied().unwrap_or(Chip8Key::None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edges() {
+        let mut keyboard = Keyboard::new();
+        keyboard.set_keys(0b101);
+        keyboard.release(Chip8Key::Zero);
+        keyboard.press(Chip8Key::F);
+        assert_eq!(keyboard.get_keys(), 0x8004);
+        assert!(keyboard.is_down(2) && keyboard.is_down(0x1F) && !keyboard.is_down(0));
+        assert_eq!(keyboard.pressed(), 0x8005);
+        assert_eq!(keyboard.released(), 0b1);
+        keyboard.end_frame();
+        assert_eq!((keyboard.pressed(), keyboard.released()), (0, 0));
+    }
+
+    #[test]
+    fn waiting() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(keyboard.wait_for_key(), None);
+        keyboard.press(Chip8Key::Seven);
+        assert_eq!(keyboard.wait_for_key(), None);
+        // Other keys don't matter once one has been picked
+        keyboard.press(Chip8Key::Three);
+        keyboard.release(Chip8Key::Seven);
+        assert_eq!(keyboard.wait_for_key(), Some(7));
+        keyboard.release(Chip8Key::Three);
+        keyboard.end_frame();
+        // A tap between two frames counts
+        keyboard.set_keys(1 << 0xA);
+        keyboard.set_keys(0);
+        assert_eq!(keyboard.wait_for_key(), Some(0xA));
+    }
+}
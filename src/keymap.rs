@@ -0,0 +1,142 @@
+use chip_8::bindings::{Bindings, Hotkey, PAD_PREFIX};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::Scancode;
+use sdl2::EventPump;
+use std::collections::HashMap;
+
+// Something a key name can stand for
+enum Input {
+    Key(Scancode),
+    Button(Button),
+    // An axis pushed in one direction, true for positive
+    Axis(Axis, bool),
+}
+
+fn input(name: &str) -> Result<Input, String> {
+    let unknown = || format!("Unknown key '{}'", name);
+    let pad = name
+        .get(..PAD_PREFIX.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(PAD_PREFIX))
+        .map(|_| &name[PAD_PREFIX.len()..]);
+    let Some(pad) = pad else {
+        return Scancode::from_name(name)
+            .map(Input::Key)
+            .ok_or_else(unknown);
+    };
+    let axis = |sign: char| {
+        pad.strip_suffix(sign)
+            .and_then(Axis::from_string)
+            .map(|axis| Input::Axis(axis, sign == '+'))
+    };
+    axis('+')
+        .or_else(|| axis('-'))
+        .or_else(|| Button::from_string(pad).map(Input::Button))
+        .ok_or_else(unknown)
+}
+
+// The bindings with the key names turned into scancodes and controller inputs
+pub struct Keymap {
+    keypad: HashMap<Scancode, u16>,
+    hotkeys: HashMap<Scancode, Hotkey>,
+    buttons: HashMap<Button, u16>,
+    axes: HashMap<(Axis, bool), u16>,
+    button_hotkeys: HashMap<Button, Hotkey>,
+    // How far from the center a stick has to be to count
+    deadzone: i16,
+}
+
+impl Keymap {
+    pub fn new(bindings: &Bindings) -> Result<Keymap, String> {
+        let mut keymap = Keymap {
+            keypad: HashMap::new(),
+            hotkeys: HashMap::new(),
+            buttons: HashMap::new(),
+            axes: HashMap::new(),
+            button_hotkeys: HashMap::new(),
+            deadzone: (bindings.deadzone() * i16::MAX as f32) as i16,
+        };
+        for (key, names) in bindings.keypad().iter().enumerate() {
+            for name in names {
+                let mask = match input(name)? {
+                    Input::Key(scancode) => keymap.keypad.entry(scancode).or_insert(0),
+                    Input::Button(button) => keymap.buttons.entry(button).or_insert(0),
+                    Input::Axis(axis, positive) => keymap.axes.entry((axis, positive)).or_insert(0),
+                };
+                *mask |= 1 << key;
+            }
+        }
+        for (hotkey, names) in bindings.hotkeys() {
+            for name in names {
+                let previous = match input(name)? {
+                    Input::Key(scancode) => keymap.hotkeys.insert(scancode, *hotkey),
+                    Input::Button(button) => keymap.button_hotkeys.insert(button, *hotkey),
+                    Input::Axis(..) => {
+                        return Err(format!("Hotkeys can't be bound to a stick, '{}'", name))
+                    }
+                };
+                if let Some(previous) = previous.filter(|previous| previous != hotkey) {
+                    return Err(format!(
+                        "Key '{}' is bound to two hotkeys, {} and {}",
+                        name,
+                        format!("{:?}", previous).to_lowercase(),
+                        format!("{:?}", hotkey).to_lowercase()
+                    ));
+                }
+            }
+        }
+        Ok(keymap)
+    }
+
+    // The keypad keys a scancode is bound to, bit N is key N
+    #[inline]
+    pub fn keypad_mask(&self, scancode: Scancode) -> u16 {
+        self.keypad.get(&scancode).copied().unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn hotkey(&self, scancode: Scancode) -> Option<Hotkey> {
+        self.hotkeys.get(&scancode).copied()
+    }
+
+    #[inline]
+    pub fn button_mask(&self, button: Button) -> u16 {
+        self.buttons.get(&button).copied().unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn button_hotkey(&self, button: Button) -> Option<Hotkey> {
+        self.button_hotkeys.get(&button).copied()
+    }
+
+    // The keypad keys held down on the keyboard and the controllers
+    pub fn keypad_state(&self, event_pump: &EventPump, controllers: &[GameController]) -> u16 {
+        let keys = event_pump
+            .keyboard_state()
+            .pressed_scancodes()
+            .fold(0, |keys, scancode| keys | self.keypad_mask(scancode));
+        controllers.iter().fold(keys, |keys, controller| {
+            keys | self.controller_state(controller)
+        })
+    }
+
+    fn controller_state(&self, controller: &GameController) -> u16 {
+        let mut keys = 0;
+        for (button, mask) in &self.buttons {
+            if controller.button(*button) {
+                keys |= mask;
+            }
+        }
+        for ((axis, positive), mask) in &self.axes {
+            let value = controller.axis(*axis);
+            let pushed = if *positive {
+                value > self.deadzone
+            } else {
+                value < -self.deadzone
+            };
+            if pushed {
+                keys |= mask;
+            }
+        }
+        keys
+    }
+}
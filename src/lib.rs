@@ -0,0 +1,20 @@
+pub mod assembler;
+pub mod bindings;
+pub mod chip8;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod instruction;
+pub mod keyboard;
+pub mod monitor;
+pub mod movie;
+pub mod octo;
+pub mod profiler;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod state;
+pub mod symbols;
+pub mod timing;
+pub mod trace;
@@ -1,60 +1,163 @@
-mod chip8;
-mod keyboard;
-mod monitor;
+mod keymap;
+mod memview;
+mod panel;
 mod speaker;
 
 extern crate sdl2;
 
-use chip8::Chip8;
-use monitor::*;
+use chip_8::bindings::{self, Bindings, Hotkey};
+use chip_8::chip8::Chip8;
+use chip_8::debugger::{self, Condition, Debugger, Watchpoint};
+use chip_8::error::Chip8Error;
+use chip_8::monitor::*;
+use chip_8::movie::{Movie, Playback, PlaybackError};
+use chip_8::octo;
+use chip_8::profiler::Profiler;
+use chip_8::quirks::Quirks;
+use chip_8::rewind::Rewind;
+use chip_8::rng::{self, MemoryRng, VipRng, XorShift};
+use chip_8::symbols::{self, SymbolMap};
+use chip_8::timing::{FrameClock, TimingMode, DEFAULT_IPS};
+use chip_8::trace::{Trace, Tracer};
+use keymap::Keymap;
+use memview::{Glyphs, MemoryView};
+use panel::{RegisterPanel, PANEL_WIDTH};
+use sdl2::event::Event;
+use sdl2::keyboard::Scancode;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
-use sdl2::render::{Canvas, TextureCreator};
+use sdl2::render::{BlendMode, Canvas, TextureCreator};
 use sdl2::ttf::Font;
 use sdl2::video::{Window, WindowContext};
-use std::time::{Duration, Instant};
-use std::{fs, path::Path};
+use std::cell::RefCell;
+use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fs, process};
 
 const SCREEN_W: u32 = 1280;
 const SCREEN_H: u32 = 720;
-const FPS: u64 = 60;
-// Divide a second by the FPS to get the interval. Basically pin the chip8 cycles
-// to execute every ~16 miliseconds
-const FPS_INTERVAL: Duration = Duration::from_millis(1000 / FPS);
+// How long messages about save states stay on screen
+const STATUS_TIME: Duration = Duration::from_secs(2);
+const STATE_SLOTS: u8 = 10;
+const WHITE: Color = Color::RGBA(255, 255, 255, 255);
+const RED: Color = Color::RGBA(255, 80, 80, 255);
+// The colors of the four XO-CHIP bitplane combinations, 0 is the background
+const PALETTE: [Color; 4] = [
+    Color::RGB(0, 0, 0),
+    Color::RGB(255, 255, 255),
+    Color::RGB(255, 170, 0),
+    Color::RGB(85, 85, 85),
+];
+// The disassembly shown while the debugger is stopped, lines above the cursor and in all
+const LISTING_BEFORE: u16 = 8;
+const LISTING_LINES: usize = 17;
+const LINE_HEIGHT: u32 = 32;
+const STATUS_HEIGHT: u32 = 50;
 
 fn calculate_delta(start: Instant) -> Duration {
     Instant::now().duration_since(start)
 }
 
-fn display_metrics(
+// Messages about save states, errors and why the debugger stopped, along the bottom
+fn display_status(
     canvas: &mut Canvas<Window>,
     font: &Font,
     texture_creator: &TextureCreator<WindowContext>,
-    metrics: &str,
+    status: &str,
+    color: Color,
 ) {
-    let surface = font
-        .render(metrics)
-        .blended(Color::RGBA(255, 255, 255, 255))
-        .unwrap();
+    let y = (SCREEN_H - STATUS_HEIGHT) as i32;
+    draw_text(
+        canvas,
+        font,
+        texture_creator,
+        status,
+        color,
+        (8, y),
+        STATUS_HEIGHT - 10,
+    );
+}
+
+// Draws a line of text at its natural width for the height
+fn draw_text(
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    texture_creator: &TextureCreator<WindowContext>,
+    text: &str,
+    color: Color,
+    (x, y): (i32, i32),
+    height: u32,
+) {
+    let surface = font.render(text).blended(color).unwrap();
     let texture = texture_creator
         .create_texture_from_surface(&surface)
         .unwrap();
-    let target = Rect::new(0, 720 - 50, 1280, 50);
+    let width = surface.width() * height / surface.height().max(1);
+    let target = Rect::new(x, y, width, height);
     canvas.copy(&texture, None, Some(target)).unwrap();
 }
 
+// The instructions around the cursor, the PC is marked with > and breakpoints with *
+fn draw_listing(
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    texture_creator: &TextureCreator<WindowContext>,
+    chip8: &Chip8,
+    debugger: &Debugger,
+    cursor: u16,
+) {
+    let lines = debugger::listing(chip8, cursor, LISTING_BEFORE, LISTING_LINES);
+    canvas.set_blend_mode(BlendMode::Blend);
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 200));
+    let height = LISTING_LINES as u32 * LINE_HEIGHT + 16;
+    canvas
+        .fill_rect(Rect::new(0, 0, SCREEN_W / 3, height))
+        .unwrap();
+    for (line, (address, instruction)) in lines.into_iter().enumerate() {
+        let text = format!(
+            "{}{} {:03X}  {}",
+            if address == chip8.get_pc() { ">" } else { "  " },
+            if debugger.has_breakpoint(address) {
+                "*"
+            } else {
+                "  "
+            },
+            address,
+            instruction
+        );
+        let color = if address == cursor { PALETTE[2] } else { WHITE };
+        let y = 8 + (line as u32 * LINE_HEIGHT) as i32;
+        draw_text(
+            canvas,
+            font,
+            texture_creator,
+            &text,
+            color,
+            (8, y),
+            LINE_HEIGHT,
+        );
+    }
+}
+
+fn window_title(quirks: &Quirks) -> String {
+    format!("CHIP-8 - {}", quirks.name().unwrap_or("Custom quirks"))
+}
+
 // Takes the buffer of the monitor and draws it to the canvas
-fn draw(screen: [u8; 2048], canvas: &mut Canvas<Window>, offset_x: u32, offset_y: u32) {
-    let mut rect = Rect::new(0, 0, SCALE as u32, SCALE as u32);
-    let mut y = 0;
-    for (index, px) in screen.iter().enumerate() {
-        if index % COLS == 0 && index > 0 {
-            y += 1;
-        }
-        if *px == 1 {
+fn draw(monitor: &Monitor, canvas: &mut Canvas<Window>, offset_x: u32, offset_y: u32) {
+    let scale = monitor.get_scale();
+    let (cols, rows) = monitor.get_res();
+    let mut rect = Rect::new(0, 0, scale as u32, scale as u32);
+    let screen = monitor.get_buffer();
+    for (index, px) in screen[..cols * rows].iter().enumerate() {
+        if *px > 0 {
+            canvas.set_draw_color(PALETTE[*px as usize & 0b11]);
             rect.reposition((
-                ((index % 64) * SCALE) as i32 + offset_x as i32,
-                (y * SCALE) as i32 + offset_y as i32,
+                ((index % cols) * scale) as i32 + offset_x as i32,
+                ((index / cols) * scale) as i32 + offset_y as i32,
             ));
             canvas.draw_rect(rect).unwrap();
             canvas.fill_rect(rect).unwrap();
@@ -62,25 +165,274 @@ fn draw(screen: [u8; 2048], canvas: &mut Canvas<Window>, offset_x: u32, offset_y
     }
 }
 
+struct Options {
+    path: String,
+    // Runs with the same seed make the same random numbers
+    seed: u64,
+    // The id of the generator for CXNN
+    rng: u8,
+    // Instructions per second
+    ips: u32,
+    timing: TimingMode,
+    // The key bindings, the default file is optional
+    config: Option<String>,
+    // Where to save the input of the run, or the movie to play back instead of live input
+    record: Option<String>,
+    play: Option<String>,
+    // Breakpoints, with the condition they stop on, and watchpoints to start with
+    breakpoints: Vec<(u16, Option<Condition>)>,
+    watchpoints: Vec<Watchpoint>,
+    break_unknown: bool,
+    // The file to trace the instructions to, only the ones in the ranges when there are
+    // any, and how big it gets before it's rotated
+    trace: Option<String>,
+    trace_ranges: Vec<RangeInclusive<u16>>,
+    trace_max: Option<u64>,
+    // Where to write the profile, as a report and as collapsed stacks for flame graphs
+    profile: Option<String>,
+    profile_stacks: Option<String>,
+}
+
+// A number of bytes, with an optional K, M or G
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, shift) = match text.to_ascii_uppercase().chars().last()? {
+        'K' => (&text[..text.len() - 1], 10),
+        'M' => (&text[..text.len() - 1], 20),
+        'G' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        // Without a seed every run is different
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        let mut options = Options {
+            path: "./roms/Hidden.ch8".to_string(),
+            seed,
+            rng: XorShift::ID,
+            ips: DEFAULT_IPS,
+            timing: TimingMode::Instructions,
+            config: None,
+            record: None,
+            play: None,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            break_unknown: false,
+            trace: None,
+            trace_ranges: Vec::new(),
+            trace_max: None,
+            profile: None,
+            profile_stacks: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--seed" => {
+                    let seed = args.next().ok_or("--seed needs a value")?;
+                    options.seed = seed
+                        .parse()
+                        .map_err(|_| format!("Invalid seed '{}'", seed))?;
+                }
+                "--vip-rng" => options.rng = VipRng::ID,
+                "--memory-rng" => options.rng = MemoryRng::ID,
+                "--vip-timing" => options.timing = TimingMode::Vip,
+                "--config" => options.config = Some(args.next().ok_or("--config needs a path")?),
+                "--record" => options.record = Some(args.next().ok_or("--record needs a path")?),
+                "--play" => options.play = Some(args.next().ok_or("--play needs a path")?),
+                "--break" => {
+                    let breakpoint = args.next().ok_or("--break needs an address")?;
+                    let (address, condition) = match breakpoint.split_once(" if ") {
+                        Some((address, condition)) => (address, Some(condition.parse()?)),
+                        None => (breakpoint.as_str(), None),
+                    };
+                    let address = debugger::parse_number(address)
+                        .ok_or_else(|| format!("Invalid address '{}'", address))?;
+                    options.breakpoints.push((address, condition));
+                }
+                "--watch" => {
+                    let watchpoint = args.next().ok_or("--watch needs an address")?;
+                    options.watchpoints.push(watchpoint.parse()?);
+                }
+                "--break-unknown" => options.break_unknown = true,
+                "--trace" => options.trace = Some(args.next().ok_or("--trace needs a path")?),
+                "--trace-range" => {
+                    let range = args.next().ok_or("--trace-range needs an address")?;
+                    let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+                    let start = debugger::parse_number(start);
+                    let end = debugger::parse_number(end);
+                    match (start, end) {
+                        (Some(start), Some(end)) if start <= end => {
+                            options.trace_ranges.push(start..=end)
+                        }
+                        _ => return Err(format!("Invalid address range '{}'", range)),
+                    }
+                }
+                "--profile" => options.profile = Some(args.next().ok_or("--profile needs a path")?),
+                "--profile-stacks" => {
+                    options.profile_stacks =
+                        Some(args.next().ok_or("--profile-stacks needs a path")?)
+                }
+                "--trace-max" => {
+                    let size = args.next().ok_or("--trace-max needs a size")?;
+                    options.trace_max = Some(
+                        parse_size(&size)
+                            .filter(|size| *size > 0)
+                            .ok_or_else(|| format!("Invalid size '{}'", size))?,
+                    );
+                }
+                "--ips" => {
+                    let ips = args.next().ok_or("--ips needs a value")?;
+                    options.ips = ips
+                        .parse()
+                        .ok()
+                        .filter(|ips| *ips > 0)
+                        .ok_or_else(|| format!("Invalid instructions per second '{}'", ips))?;
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+                _ => options.path = arg,
+            }
+        }
+        if options.record.is_some() && options.play.is_some() {
+            return Err("--record and --play can't be used together".to_string());
+        }
+        Ok(options)
+    }
+}
+
+enum MovieMode {
+    Recording(Movie, String),
+    Playing(Playback),
+}
+
+// Reads a movie and sets the machine up the way it was recorded
+fn load_movie(chip8: &mut Chip8, path: &str) -> Result<Playback, String> {
+    let bytes = fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+    let movie = Movie::from_bytes(&bytes)
+        .and_then(|movie| movie.apply(chip8).map(|_| movie))
+        .map_err(|err| format!("Couldn't play {}: {}", path, err))?;
+    Ok(Playback::new(movie))
+}
+
+// Runs a frame with the live keys, while recording them, or with the movie's keys.
+// Returns a message when something happened to the movie. The debugger only gets to
+// stop the machine without a movie, a frame cut short can't be recorded or played
+fn run_frame(
+    chip8: &mut Chip8,
+    debugger: &mut Debugger,
+    movie: &mut Option<MovieMode>,
+    keys: u16,
+) -> Result<Option<(String, Color)>, Chip8Error> {
+    match movie {
+        None => {
+            chip8.set_keys(keys);
+            debugger.cycle(chip8)?;
+            Ok(None)
+        }
+        Some(MovieMode::Recording(movie, _)) => {
+            chip8.set_keys(keys);
+            movie.record(chip8)?;
+            Ok(None)
+        }
+        Some(MovieMode::Playing(playback)) => match playback.step(chip8) {
+            Some(Ok(())) => Ok(None),
+            Some(Err(PlaybackError::Machine(err))) => Err(err),
+            Some(Err(desync)) => {
+                eprintln!("{}", desync);
+                Ok(Some((desync.to_string(), RED)))
+            }
+            // Live input takes over at the end
+            None => {
+                let message = format!("Movie finished after {} frames", playback.frame());
+                *movie = None;
+                chip8.set_keys(keys);
+                debugger.cycle(chip8)?;
+                Ok(Some((message, WHITE)))
+            }
+        },
+    }
+}
+
+// Save states live next to the ROM, one file per slot
+fn state_path(rom_path: &str, slot: u8) -> PathBuf {
+    PathBuf::from(format!("{}.state{}", rom_path, slot))
+}
+
+fn save_slot(chip8: &Chip8, rom_path: &str, slot: u8) -> Result<String, String> {
+    fs::write(state_path(rom_path, slot), chip8.save_state())
+        .map(|_| format!("Saved slot {}", slot))
+        .map_err(|err| format!("Couldn't save slot {}: {}", slot, err))
+}
+
+fn load_slot(chip8: &mut Chip8, rom_path: &str, slot: u8) -> Result<String, String> {
+    let state = fs::read(state_path(rom_path, slot))
+        .map_err(|err| format!("Couldn't read slot {}: {}", slot, err))?;
+    chip8
+        .load_state(&state)
+        .map(|_| format!("Loaded slot {}", slot))
+        .map_err(|err| format!("Couldn't load slot {}: {}", slot, err))
+}
+
+// Reads a ROM, .8o files are compiled from Octo source first. The symbols come from the
+// compiler or the ROM's symbol map, when it has a readable one
+fn load_rom(path: &Path) -> Result<(Vec<u8>, Option<SymbolMap>), String> {
+    if path.extension().is_some_and(|ext| ext == "8o") {
+        octo::compile_file_with_symbols(path)
+            .map(|(rom, symbols)| (rom, Some(symbols)))
+            .map_err(|err| err.to_string())
+    } else {
+        let rom =
+            fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+        let symbols = fs::read_to_string(symbols::path_for(path))
+            .ok()
+            .and_then(|text| text.parse().ok());
+        Ok((rom, symbols))
+    }
+}
+
+// Reads the key bindings for the ROM, the defaults are used when there's no config
+fn load_bindings(config: Option<&str>, rom_path: &Path) -> Result<Bindings, String> {
+    let path = config.unwrap_or(bindings::DEFAULT_PATH);
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) if err.kind() == ErrorKind::NotFound && config.is_none() => {
+            return Ok(Bindings::default())
+        }
+        Err(err) => return Err(format!("Couldn't read {}: {}", path, err)),
+    };
+    let rom = rom_path.file_name().unwrap_or_default().to_string_lossy();
+    Bindings::parse(&source, &rom).map_err(|err| format!("{}: {}", path, err))
+}
+
+// A machine fresh out of reset with the ROM loaded
+fn new_machine(options: &Options, rom: &[u8], quirks: Quirks) -> Chip8 {
+    let mut chip8 = Chip8::new(Monitor::new_default(), quirks);
+    chip8.load_sprites();
+    chip8.load_program(rom);
+    chip8.set_rng(rng::from_id(options.rng, options.seed).unwrap());
+    chip8.set_ips(options.ips);
+    chip8.set_timing(options.timing);
+    chip8
+}
+
 pub fn main() {
     // Set the audio and video subsystems
     let sdl_context = sdl2::init().unwrap();
     let ttl_context = sdl2::ttf::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let audio_subsystem = sdl_context.audio().unwrap();
+    let controller_subsystem = sdl_context.game_controller().unwrap();
     let font = ttl_context
         .load_font(Path::new("./OpenSans-Regular.ttf"), 128)
         .unwrap();
 
-    // Init chip 8 and components
-    let monitor = Monitor::new_default();
-    let audio_device = speaker::init_speaker(audio_subsystem);
-    let mut chip8 = Chip8::new(monitor);
+    let mut audio_device = speaker::init_speaker(audio_subsystem);
 
     // Generate the window
-    let (c8_width, c8_height) = chip8.monitor.get_scaled_res();
     let window = video_subsystem
-        .window("CHIP-8", SCREEN_W, SCREEN_H)
+        .window(&window_title(&Quirks::default()), SCREEN_W, SCREEN_H)
         .position_centered()
         .build()
         .unwrap();
@@ -93,48 +445,398 @@ pub fn main() {
     canvas.clear();
     canvas.present();
 
-    // Read the ROM and load it into chip8
-    let rom = fs::read(Path::new("./roms/Hidden.ch8")).expect("Couldn't read ROM");
-    chip8.load_sprites();
-    chip8.load_program(&rom);
+    // Read the ROM, or compile it when it's Octo source, and load it into chip8
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("Usage: chip-8 [--seed <n>] [--vip-rng | --memory-rng] [--ips <n>] [--vip-timing] [--config <file>] [--record <file> | --play <file>] [--break <addr>[ if <condition>]] [--watch <start>[-<end>][:r|w|rw]] [--break-unknown] [--trace <file>] [--trace-range <start>[-<end>]] [--trace-max <size>[K|M|G]] [--profile <file>] [--profile-stacks <file>] [rom]");
+            process::exit(1);
+        }
+    };
+    let path = options.path.clone();
+    let (rom, symbol_map) = match load_rom(Path::new(&path)) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let keymap = match load_bindings(options.config.as_deref(), Path::new(&path))
+        .and_then(|bindings| Keymap::new(&bindings))
+    {
+        Ok(keymap) => keymap,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let mut chip8 = new_machine(&options, &rom, Quirks::default());
+    // The tracer and the profiler both follow every instruction
+    let mut tracers: Vec<Box<dyn Trace>> = Vec::new();
+    if let Some(trace_path) = &options.trace {
+        match Tracer::create(Path::new(trace_path)) {
+            Ok(mut tracer) => {
+                for range in &options.trace_ranges {
+                    tracer.add_range(range.clone());
+                }
+                tracer.set_max_size(options.trace_max);
+                tracers.push(Box::new(tracer));
+            }
+            Err(err) => {
+                eprintln!("Couldn't create the trace {}: {}", trace_path, err);
+                process::exit(1);
+            }
+        }
+    }
+    let profiler = (options.profile.is_some() || options.profile_stacks.is_some())
+        .then(|| Rc::new(RefCell::new(Profiler::new())));
+    if let Some(profiler) = &profiler {
+        tracers.push(Box::new(profiler.clone()));
+    }
+    if !tracers.is_empty() {
+        chip8.set_tracer(Some(Box::new(tracers)));
+    }
+    // A movie starts from power on. Playing one overrides the settings from the options
+    let mut movie = if let Some(movie_path) = &options.play {
+        match load_movie(&mut chip8, movie_path) {
+            Ok(playback) => Some(MovieMode::Playing(playback)),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    } else {
+        options
+            .record
+            .clone()
+            .map(|movie_path| MovieMode::Recording(Movie::new(&chip8), movie_path))
+    };
+    canvas
+        .window_mut()
+        .set_title(&window_title(&chip8.quirks))
+        .unwrap();
 
-    let mut start = Instant::now();
+    // The last minute of frames, Backspace steps back through them
+    let mut rewind = Rewind::default();
+    rewind.push(chip8.save_state());
+
+    // Frames are run as the wall-clock time for them passes
+    let mut clock = FrameClock::default();
+    let mut last_frame = Instant::now();
+    // Set when the ROM does something illegal, the machine stays halted until a state is loaded
+    let mut error = None;
+    // The save state slot the hotkeys use, and the last message about it
+    let mut slot = 0;
+    let mut status: Option<(String, Color, Instant)> = None;
+    let mut debugger = Debugger::new();
+    for (address, condition) in &options.breakpoints {
+        debugger.add_breakpoint(*address, *condition);
+    }
+    for watchpoint in &options.watchpoints {
+        debugger.add_watchpoint(watchpoint.clone());
+    }
+    debugger.set_break_on_unknown(options.break_unknown);
+    // The line of the disassembly the debugger keys act on, the PC unless it's moved
+    let mut cursor = None;
+    let mut memory_view = MemoryView::new();
+    let mut register_panel = RegisterPanel::new();
+    register_panel.update(&chip8);
+    let mut glyphs = Glyphs::new(&texture_creator);
+    // Controllers are opened as SDL reports them, including the ones plugged in at start
+    let mut controllers = Vec::new();
     // The loop
     loop {
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
-        // Cycle the chip8
-        if calculate_delta(start) >= FPS_INTERVAL {
-            chip8.cycle(&event_pump);
-            start = Instant::now();
+        // Cycle the chip8 once per frame that's due, or go back a frame while the
+        // rewind key is held
+        let now = Instant::now();
+        let frames = clock.advance(now.duration_since(last_frame));
+        last_frame = now;
+        let mut ran = false;
+        for _ in 0..frames {
+            let rewinding = event_pump
+                .keyboard_state()
+                .is_scancode_pressed(Scancode::Backspace);
+            // Going back would break the movie, it only holds frames in order
+            if rewinding && movie.is_none() {
+                if let Some(state) = rewind.step_back() {
+                    if chip8.load_state(state).is_ok() {
+                        error = None;
+                        ran = true;
+                    }
+                }
+            } else if error.is_none() && !debugger.is_stopped() {
+                let keys = keymap.keypad_state(&event_pump, &controllers);
+                ran = true;
+                match run_frame(&mut chip8, &mut debugger, &mut movie, keys) {
+                    Ok(message) => {
+                        if let Some((message, color)) = message {
+                            status = Some((message, color, Instant::now()));
+                        }
+                        // A frame the debugger stopped in the middle of isn't over yet
+                        if !debugger.is_stopped() {
+                            rewind.push(chip8.save_state());
+                        }
+                    }
+                    Err(e) => error = Some(e),
+                }
+            }
+        }
+        if ran {
+            memory_view.update(chip8.get_memory());
+            register_panel.update(&chip8);
         }
         // Play sound
+        audio_device
+            .lock()
+            .set_pattern(chip8.get_audio_pattern(), chip8.get_pitch());
         if chip8.check_sound() {
             audio_device.resume();
         } else {
             audio_device.pause();
         }
-        // Update metrics
-        display_metrics(
-            &mut canvas,
-            &font,
-            &texture_creator,
-            &chip8.get_metrics()[..],
-        );
-        // Draw
-        let screen = chip8.monitor.get_buffer();
-        canvas.set_draw_color(Color::RGB(255, 255, 255));
+        // Show why the machine halted. Save state messages go first
+        let message = status
+            .as_ref()
+            .filter(|(_, _, since)| calculate_delta(*since) < STATUS_TIME)
+            .map(|(message, color, _)| (message.clone(), *color));
+        let status_line = match (message, error) {
+            (Some(message), _) => Some(message),
+            (None, Some(e)) => Some((e.to_string(), RED)),
+            (None, None) => debugger.stopped().map(|reason| (reason.to_string(), WHITE)),
+        };
+        if let Some((status_line, color)) = status_line {
+            display_status(&mut canvas, &font, &texture_creator, &status_line, color);
+        }
+        // Draw, the resolution can change when SUPER-CHIP switches to hires
+        // The game is centered in the space the register panel leaves
+        let (c8_width, c8_height) = chip8.monitor.get_scaled_res();
+        let width = if register_panel.is_visible() {
+            SCREEN_W - PANEL_WIDTH
+        } else {
+            SCREEN_W
+        };
         draw(
-            screen,
+            &chip8.monitor,
             &mut canvas,
-            (SCREEN_W - c8_width) / 2,
+            width.saturating_sub(c8_width) / 2,
             (SCREEN_H - c8_height) / 2,
         );
+        if debugger.is_stopped() {
+            let cursor = cursor.unwrap_or(chip8.get_pc());
+            draw_listing(
+                &mut canvas,
+                &font,
+                &texture_creator,
+                &chip8,
+                &debugger,
+                cursor,
+            );
+        }
+        register_panel.draw(&mut canvas, &font, &texture_creator, &mut glyphs, &chip8);
+        memory_view.draw(&mut canvas, &font, &texture_creator, &mut glyphs, &chip8);
         canvas.present();
-        if chip8.kill_flag {
+        if chip8.exit_requested() {
             break;
         }
-        event_pump.poll_event();
+        // Collected first, the held keys are looked up while handling them
+        let events: Vec<Event> = event_pump.poll_iter().collect();
+        for event in events {
+            // Keys are also followed through their events, so a tap that's over before
+            // the next frame isn't missed. A keypad key bound to several keys or buttons
+            // stays down while any of them is
+            let held = keymap.keypad_state(&event_pump, &controllers);
+            let keys = chip8.keyboard.get_keys();
+            let hotkey = match event {
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    repeat: false,
+                    ..
+                } => {
+                    chip8.set_keys(keys | keymap.keypad_mask(scancode));
+                    keymap.hotkey(scancode)
+                }
+                Event::KeyUp {
+                    scancode: Some(scancode),
+                    ..
+                } => {
+                    chip8.set_keys(keys & !(keymap.keypad_mask(scancode) & !held));
+                    None
+                }
+                Event::ControllerButtonDown { button, .. } => {
+                    chip8.set_keys(keys | keymap.button_mask(button));
+                    keymap.button_hotkey(button)
+                }
+                Event::ControllerButtonUp { button, .. } => {
+                    chip8.set_keys(keys & !(keymap.button_mask(button) & !held));
+                    None
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    status = Some(match controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            let message = format!("Connected {}", controller.name());
+                            controllers.push(controller);
+                            (message, WHITE, Instant::now())
+                        }
+                        Err(err) => (
+                            format!("Couldn't open controller: {}", err),
+                            RED,
+                            Instant::now(),
+                        ),
+                    });
+                    None
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    controllers.retain(|controller| controller.instance_id() != which);
+                    status = Some(("Controller disconnected".to_string(), WHITE, Instant::now()));
+                    None
+                }
+                Event::MouseWheel { y, .. } => {
+                    if memory_view.is_visible() {
+                        memory_view.scroll(-y * 3, &chip8);
+                    }
+                    None
+                }
+                Event::Quit { .. } => {
+                    chip8.kill_flag = true;
+                    None
+                }
+                _ => None,
+            };
+            match hotkey {
+                Some(Hotkey::Quit) => chip8.kill_flag = true,
+                Some(Hotkey::Pause) => {
+                    if debugger.is_stopped() {
+                        debugger.resume();
+                    } else {
+                        debugger.pause();
+                    }
+                    cursor = None;
+                }
+                Some(Hotkey::Memory) => memory_view.toggle(),
+                Some(Hotkey::Registers) => register_panel.toggle(),
+                Some(Hotkey::Reset) => {
+                    // The trace goes on through the reset
+                    let tracer = chip8.set_tracer(None);
+                    chip8 = new_machine(&options, &rom, chip8.quirks);
+                    chip8.set_tracer(tracer);
+                    rewind.clear();
+                    rewind.push(chip8.save_state());
+                    error = None;
+                    // A movie starts over along with the machine
+                    match &mut movie {
+                        Some(MovieMode::Recording(recording, _)) => *recording = Movie::new(&chip8),
+                        Some(MovieMode::Playing(playback)) => {
+                            let recording = playback.movie().clone();
+                            if recording.apply(&mut chip8).is_ok() {
+                                *playback = Playback::new(recording);
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                None => {}
+            }
+            // F1-F4 switch between the quirk presets while the ROM is running. Neither
+            // they nor loading a state are allowed during a movie, it would go out of sync
+            if let Event::KeyDown {
+                scancode: Some(scancode),
+                ..
+            } = event
+            {
+                let preset = match scancode {
+                    Scancode::F1 => Some(Quirks::COSMAC_VIP),
+                    Scancode::F2 => Some(Quirks::CHIP_48),
+                    Scancode::F3 => Some(Quirks::SUPER_CHIP),
+                    Scancode::F4 => Some(Quirks::XO_CHIP),
+                    _ => None,
+                };
+                if let Some(quirks) = preset.filter(|_| movie.is_none()) {
+                    chip8.set_quirks(quirks);
+                    canvas
+                        .window_mut()
+                        .set_title(&window_title(&chip8.quirks))
+                        .unwrap();
+                }
+                // F5 saves to the selected slot, F9 loads it, F6/F7 select the slot
+                let result = match scancode {
+                    Scancode::F5 => Some(save_slot(&chip8, &path, slot)),
+                    Scancode::F9 if movie.is_none() => {
+                        Some(load_slot(&mut chip8, &path, slot).inspect(|_| error = None))
+                    }
+                    Scancode::F6 => {
+                        slot = (slot + STATE_SLOTS - 1) % STATE_SLOTS;
+                        Some(Ok(format!("Slot {}", slot)))
+                    }
+                    Scancode::F7 => {
+                        slot = (slot + 1) % STATE_SLOTS;
+                        Some(Ok(format!("Slot {}", slot)))
+                    }
+                    _ => None,
+                };
+                status = result.map(|result| match result {
+                    Ok(message) => (message, WHITE, Instant::now()),
+                    Err(message) => (message, RED, Instant::now()),
+                });
+                // The memory view gets the keys first, it's edited while the machine is
+                // stopped and there's no movie to break
+                let editable = debugger.is_stopped() && movie.is_none();
+                let used = memory_view.handle_key(scancode, &mut chip8, editable);
+                // While stopped: F10 steps over, F11 into and F12 out of a subroutine.
+                // Page Up and Down move the cursor, Insert puts a breakpoint on its line
+                // and Home runs to it
+                if debugger.is_stopped() && movie.is_none() && !used {
+                    let at = cursor.unwrap_or(chip8.get_pc());
+                    match scancode {
+                        Scancode::F10 => debugger.step_over(&chip8),
+                        Scancode::F11 => debugger.step_into(),
+                        Scancode::F12 => debugger.step_out(&chip8),
+                        Scancode::Home => debugger.run_to(at),
+                        Scancode::PageUp => cursor = Some(at.saturating_sub(2)),
+                        Scancode::PageDown => {
+                            let last = chip8.get_memory().len() - 2;
+                            cursor = Some(at.saturating_add(2).min(last as u16));
+                        }
+                        Scancode::Insert => debugger.toggle_breakpoint(at),
+                        _ => {}
+                    }
+                    if !debugger.is_stopped() {
+                        cursor = None;
+                    }
+                }
+            }
+        }
+    }
+    if let Some(mut tracer) = chip8.set_tracer(None) {
+        if let Err(err) = tracer.finish() {
+            eprintln!("Couldn't write the trace: {}", err);
+        }
+    }
+    if let Some(profiler) = profiler {
+        let profiler = profiler.borrow();
+        let outputs = [
+            (&options.profile, profiler.report(symbol_map.as_ref())),
+            (
+                &options.profile_stacks,
+                profiler.collapsed_stacks(symbol_map.as_ref()),
+            ),
+        ];
+        for (path, text) in outputs {
+            if let Some(path) = path {
+                match fs::write(path, text) {
+                    Ok(()) => println!("Wrote the profile to {}", path),
+                    Err(err) => eprintln!("Couldn't write the profile to {}: {}", path, err),
+                }
+            }
+        }
+    }
+    if let Some(MovieMode::Recording(movie, movie_path)) = movie {
+        match fs::write(&movie_path, movie.to_bytes()) {
+            Ok(()) => println!("Recorded {} frames to {}", movie.len(), movie_path),
+            Err(err) => eprintln!("Couldn't save the movie to {}: {}", movie_path, err),
+        }
     }
 }
 
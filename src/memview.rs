@@ -0,0 +1,337 @@
+use crate::{draw_text, SCREEN_W, WHITE};
+use chip_8::chip8::Chip8;
+use sdl2::keyboard::Scancode;
+use sdl2::pixels::Color;
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+use std::collections::HashMap;
+
+const ROWS: usize = 24;
+const BYTES_PER_ROW: usize = 16;
+const CELL_HEIGHT: u32 = 24;
+const ADDRESS_WIDTH: i32 = 64;
+const HEX_WIDTH: i32 = 28;
+const ASCII_WIDTH: i32 = 14;
+const PANEL_WIDTH: i32 = ADDRESS_WIDTH + BYTES_PER_ROW as i32 * (HEX_WIDTH + ASCII_WIDTH) + 24;
+const PANEL_X: i32 = SCREEN_W as i32 - PANEL_WIDTH;
+// Below the header line
+const GRID_Y: i32 = 8 + CELL_HEIGHT as i32;
+// The highlights of the bytes the machine points at
+const PC_COLOR: Color = Color::RGBA(40, 140, 60, 255);
+const INDEX_COLOR: Color = Color::RGBA(40, 80, 170, 255);
+const STACK_COLOR: Color = Color::RGBA(130, 50, 140, 255);
+// Bytes written during the last frame
+const WRITTEN_COLOR: Color = Color::RGBA(255, 170, 0, 255);
+// The address column tells the regions apart
+const FONT_COLOR: Color = Color::RGBA(120, 170, 255, 255);
+const FREE_COLOR: Color = Color::RGBA(140, 140, 140, 255);
+
+// Rendered text for the strings that keep coming back, like the hex of a byte. The
+// textures are white and tinted when drawn
+pub struct Glyphs<'a> {
+    creator: &'a TextureCreator<WindowContext>,
+    cache: HashMap<String, Texture<'a>>,
+}
+
+impl<'a> Glyphs<'a> {
+    pub fn new(creator: &'a TextureCreator<WindowContext>) -> Self {
+        Self {
+            creator,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        canvas: &mut Canvas<Window>,
+        font: &Font,
+        text: &str,
+        color: Color,
+        (x, y): (i32, i32),
+    ) {
+        if !self.cache.contains_key(text) {
+            let surface = font.render(text).blended(WHITE).unwrap();
+            let texture = self.creator.create_texture_from_surface(&surface).unwrap();
+            self.cache.insert(text.to_string(), texture);
+        }
+        let texture = self.cache.get_mut(text).unwrap();
+        texture.set_color_mod(color.r, color.g, color.b);
+        let query = texture.query();
+        let width = query.width * CELL_HEIGHT / query.height.max(1);
+        let target = Rect::new(x, y, width, CELL_HEIGHT);
+        canvas.copy(texture, None, Some(target)).unwrap();
+    }
+
+    // Draws the text a character at a time, every one as wide as the advance, so
+    // numbers that keep changing line up and only need a texture per digit
+    pub fn draw_mono(
+        &mut self,
+        canvas: &mut Canvas<Window>,
+        font: &Font,
+        text: &str,
+        color: Color,
+        (x, y): (i32, i32),
+        advance: i32,
+    ) {
+        let mut buffer = [0; 4];
+        for (i, c) in text.chars().enumerate().filter(|(_, c)| *c != ' ') {
+            let x = x + i as i32 * advance;
+            self.draw(canvas, font, c.encode_utf8(&mut buffer), color, (x, y));
+        }
+    }
+}
+
+// A hex and ASCII view of the whole memory that can edit it while the machine is stopped
+pub struct MemoryView {
+    visible: bool,
+    // The first row on screen
+    top: usize,
+    cursor: usize,
+    // Whether the high nibble of the byte under the cursor was just typed
+    high_nibble: bool,
+    // Memory as of the last update, and which bytes changed in it
+    previous: Vec<u8>,
+    written: Vec<bool>,
+}
+
+impl MemoryView {
+    pub fn new() -> Self {
+        Self {
+            visible: false,
+            top: 0,
+            cursor: 0x200,
+            high_nibble: false,
+            previous: Vec::new(),
+            written: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        // Nothing is compared while hidden, the next update starts over
+        self.previous.clear();
+        self.written.clear();
+        self.scroll_to_cursor();
+    }
+
+    // Notes the bytes that changed since the last update, call it after every frame
+    pub fn update(&mut self, memory: &[u8]) {
+        if !self.visible {
+            return;
+        }
+        if self.previous.len() == memory.len() {
+            self.written = self
+                .previous
+                .iter()
+                .zip(memory)
+                .map(|(a, b)| a != b)
+                .collect();
+        }
+        self.previous = memory.to_vec();
+    }
+
+    pub fn scroll(&mut self, rows: i32, chip8: &Chip8) {
+        let last = (chip8.get_memory().len() / BYTES_PER_ROW).saturating_sub(ROWS);
+        self.top = (self.top as i64 + rows as i64).clamp(0, last as i64) as usize;
+    }
+
+    fn scroll_to_cursor(&mut self) {
+        let row = self.cursor / BYTES_PER_ROW;
+        if row < self.top {
+            self.top = row;
+        } else if row >= self.top + ROWS {
+            self.top = row + 1 - ROWS;
+        }
+    }
+
+    // Arrows and Page Up/Down move the cursor, hex digits type into the byte under it.
+    // Returns whether the view used the key
+    pub fn handle_key(&mut self, scancode: Scancode, chip8: &mut Chip8, editable: bool) -> bool {
+        if !self.visible {
+            return false;
+        }
+        let size = chip8.get_memory().len();
+        let page = ROWS * BYTES_PER_ROW;
+        let cursor = match scancode {
+            Scancode::Left => self.cursor.checked_sub(1),
+            Scancode::Right => Some(self.cursor + 1),
+            Scancode::Up => self.cursor.checked_sub(BYTES_PER_ROW),
+            Scancode::Down => Some(self.cursor + BYTES_PER_ROW),
+            Scancode::PageUp => Some(self.cursor.saturating_sub(page)),
+            Scancode::PageDown => Some((self.cursor + page).min(size - 1)),
+            _ => {
+                let digit = hex_digit(scancode).filter(|_| editable);
+                let Some(digit) = digit else {
+                    return false;
+                };
+                self.type_digit(digit, chip8);
+                return true;
+            }
+        };
+        if let Some(cursor) = cursor.filter(|cursor| *cursor < size) {
+            self.cursor = cursor;
+            self.high_nibble = false;
+            self.scroll_to_cursor();
+        }
+        true
+    }
+
+    // The first digit replaces the high nibble, the second the low one and moves on
+    fn type_digit(&mut self, digit: u8, chip8: &mut Chip8) {
+        let byte = chip8.get_memory()[self.cursor];
+        let byte = if self.high_nibble {
+            byte & 0xF0 | digit
+        } else {
+            digit << 4 | byte & 0x0F
+        };
+        chip8.write_memory(self.cursor, byte);
+        // An edit isn't something the program wrote
+        if let Some(previous) = self.previous.get_mut(self.cursor) {
+            *previous = byte;
+        }
+        if self.high_nibble && self.cursor + 1 < chip8.get_memory().len() {
+            self.cursor += 1;
+            self.scroll_to_cursor();
+        }
+        self.high_nibble = !self.high_nibble;
+    }
+
+    pub fn draw(
+        &self,
+        canvas: &mut Canvas<Window>,
+        font: &Font,
+        texture_creator: &TextureCreator<WindowContext>,
+        glyphs: &mut Glyphs,
+        chip8: &Chip8,
+    ) {
+        if !self.visible {
+            return;
+        }
+        let memory = chip8.get_memory();
+        let font_range = chip8.font_range();
+        let program = chip8.program_range();
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 220));
+        let height = (ROWS as u32 + 1) * CELL_HEIGHT + 16;
+        canvas
+            .fill_rect(Rect::new(PANEL_X, 0, PANEL_WIDTH as u32, height))
+            .unwrap();
+        let header = format!(
+            "Font {:03X}-{:03X}  Program {:03X}-{:03X}  Cursor {:04X}",
+            font_range.start,
+            font_range.end - 1,
+            program.start,
+            program.end.max(program.start + 1) - 1,
+            self.cursor
+        );
+        draw_text(
+            canvas,
+            font,
+            texture_creator,
+            &header,
+            WHITE,
+            (PANEL_X + 8, 4),
+            CELL_HEIGHT,
+        );
+
+        // The bytes the PC, I and the return addresses on the stack point at
+        let pc = chip8.get_pc() as usize;
+        let index = chip8.get_index() as usize;
+        let highlight = |address: usize| {
+            if address == pc || address == pc + 1 {
+                Some(PC_COLOR)
+            } else if address == index {
+                Some(INDEX_COLOR)
+            } else if chip8
+                .get_stack()
+                .iter()
+                .any(|target| address == *target as usize || address == *target as usize + 1)
+            {
+                Some(STACK_COLOR)
+            } else {
+                None
+            }
+        };
+        for row in 0..ROWS {
+            let start = (self.top + row) * BYTES_PER_ROW;
+            if start >= memory.len() {
+                break;
+            }
+            let y = GRID_Y + (row as u32 * CELL_HEIGHT) as i32;
+            let region = if font_range.contains(&start) {
+                FONT_COLOR
+            } else if program.contains(&start) {
+                WHITE
+            } else {
+                FREE_COLOR
+            };
+            // Lines between the font, the rest of the interpreter's memory, the program
+            // and the free memory after it
+            let boundaries = [
+                font_range.end,
+                program.start,
+                program.end.next_multiple_of(BYTES_PER_ROW),
+            ];
+            if boundaries.contains(&start) && row > 0 {
+                canvas.set_draw_color(WHITE);
+                canvas
+                    .draw_line(
+                        Point::new(PANEL_X, y - 1),
+                        Point::new(PANEL_X + PANEL_WIDTH, y - 1),
+                    )
+                    .unwrap();
+            }
+            let address = format!("{:04X}", start);
+            glyphs.draw(canvas, font, &address, region, (PANEL_X + 8, y));
+            for column in 0..BYTES_PER_ROW {
+                let address = start + column;
+                let byte = memory[address];
+                let x = PANEL_X + 8 + ADDRESS_WIDTH + column as i32 * HEX_WIDTH;
+                let ascii_x = PANEL_X
+                    + 16
+                    + ADDRESS_WIDTH
+                    + BYTES_PER_ROW as i32 * HEX_WIDTH
+                    + column as i32 * ASCII_WIDTH;
+                let cell = Rect::new(x - 2, y, HEX_WIDTH as u32, CELL_HEIGHT);
+                if let Some(color) = highlight(address) {
+                    canvas.set_draw_color(color);
+                    canvas.fill_rect(cell).unwrap();
+                }
+                if address == self.cursor {
+                    canvas.set_draw_color(WHITE);
+                    canvas.draw_rect(cell).unwrap();
+                }
+                let color = if self.written.get(address).copied().unwrap_or(false) {
+                    WRITTEN_COLOR
+                } else if font_range.contains(&address) || program.contains(&address) {
+                    WHITE
+                } else {
+                    FREE_COLOR
+                };
+                glyphs.draw(canvas, font, &format!("{:02X}", byte), color, (x, y));
+                let ascii = if byte.is_ascii_graphic() {
+                    (byte as char).to_string()
+                } else {
+                    ".".to_string()
+                };
+                glyphs.draw(canvas, font, &ascii, color, (ascii_x, y));
+            }
+        }
+    }
+}
+
+fn hex_digit(scancode: Scancode) -> Option<u8> {
+    let name = scancode.name();
+    let name = name.strip_prefix("Keypad ").unwrap_or(name);
+    match name.len() {
+        1 => u8::from_str_radix(name, 16).ok(),
+        _ => None,
+    }
+}
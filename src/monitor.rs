@@ -1,12 +1,20 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 pub const COLS: usize = 64;
 pub const ROWS: usize = 32;
+pub const HIRES_COLS: usize = 128;
+pub const HIRES_ROWS: usize = 64;
 pub const SCALE: usize = 15;
 
 #[derive(Clone)]
 pub struct Monitor {
     cols: u8,
     rows: u8,
-    pub buffer: [u8; 2048],
+    // The XO-CHIP bitplanes drawing and clearing affect, bit 0 is plane 1 and bit 1 plane 2
+    planes: u8,
+    // Sized for the SUPER-CHIP hires mode, lores only uses the first COLS * ROWS pixels.
+    // Every pixel holds the bits of the planes it's set in, so its value is a color 0..=3
+    pub buffer: [u8; HIRES_COLS * HIRES_ROWS],
 }
 
 impl Monitor {
@@ -15,34 +23,145 @@ impl Monitor {
         Self {
             cols: COLS as u8,
             rows: ROWS as u8,
-            buffer: [0; COLS * ROWS],
+            planes: 1,
+            buffer: [0; HIRES_COLS * HIRES_ROWS],
         }
     }
     #[inline]
-    pub fn toggle_pixel(&mut self, mut x: usize, mut y: usize) -> bool {
-        x %= 64;
-        y %= 32;
-        self.buffer[x + (y * self.cols as usize)] ^= 1;
-        self.buffer[x + (y * self.cols as usize)] == 0
+    pub fn toggle_pixel(&mut self, x: usize, y: usize) -> bool {
+        self.toggle_plane_pixel(x, y, 1)
+    }
+
+    // Flips the pixel in the given plane, returns true if it was turned off
+    #[inline]
+    pub fn toggle_plane_pixel(&mut self, mut x: usize, mut y: usize, plane: u8) -> bool {
+        x %= self.cols as usize;
+        y %= self.rows as usize;
+        self.buffer[x + (y * self.cols as usize)] ^= plane;
+        self.buffer[x + (y * self.cols as usize)] & plane == 0
     }
 
+    // Clears the selected planes
     pub fn clear(&mut self) {
-        self.buffer = [0; COLS * ROWS]
+        for px in self.buffer.iter_mut() {
+            *px &= !self.planes;
+        }
+    }
+
+    #[inline]
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & 0b11;
+    }
+
+    // Switches between the 64x32 and the 128x64 SUPER-CHIP resolution, clearing the screen
+    pub fn set_hires(&mut self, hires: bool) {
+        if hires {
+            self.cols = HIRES_COLS as u8;
+            self.rows = HIRES_ROWS as u8;
+        } else {
+            self.cols = COLS as u8;
+            self.rows = ROWS as u8;
+        }
+        self.buffer = [0; HIRES_COLS * HIRES_ROWS];
+    }
+
+    // Moves the selected planes, pixels shifted in from outside of the screen are empty
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (cols, rows) = (self.cols as isize, self.rows as isize);
+        let source = self.buffer;
+        for y in 0..rows {
+            for x in 0..cols {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let shifted = if (0..cols).contains(&from_x) && (0..rows).contains(&from_y) {
+                    source[(from_x + from_y * cols) as usize]
+                } else {
+                    0
+                };
+                let px = &mut self.buffer[(x + y * cols) as usize];
+                *px = (*px & !self.planes) | (shifted & self.planes);
+            }
+        }
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
     }
 
     // Utility methods
+    #[inline]
+    pub fn get_planes(&self) -> u8 {
+        self.planes
+    }
+
+    #[inline]
+    pub fn is_hires(&self) -> bool {
+        self.cols as usize == HIRES_COLS
+    }
+
+    #[inline]
+    pub fn get_res(&self) -> (usize, usize) {
+        (self.cols as usize, self.rows as usize)
+    }
+
+    // Hires pixels are drawn at half the size so the screen keeps its dimensions
+    #[inline]
+    pub fn get_scale(&self) -> usize {
+        if self.is_hires() {
+            SCALE / 2
+        } else {
+            SCALE
+        }
+    }
+
     #[inline]
     pub fn get_scaled_res(&self) -> (u32, u32) {
         (
-            ((self.cols as usize) * SCALE) as u32,
-            ((self.rows as usize) * SCALE) as u32,
+            ((self.cols as usize) * self.get_scale()) as u32,
+            ((self.rows as usize) * self.get_scale()) as u32,
         )
     }
 
     #[inline]
-    pub fn get_buffer(&self) -> [u8; 2048] {
+    pub fn get_buffer(&self) -> [u8; HIRES_COLS * HIRES_ROWS] {
         self.buffer
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.is_hires() as u8);
+        writer.u8(self.planes);
+        writer.bytes(&self.buffer);
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> Result<Self, StateError> {
+        let mut monitor = Monitor::new_default();
+        match reader.u8()? {
+            0 => {}
+            1 => monitor.set_hires(true),
+            _ => return Err(StateError::Invalid("resolution")),
+        }
+        let planes = reader.u8()?;
+        if planes > 0b11 {
+            return Err(StateError::Invalid("plane selection"));
+        }
+        monitor.select_planes(planes);
+        monitor.buffer = reader.array()?;
+        if monitor.buffer.iter().any(|px| *px > 0b11) {
+            return Err(StateError::Invalid("pixel"));
+        }
+        Ok(monitor)
+    }
 }
 
 #[cfg(test)]
@@ -54,7 +173,7 @@ mod tests {
         let mut monitor = Monitor::new_default();
         let mut i = 0;
         let mut j = 0;
-        for _ in monitor.buffer.clone().iter() {
+        for _ in 0..COLS * ROWS {
             monitor.toggle_pixel(j, i);
             j += 1;
             if j % 64 == 0 {
@@ -63,10 +182,10 @@ mod tests {
             }
         }
         let arr = [1; COLS * ROWS];
-        assert_eq!(monitor.buffer, arr);
+        assert_eq!(monitor.buffer[..COLS * ROWS], arr);
         monitor.clear();
-        assert_eq!(monitor.buffer, [0; 2048]);
-        for _ in monitor.buffer.clone().iter() {
+        assert_eq!(monitor.buffer, [0; HIRES_COLS * HIRES_ROWS]);
+        for _ in 0..COLS * ROWS {
             monitor.toggle_pixel(j, i);
             j += 1;
             if j % 64 == 0 {
@@ -75,7 +194,7 @@ mod tests {
             }
         }
         let arr = [1; COLS * ROWS];
-        assert_eq!(monitor.buffer, arr);
+        assert_eq!(monitor.buffer[..COLS * ROWS], arr);
     }
 
     #[test]
@@ -90,4 +209,57 @@ mod tests {
         monitor.toggle_pixel(127, 63);
         assert_eq!(monitor.buffer[63 + 31 * COLS], 1);
     }
+
+    #[test]
+    fn hires() {
+        let mut monitor = Monitor::new_default();
+        monitor.set_hires(true);
+        assert!(monitor.is_hires());
+        assert_eq!(monitor.get_scaled_res(), (896, 448));
+        monitor.toggle_pixel(127, 63);
+        assert_eq!(monitor.buffer[127 + 63 * HIRES_COLS], 1);
+        monitor.toggle_pixel(128, 64);
+        assert_eq!(monitor.buffer[0], 1);
+        monitor.set_hires(false);
+        assert_eq!(monitor.buffer[0], 0);
+    }
+
+    #[test]
+    fn scrolling() {
+        let mut monitor = Monitor::new_default();
+        monitor.toggle_pixel(10, 10);
+        monitor.scroll_down(4);
+        assert_eq!(monitor.buffer[10 + 14 * COLS], 1);
+        assert_eq!(monitor.buffer[10 + 10 * COLS], 0);
+        monitor.scroll_right(4);
+        assert_eq!(monitor.buffer[14 + 14 * COLS], 1);
+        monitor.scroll_left(4);
+        monitor.scroll_left(4);
+        assert_eq!(monitor.buffer[6 + 14 * COLS], 1);
+        monitor.scroll_left(8);
+        assert_eq!(monitor.buffer[..COLS * ROWS], [0; COLS * ROWS]);
+        monitor.toggle_pixel(10, 10);
+        monitor.scroll_up(10);
+        assert_eq!(monitor.buffer[10], 1);
+    }
+
+    #[test]
+    fn planes() {
+        let mut monitor = Monitor::new_default();
+        monitor.select_planes(0b11);
+        monitor.toggle_plane_pixel(3, 3, 2);
+        monitor.toggle_pixel(3, 3);
+        assert_eq!(monitor.buffer[3 + 3 * COLS], 3);
+        assert!(monitor.toggle_plane_pixel(3, 3, 2));
+        assert_eq!(monitor.buffer[3 + 3 * COLS], 1);
+        // Only the selected planes are scrolled and cleared
+        monitor.toggle_plane_pixel(3, 3, 2);
+        monitor.select_planes(2);
+        monitor.scroll_right(1);
+        assert_eq!(monitor.buffer[3 + 3 * COLS], 1);
+        assert_eq!(monitor.buffer[4 + 3 * COLS], 2);
+        monitor.clear();
+        assert_eq!(monitor.buffer[4 + 3 * COLS], 0);
+        assert_eq!(monitor.buffer[3 + 3 * COLS], 1);
+    }
 }
@@ -0,0 +1,389 @@
+use crate::chip8::Chip8;
+use crate::error::Chip8Error;
+use crate::quirks::Quirks;
+use crate::rng;
+use crate::state::{self, StateError, StateReader, StateWriter};
+use crate::timing::TimingMode;
+use std::fmt;
+
+/// The first bytes of every movie
+pub const MAGIC: [u8; 4] = *b"C8MV";
+/// Bumped whenever the layout changes, movies of other versions are rejected
+pub const VERSION: u16 = 1;
+/// How often a hash of the whole machine is stored, in frames
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+/// The keypad as the machine saw it at the start of a frame, see `Keyboard`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameInput {
+    pub keys: u16,
+    pub pressed: u16,
+    pub released: u16,
+}
+
+/// The input of every frame of a run, from power on, with everything needed to repeat
+/// it: the ROM, the quirks, the random number generator and the speed. Every
+/// `hash_interval` frames the state of the machine is hashed, playback compares its
+/// own hashes against them to notice when it has gone a different way.
+///
+/// The file is the magic, the version, the ROM hash, the quirks, the generator's id
+/// and seed, the timing mode and instructions per second, the hash interval, the frame
+/// count and the input of each frame, the hash count and each hash with its frame, and
+/// an FNV-1a checksum of everything before it. Numbers are little endian
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub rng_id: u8,
+    pub seed: u64,
+    pub timing: TimingMode,
+    pub ips: u32,
+    pub hash_interval: u32,
+    frames: Vec<FrameInput>,
+    // The frame each hash was taken after, in order
+    hashes: Vec<(u32, u64)>,
+}
+
+/// A recording played back into a machine that doesn't end up the way it did
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlaybackError {
+    Machine(Chip8Error),
+    // The machine's state after the frame doesn't hash to the recorded value
+    Desync {
+        frame: u32,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PlaybackError::Machine(err) => err.fmt(f),
+            PlaybackError::Desync {
+                frame,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Movie desynced at frame {} (hash {:016X}, recorded {:016X})",
+                frame, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlaybackError {}
+
+impl Movie {
+    /// Starts an empty movie with the machine's settings. The machine should be fresh
+    /// out of reset with the ROM loaded, that's where playback starts from too
+    pub fn new(chip8: &Chip8) -> Self {
+        Self {
+            rom_hash: chip8.get_rom_hash(),
+            quirks: chip8.quirks,
+            rng_id: chip8.get_rng_id(),
+            seed: chip8.get_seed(),
+            timing: chip8.get_timing(),
+            ips: chip8.get_ips(),
+            hash_interval: DEFAULT_HASH_INTERVAL,
+            frames: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// The number of recorded frames
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    #[inline]
+    pub fn frames(&self) -> &[FrameInput] {
+        &self.frames
+    }
+
+    /// Runs a frame and records the keypad the machine saw in it
+    pub fn record(&mut self, chip8: &mut Chip8) -> Result<(), Chip8Error> {
+        let keyboard = &chip8.keyboard;
+        let input = FrameInput {
+            keys: keyboard.get_keys(),
+            pressed: keyboard.pressed(),
+            released: keyboard.released(),
+        };
+        chip8.cycle()?;
+        self.frames.push(input);
+        let frame = self.frames.len() as u32;
+        if frame.is_multiple_of(self.hash_interval) {
+            self.hashes.push((frame, state_hash(chip8)));
+        }
+        Ok(())
+    }
+
+    /// Gives a fresh machine the settings the movie was recorded with
+    pub fn apply(&self, chip8: &mut Chip8) -> Result<(), StateError> {
+        if self.rom_hash != chip8.get_rom_hash() {
+            return Err(StateError::RomMismatch {
+                found: self.rom_hash,
+                expected: chip8.get_rom_hash(),
+            });
+        }
+        let rng = rng::from_id(self.rng_id, self.seed)
+            .ok_or(StateError::Invalid("random number generator"))?;
+        chip8.set_quirks(self.quirks);
+        chip8.set_rng(rng);
+        chip8.set_ips(self.ips);
+        chip8.set_timing(self.timing);
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.bytes(&MAGIC);
+        writer.u16(VERSION);
+        writer.u64(self.rom_hash);
+        self.quirks.save_state(&mut writer);
+        writer.u8(self.rng_id);
+        writer.u64(self.seed);
+        writer.u8(match self.timing {
+            TimingMode::Instructions => 0,
+            TimingMode::Vip => 1,
+        });
+        writer.u32(self.ips);
+        writer.u32(self.hash_interval);
+        writer.u32(self.frames.len() as u32);
+        for input in &self.frames {
+            writer.u16(input.keys);
+            writer.u16(input.pressed);
+            writer.u16(input.released);
+        }
+        writer.u32(self.hashes.len() as u32);
+        for (frame, hash) in &self.hashes {
+            writer.u32(*frame);
+            writer.u64(*hash);
+        }
+        let checksum = state::fnv1a(&writer.bytes);
+        writer.u64(checksum);
+        writer.bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, StateError> {
+        let mut reader = StateReader::new(bytes);
+        if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion {
+                found: version,
+                expected: VERSION,
+            });
+        }
+        if bytes.len() < MAGIC.len() + 2 + 8 {
+            return Err(StateError::Truncated);
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 8);
+        if state::fnv1a(body) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(StateError::BadChecksum);
+        }
+        let mut reader = StateReader::new(&body[MAGIC.len() + 2..]);
+        let rom_hash = reader.u64()?;
+        let quirks = Quirks::load_state(&mut reader)?;
+        let rng_id = reader.u8()?;
+        let seed = reader.u64()?;
+        let timing = match reader.u8()? {
+            0 => TimingMode::Instructions,
+            1 => TimingMode::Vip,
+            _ => return Err(StateError::Invalid("timing mode")),
+        };
+        let ips = reader.u32()?;
+        let hash_interval = reader.u32()?;
+        if hash_interval == 0 {
+            return Err(StateError::Invalid("hash interval"));
+        }
+        let mut frames = Vec::new();
+        for _ in 0..reader.u32()? {
+            frames.push(FrameInput {
+                keys: reader.u16()?,
+                pressed: reader.u16()?,
+                released: reader.u16()?,
+            });
+        }
+        let mut hashes = Vec::new();
+        for _ in 0..reader.u32()? {
+            hashes.push((reader.u32()?, reader.u64()?));
+        }
+        if !reader.is_empty() {
+            return Err(StateError::Invalid("length"));
+        }
+        Ok(Movie {
+            rom_hash,
+            quirks,
+            rng_id,
+            seed,
+            timing,
+            ips,
+            hash_interval,
+            frames,
+            hashes,
+        })
+    }
+}
+
+/// Feeds a movie's input into a machine set up with `Movie::apply`, in place of live
+/// input, and checks the recorded hashes as it goes
+pub struct Playback {
+    movie: Movie,
+    // The next frame to play
+    frame: usize,
+    // The next hash to check
+    hash: usize,
+}
+
+impl Playback {
+    pub fn new(movie: Movie) -> Self {
+        Self {
+            movie,
+            frame: 0,
+            hash: 0,
+        }
+    }
+
+    /// The number of frames played so far
+    #[inline]
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    #[inline]
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Runs the next frame with the recorded input. Returns None once every frame has
+    /// been played. A desync is reported once, playback carries on after it
+    pub fn step(&mut self, chip8: &mut Chip8) -> Option<Result<(), PlaybackError>> {
+        let input = *self.movie.frames.get(self.frame)?;
+        chip8
+            .keyboard
+            .set_input(input.keys, input.pressed, input.released);
+        if let Err(err) = chip8.cycle() {
+            return Some(Err(PlaybackError::Machine(err)));
+        }
+        self.frame += 1;
+        let frame = self.frame as u32;
+        while self
+            .movie
+            .hashes
+            .get(self.hash)
+            .is_some_and(|(at, _)| *at < frame)
+        {
+            self.hash += 1;
+        }
+        match self.movie.hashes.get(self.hash) {
+            Some(&(at, expected)) if at == frame => {
+                self.hash += 1;
+                let found = state_hash(chip8);
+                if found != expected {
+                    return Some(Err(PlaybackError::Desync {
+                        frame,
+                        expected,
+                        found,
+                    }));
+                }
+                Some(Ok(()))
+            }
+            _ => Some(Ok(())),
+        }
+    }
+}
+
+/// The hash of everything in the machine's save state
+pub fn state_hash(chip8: &Chip8) -> u64 {
+    state::fnv1a(&chip8.save_state())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::Monitor;
+    use crate::rng::MemoryRng;
+
+    // Draws a random digit whenever key 5 is held
+    const ROM: [u8; 16] = [
+        0x65, 0x05, 0xE5, 0xA1, 0x12, 0x08, 0x12, 0x02, 0xC0, 0x0F, 0xF0, 0x29, 0xD1, 0x15, 0x12,
+        0x02,
+    ];
+
+    fn machine() -> Chip8 {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        chip8.load_sprites();
+        chip8.load_program(&ROM);
+        chip8
+    }
+
+    #[test]
+    fn recording_and_playback() {
+        let mut chip8 = machine();
+        chip8.set_quirks(Quirks::CHIP_48);
+        chip8.set_rng(Box::new(MemoryRng::new(1234)));
+        chip8.set_ips(500);
+        chip8.keyboard.set_input(0, 0, 0);
+        let mut movie = Movie::new(&chip8);
+        movie.hash_interval = 10;
+        for frame in 0..100 {
+            chip8.set_keys(if frame % 7 < 3 { 1 << 5 } else { 0 });
+            movie.record(&mut chip8).unwrap();
+        }
+        let end = state_hash(&chip8);
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(movie.len(), 100);
+        let mut replay = machine();
+        movie.apply(&mut replay).unwrap();
+        assert_eq!(replay.quirks, Quirks::CHIP_48);
+        let mut playback = Playback::new(movie.clone());
+        while let Some(result) = playback.step(&mut replay) {
+            result.unwrap();
+        }
+        assert_eq!(playback.frame(), 100);
+        assert_eq!(state_hash(&replay), end);
+
+        // Another seed goes another way, and that's noticed at the first hash after
+        let mut other = machine();
+        movie.apply(&mut other).unwrap();
+        other.set_rng(Box::new(MemoryRng::new(4321)));
+        let mut playback = Playback::new(movie);
+        let desync = std::iter::from_fn(|| playback.step(&mut other)).find_map(Result::err);
+        assert!(matches!(
+            desync,
+            Some(PlaybackError::Desync { frame: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn format_errors() {
+        let movie = Movie::new(&machine()).to_bytes();
+        assert!(Movie::from_bytes(&movie).is_ok());
+        let mut corrupted = movie.clone();
+        corrupted[10] ^= 1;
+        assert_eq!(Movie::from_bytes(&corrupted), Err(StateError::BadChecksum));
+        assert_eq!(Movie::from_bytes(b"C8ST"), Err(StateError::BadMagic));
+        let mut other = Chip8::new(Monitor::new_default(), Quirks::default());
+        other.load_program(&[0x12, 0x00]);
+        assert!(matches!(
+            Movie::from_bytes(&movie).unwrap().apply(&mut other),
+            Err(StateError::RomMismatch { .. })
+        ));
+    }
+}
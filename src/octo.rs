@@ -0,0 +1,1020 @@
+use crate::assembler::AsmError;
+use crate::disasm::ORIGIN;
+use crate::instruction::Instruction;
+use crate::symbols::SymbolMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+
+// How many macro expansions a program can go through before it's assumed to recurse forever
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// Compiles Octo source into a ROM that loads at 0x200.
+/// Like Octo, 0x200 holds a jump to the `main` label
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    compile_with_symbols(source).map(|(rom, _)| rom)
+}
+
+/// Compiles the source like `compile`, along with the labels and lines of the program
+pub fn compile_with_symbols(source: &str) -> Result<(Vec<u8>, SymbolMap), AsmError> {
+    Compiler::new(source).run(None)
+}
+
+/// Compiles the file, errors name it
+pub fn compile_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    compile_file_with_symbols(path).map(|(rom, _)| rom)
+}
+
+/// Compiles the file like `compile_file`, along with the labels and lines of the program
+pub fn compile_file_with_symbols(path: &Path) -> Result<(Vec<u8>, SymbolMap), AsmError> {
+    let file = Some(path.display().to_string());
+    let source = fs::read_to_string(path).map_err(|err| AsmError {
+        file: file.clone(),
+        line: 0,
+        column: 0,
+        message: format!("can't read the file: {}", err),
+    })?;
+    Compiler::new(&source)
+        .run(file.as_deref())
+        .map_err(|err| AsmError { file, ..err })
+}
+
+// A whitespace separated word of the source
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl Token<'_> {
+    fn error(&self, message: &str) -> AsmError {
+        AsmError {
+            file: None,
+            line: self.line,
+            column: self.column,
+            message: message.to_string(),
+        }
+    }
+}
+
+// Splits the source into words, # starts a comment running to the end of the line
+fn tokenize(source: &str) -> VecDeque<Token<'_>> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        let mut rest = code;
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let len = rest[start..]
+                .find(char::is_whitespace)
+                .unwrap_or(rest.len() - start);
+            tokens.push_back(Token {
+                text: &rest[start..start + len],
+                line: i + 1,
+                column: code.len() - rest.len() + start + 1,
+            });
+            rest = &rest[start + len..];
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+// Returns the number of the register v0..vf
+fn parse_register(text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v' | 'V'), Some(digit), None) => digit.to_digit(16).map(|x| x as usize),
+        _ => None,
+    }
+}
+
+// Something that stands for a number, labels can be used before they're defined
+enum Value<'a> {
+    Known(f64),
+    Forward(Token<'a>),
+}
+
+// What a placeholder left for a forward reference is filled with
+#[derive(Copy, Clone)]
+enum Fixup {
+    // The low 12 bits of an opcode
+    Address,
+    // A whole word, the operand of i := long
+    Long,
+}
+
+// A block of control flow waiting to be closed
+enum Flow<'a> {
+    // loop, with where it starts and the jumps out of it the whiles left
+    Loop {
+        token: Token<'a>,
+        start: u16,
+        exits: Vec<usize>,
+    },
+    // if ... begin, with the jump past the block
+    Begin {
+        token: Token<'a>,
+        jump: usize,
+    },
+    // else, with the jump past the else block
+    Else {
+        token: Token<'a>,
+        jump: usize,
+    },
+}
+
+struct Macro<'a> {
+    arguments: Vec<&'a str>,
+    body: Vec<Token<'a>>,
+}
+
+// A condition compiled into the instructions that set it up and the skip that tests it
+struct Condition {
+    setup: Vec<Instruction>,
+    // Skips the next instruction when the condition doesn't hold
+    skip_unless: Instruction,
+    // Skips the next instruction when it does
+    skip_if: Instruction,
+}
+
+struct Compiler<'a> {
+    tokens: VecDeque<Token<'a>>,
+    // The last token taken, for errors at the end of the source
+    last: Token<'a>,
+    memory: Vec<u8>,
+    written: Vec<bool>,
+    // Where the next byte goes
+    here: usize,
+    end: usize,
+    labels: HashMap<&'a str, u16>,
+    constants: HashMap<&'a str, f64>,
+    aliases: HashMap<&'a str, usize>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    fixups: Vec<(usize, Fixup, Token<'a>)>,
+    flow: Vec<Flow<'a>>,
+    expansions: usize,
+    // The address of everything emitted with the line it came from
+    lines: Vec<(u16, usize)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: &'a str) -> Self {
+        let start = Token {
+            text: "",
+            line: 1,
+            column: 1,
+        };
+        Compiler {
+            tokens: tokenize(source),
+            last: start,
+            memory: vec![0; 0x10000],
+            written: vec![false; 0x10000],
+            here: ORIGIN as usize,
+            end: ORIGIN as usize,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            expansions: 0,
+            lines: Vec::new(),
+        }
+    }
+
+    fn run(mut self, file: Option<&str>) -> Result<(Vec<u8>, SymbolMap), AsmError> {
+        // The jump to main, it isn't on any line of the source
+        let main = Token {
+            text: "main",
+            ..self.last
+        };
+        self.emit_address(0x1000, Value::Forward(main), main)?;
+        self.lines.clear();
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if let Some(flow) = self.flow.last() {
+            let (token, message) = match flow {
+                Flow::Loop { token, .. } => (token, "loop without again"),
+                Flow::Begin { token, .. } | Flow::Else { token, .. } => {
+                    (token, "begin without end")
+                }
+            };
+            return Err(token.error(message));
+        }
+        if !self.labels.contains_key("main") {
+            return Err(main.error("the program is missing a 'main' label"));
+        }
+        for (position, fixup, token) in std::mem::take(&mut self.fixups) {
+            let value = match self.lookup(token.text) {
+                Some(value) => value as i64,
+                None => return Err(token.error(&format!("undefined name '{}'", token.text))),
+            };
+            match fixup {
+                Fixup::Address => {
+                    let address = check(token, value, 0, 0xFFF, "address")? as u16;
+                    self.memory[position] |= (address >> 8) as u8;
+                    self.memory[position + 1] = address as u8;
+                }
+                Fixup::Long => {
+                    let address = check(token, value, 0, 0xFFFF, "address")? as u16;
+                    self.memory[position..position + 2].copy_from_slice(&address.to_be_bytes());
+                }
+            }
+        }
+        let mut symbols = SymbolMap::new();
+        for (name, address) in &self.labels {
+            symbols.add_label(name, *address);
+        }
+        for (address, line) in &self.lines {
+            symbols.add_line(*address, file, *line);
+        }
+        Ok((self.memory[ORIGIN as usize..self.end].to_vec(), symbols))
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = token;
+                Ok(token)
+            }
+            None => Err(self.last.error("unexpected end of the source")),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.front().map(|token| token.text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token<'a>, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(token.error(&format!("expected '{}'", text)));
+        }
+        Ok(token)
+    }
+
+    fn lookup(&self, name: &str) -> Option<f64> {
+        match self.labels.get(name) {
+            Some(address) => Some(*address as f64),
+            None => self.constants.get(name).copied(),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8], token: Token) -> Result<(), AsmError> {
+        if self.here + bytes.len() > self.memory.len() {
+            return Err(token.error("the program doesn't fit in memory"));
+        }
+        self.lines.push((self.here as u16, token.line));
+        for byte in bytes {
+            if self.written[self.here] {
+                return Err(token.error(&format!("overwrites {:#06X}", self.here)));
+            }
+            self.memory[self.here] = *byte;
+            self.written[self.here] = true;
+            self.here += 1;
+        }
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: Instruction, token: Token) -> Result<(), AsmError> {
+        self.emit(&instruction.encode().to_be_bytes(), token)
+    }
+
+    fn emit_address(&mut self, base: u16, value: Value<'a>, token: Token) -> Result<(), AsmError> {
+        let address = match value {
+            Value::Known(address) => check(token, address as i64, 0, 0xFFF, "address")?,
+            Value::Forward(name) => {
+                self.fixups.push((self.here, Fixup::Address, name));
+                0
+            }
+        };
+        self.emit(&(base | address as u16).to_be_bytes(), token)
+    }
+
+    // A jump whose target is filled in when the block it leaves is closed
+    fn emit_jump(&mut self, token: Token) -> Result<usize, AsmError> {
+        let position = self.here;
+        self.instruction(Instruction::Jp(0), token)?;
+        Ok(position)
+    }
+
+    fn patch_jump(&mut self, position: usize, token: Token) -> Result<(), AsmError> {
+        let target = check(token, self.here as i64, 0, 0xFFF, "address")? as u16;
+        let jump = Instruction::Jp(target).encode().to_be_bytes();
+        self.memory[position..position + 2].copy_from_slice(&jump);
+        Ok(())
+    }
+
+    fn define(&mut self, name: Token<'a>) -> Result<(), AsmError> {
+        let reserved = parse_number(name.text).is_some()
+            || parse_register(name.text).is_some()
+            || self.labels.contains_key(name.text)
+            || self.constants.contains_key(name.text)
+            || self.aliases.contains_key(name.text);
+        if reserved {
+            return Err(name.error(&format!("'{}' can't be redefined", name.text)));
+        }
+        Ok(())
+    }
+
+    fn register(&mut self) -> Result<usize, AsmError> {
+        let token = self.next()?;
+        self.as_register(token)
+            .ok_or_else(|| token.error("expected a register"))
+    }
+
+    fn as_register(&self, token: Token) -> Option<usize> {
+        parse_register(token.text).or_else(|| self.aliases.get(token.text).copied())
+    }
+
+    fn value(&mut self) -> Result<Value<'a>, AsmError> {
+        let token = self.next()?;
+        if token.text == "{" {
+            let value = self.calc_block()?;
+            return Ok(Value::Known(value));
+        }
+        if let Some(value) = parse_number(token.text).or_else(|| self.lookup(token.text)) {
+            return Ok(Value::Known(value));
+        }
+        if self.as_register(token).is_some() || token.text.starts_with(':') {
+            return Err(token.error(&format!("expected a value, found '{}'", token.text)));
+        }
+        Ok(Value::Forward(token))
+    }
+
+    // A value that has to be known where it's used
+    fn known(&mut self, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        let token = *self.tokens.front().unwrap_or(&self.last);
+        match self.value()? {
+            Value::Known(value) => check(token, value as i64, min, max, what),
+            Value::Forward(name) => Err(name.error(&format!("undefined name '{}'", name.text))),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        Ok(self.known(-0x80, 0xFF, "byte")? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        Ok(self.known(0, 0xF, "nibble")? as u8)
+    }
+
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+        match token.text {
+            ":" => {
+                let name = self.next()?;
+                self.define(name)?;
+                let address = check(name, self.here as i64, 0, 0xFFFF, "address")?;
+                self.labels.insert(name.text, address as u16);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                self.define(name)?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.next()?;
+                self.define(name)?;
+                let value = self.known(i64::MIN, i64::MAX, "value")?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.define(name)?;
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.constants.insert(name.text, value);
+            }
+            ":macro" => self.define_macro()?,
+            // Nothing below the origin ends up in the program
+            ":org" => {
+                self.here = self.known(ORIGIN as i64, 0xFFFF, "address")? as usize;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(&[byte], token)?;
+            }
+            "return" | ";" => self.instruction(Instruction::Ret, token)?,
+            "clear" => self.instruction(Instruction::Cls, token)?,
+            "hires" => self.instruction(Instruction::High, token)?,
+            "lores" => self.instruction(Instruction::Low, token)?,
+            "scroll-right" => self.instruction(Instruction::Scr, token)?,
+            "scroll-left" => self.instruction(Instruction::Scl, token)?,
+            "exit" => self.instruction(Instruction::Exit, token)?,
+            "audio" => self.instruction(Instruction::Audio, token)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(Instruction::Scd(n), token)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(Instruction::Scu(n), token)?;
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.instruction(Instruction::Plane(n), token)?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.instruction(Instruction::LdBVx(x), token)?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    match token.text {
+                        "save" => Instruction::Save { x, y },
+                        _ => Instruction::Load { x, y },
+                    }
+                } else {
+                    match token.text {
+                        "save" => Instruction::LdIVx(x),
+                        _ => Instruction::LdVxI(x),
+                    }
+                };
+                self.instruction(instruction, token)?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.instruction(Instruction::LdRVx(x), token)?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.instruction(Instruction::LdVxR(x), token)?;
+            }
+            "sprite" => {
+                let (x, y) = (self.register()?, self.register()?);
+                let n = self.nibble()?;
+                self.instruction(Instruction::Drw { x, y, n }, token)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let base = match token.text {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                let value = self.value()?;
+                self.emit_address(base, value, token)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instruction = match token.text {
+                    "delay" => Instruction::LdDtVx(x),
+                    "buzzer" => Instruction::LdStVx(x),
+                    _ => Instruction::Pitch(x),
+                };
+                self.instruction(instruction, token)?;
+            }
+            "i" => self.index(token)?,
+            "if" => self.conditional(token)?,
+            "else" => match self.flow.pop() {
+                Some(Flow::Begin { jump, .. }) => {
+                    let end = self.emit_jump(token)?;
+                    self.patch_jump(jump, token)?;
+                    self.flow.push(Flow::Else { token, jump: end });
+                }
+                _ => return Err(token.error("else without begin")),
+            },
+            "end" => match self.flow.pop() {
+                Some(Flow::Begin { jump, .. } | Flow::Else { jump, .. }) => {
+                    self.patch_jump(jump, token)?
+                }
+                _ => return Err(token.error("end without begin")),
+            },
+            "loop" => self.flow.push(Flow::Loop {
+                token,
+                start: self.here as u16,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                for instruction in condition.setup {
+                    self.instruction(instruction, token)?;
+                }
+                self.instruction(condition.skip_if, token)?;
+                let jump = self.emit_jump(token)?;
+                match self.flow.last_mut() {
+                    Some(Flow::Loop { exits, .. }) => exits.push(jump),
+                    _ => return Err(token.error("while outside of a loop")),
+                }
+            }
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop { start, exits, .. }) => {
+                    self.instruction(Instruction::Jp(start), token)?;
+                    for exit in exits {
+                        self.patch_jump(exit, token)?;
+                    }
+                }
+                _ => return Err(token.error("again without loop")),
+            },
+            _ if self.as_register(token).is_some() => self.assignment(token)?,
+            _ if self.macros.contains_key(token.text) => self.expand(token)?,
+            // Numbers on their own are data, names on their own are subroutine calls
+            _ => match parse_number(token.text) {
+                Some(value) => {
+                    let byte = check(token, value as i64, -0x80, 0xFF, "byte")? as u8;
+                    self.emit(&[byte], token)?;
+                }
+                None if token.text.starts_with(':') => {
+                    return Err(token.error(&format!("unknown directive '{}'", token.text)))
+                }
+                None => {
+                    self.tokens.push_front(token);
+                    let value = self.value()?;
+                    self.emit_address(0x2000, value, token)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, token: Token<'a>) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        match operator.text {
+            ":=" => match self.peek() {
+                Some("long") => {
+                    self.next()?;
+                    self.instruction(Instruction::LdILong, token)?;
+                    let address = match self.value()? {
+                        Value::Known(address) => {
+                            check(token, address as i64, 0, 0xFFFF, "address")?
+                        }
+                        Value::Forward(name) => {
+                            self.fixups.push((self.here, Fixup::Long, name));
+                            0
+                        }
+                    };
+                    self.emit(&(address as u16).to_be_bytes(), token)
+                }
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.instruction(Instruction::LdFVx(x), token)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.instruction(Instruction::LdHfVx(x), token)
+                }
+                _ => {
+                    let value = self.value()?;
+                    self.emit_address(0xA000, value, token)
+                }
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.instruction(Instruction::AddIVx(x), token)
+            }
+            _ => Err(operator.error("expected ':=' or '+='")),
+        }
+    }
+
+    fn assignment(&mut self, token: Token<'a>) -> Result<(), AsmError> {
+        let x = self.as_register(token).unwrap();
+        let operator = self.next()?;
+        let source = *self.tokens.front().unwrap_or(&self.last);
+        let y = self.as_register(source);
+        if y.is_some() {
+            self.next()?;
+        }
+        let instruction = match (operator.text, y) {
+            (":=", Some(y)) => Instruction::LdVxVy { x, y },
+            ("|=", Some(y)) => Instruction::Or { x, y },
+            ("&=", Some(y)) => Instruction::And { x, y },
+            ("^=", Some(y)) => Instruction::Xor { x, y },
+            ("+=", Some(y)) => Instruction::AddVxVy { x, y },
+            ("-=", Some(y)) => Instruction::Sub { x, y },
+            ("=-", Some(y)) => Instruction::Subn { x, y },
+            (">>=", Some(y)) => Instruction::Shr { x, y },
+            ("<<=", Some(y)) => Instruction::Shl { x, y },
+            (":=", None) => match source.text {
+                "delay" => {
+                    self.next()?;
+                    Instruction::LdVxDt(x)
+                }
+                "key" => {
+                    self.next()?;
+                    Instruction::LdVxK(x)
+                }
+                "random" => {
+                    self.next()?;
+                    Instruction::Rnd {
+                        x,
+                        byte: self.byte()?,
+                    }
+                }
+                _ => Instruction::LdVxByte {
+                    x,
+                    byte: self.byte()?,
+                },
+            },
+            ("+=", None) => Instruction::AddVxByte {
+                x,
+                byte: self.byte()?,
+            },
+            ("-=", None) => Instruction::AddVxByte {
+                x,
+                byte: self.byte()?.wrapping_neg(),
+            },
+            ("|=" | "&=" | "^=" | "=-" | ">>=" | "<<=", None) => {
+                return Err(source.error("expected a register"))
+            }
+            _ => return Err(operator.error(&format!("unknown operator '{}'", operator.text))),
+        };
+        self.instruction(instruction, token)
+    }
+
+    fn conditional(&mut self, token: Token<'a>) -> Result<(), AsmError> {
+        let condition = self.condition()?;
+        for instruction in &condition.setup {
+            self.instruction(*instruction, token)?;
+        }
+        let keyword = self.next()?;
+        match keyword.text {
+            // The skip jumps over the statement that follows
+            "then" => self.instruction(condition.skip_unless, token),
+            "begin" => {
+                self.instruction(condition.skip_if, token)?;
+                let jump = self.emit_jump(token)?;
+                self.flow.push(Flow::Begin { token, jump });
+                Ok(())
+            }
+            _ => Err(keyword.error("expected 'then' or 'begin'")),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        let keys = |skip_unless, skip_if| Condition {
+            setup: Vec::new(),
+            skip_unless,
+            skip_if,
+        };
+        match operator.text {
+            "key" => return Ok(keys(Instruction::Sknp(x), Instruction::Skp(x))),
+            "-key" => return Ok(keys(Instruction::Skp(x), Instruction::Sknp(x))),
+            _ => {}
+        }
+        let source = *self.tokens.front().unwrap_or(&self.last);
+        let (equal, not_equal, load) = match self.as_register(source) {
+            Some(y) => {
+                self.next()?;
+                (
+                    Instruction::SeVxVy { x, y },
+                    Instruction::SneVxVy { x, y },
+                    Instruction::LdVxVy { x: 0xF, y },
+                )
+            }
+            None => {
+                let byte = self.byte()?;
+                (
+                    Instruction::SeVxByte { x, byte },
+                    Instruction::SneVxByte { x, byte },
+                    Instruction::LdVxByte { x: 0xF, byte },
+                )
+            }
+        };
+        // The ordering comparisons subtract into VF and test the borrow flag
+        let flag = |subtract, holds| Condition {
+            setup: vec![load, subtract],
+            skip_unless: Instruction::SneVxByte {
+                x: 0xF,
+                byte: holds,
+            },
+            skip_if: Instruction::SeVxByte {
+                x: 0xF,
+                byte: holds,
+            },
+        };
+        let condition = match operator.text {
+            "==" => Condition {
+                setup: Vec::new(),
+                skip_unless: not_equal,
+                skip_if: equal,
+            },
+            "!=" => Condition {
+                setup: Vec::new(),
+                skip_unless: equal,
+                skip_if: not_equal,
+            },
+            // VF = x - y sets the flag when x >= y
+            ">=" => flag(Instruction::Subn { x: 0xF, y: x }, 1),
+            "<" => flag(Instruction::Subn { x: 0xF, y: x }, 0),
+            // VF = y - x sets the flag when x <= y
+            "<=" => flag(Instruction::Sub { x: 0xF, y: x }, 1),
+            ">" => flag(Instruction::Sub { x: 0xF, y: x }, 0),
+            _ => return Err(operator.error(&format!("unknown comparison '{}'", operator.text))),
+        };
+        Ok(condition)
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.next()?;
+        self.define(name)?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            arguments.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            match token.text {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.macros.insert(name.text, Macro { arguments, body });
+        Ok(())
+    }
+
+    // Replaces the call with the body of the macro, its arguments substituted
+    fn expand(&mut self, token: Token<'a>) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error("too many macro expansions, does the macro call itself?"));
+        }
+        let count = self.macros[token.text].arguments.len();
+        let mut values = HashMap::new();
+        for i in 0..count {
+            let value = self.next()?;
+            values.insert(self.macros[token.text].arguments[i], value);
+        }
+        let body = &self.macros[token.text].body;
+        for token in body.iter().rev() {
+            let token = values.get(token.text).copied().unwrap_or(*token);
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // Evaluates the expression up to the closing brace
+    fn calc_block(&mut self) -> Result<f64, AsmError> {
+        let value = self.calc()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    // Octo evaluates expressions right to left without precedence, parentheses group
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        let lhs = self.calc_term()?;
+        let operator = match self.peek() {
+            Some("}" | ")") | None => return Ok(lhs),
+            Some(_) => self.next()?,
+        };
+        let rhs = self.calc()?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        let result = match operator.text {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.wrapping_shl(b as u32) as f64,
+            ">>" => a.wrapping_shr(b as u32) as f64,
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return Err(operator.error(&format!("unknown operator '{}'", operator.text))),
+        };
+        if !result.is_finite() {
+            return Err(operator.error("the result isn't a finite number"));
+        }
+        Ok(result)
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        let unary: Option<fn(f64) -> f64> = match token.text {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| (v == 0.0) as i64 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term()?));
+        }
+        match token.text {
+            "(" => {
+                let value = self.calc()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            // The byte compiled so far at an address
+            "@" => {
+                let address = self.calc_term()? as i64;
+                let address = check(token, address, 0, 0xFFFF, "address")?;
+                Ok(self.memory[address as usize] as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => parse_number(token.text)
+                .or_else(|| self.lookup(token.text))
+                .ok_or_else(|| token.error(&format!("undefined name '{}'", token.text))),
+        }
+    }
+}
+
+fn check(token: Token, value: i64, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+    if value < min || value > max {
+        return Err(token.error(&format!("{} {} is out of range", what, value)));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+    use crate::monitor::Monitor;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn statements() {
+        let source = "
+            : main
+                clear
+                v0 := 5  v1 := v0  v1 += -1  va -= 2
+                i := shape
+                sprite v0 v1 4
+                draw             # a call to a label defined later
+                i := long shape
+                save v2 - v3
+                jump main
+            : draw ;
+            : shape 0xF0 0b10010000
+        ";
+        let rom = compile(source).unwrap();
+        assert_eq!(
+            rom,
+            [
+                0x12, 0x02, 0x00, 0xE0, 0x60, 0x05, 0x81, 0x00, 0x71, 0xFF, 0x7A, 0xFE, 0xA2, 0x1C,
+                0xD0, 0x14, 0x22, 0x1A, 0xF0, 0x00, 0x02, 0x1C, 0x52, 0x32, 0x12, 0x02, 0x00, 0xEE,
+                0xF0, 0x90
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            : main
+                loop
+                    if v0 == 3 then v1 := 1
+                    while v2 key
+                    if v0 != v1 begin
+                        v0 += 1
+                    else
+                        v0 := 0
+                    end
+                again
+        ";
+        let (rom, symbols) = compile_with_symbols(source).unwrap();
+        assert_eq!(symbols.label("main"), Some(0x202));
+        assert_eq!(symbols.location(0x204).unwrap().line, 4);
+        assert_eq!(symbols.address(None, 7), Some(0x20E));
+        assert_eq!(
+            rom,
+            [
+                0x12, 0x02, 0x40, 0x03, 0x61, 0x01, 0xE2, 0x9E, 0x12, 0x16, 0x90, 0x10, 0x12, 0x12,
+                0x70, 0x01, 0x12, 0x14, 0x60, 0x00, 0x12, 0x02
+            ]
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        // V0 ends up 1 when the comparison holds and 2 when it doesn't
+        let holds = |x: u8, operator: &str, y: &str| {
+            let source = format!(
+                ": main v3 := {} v4 := 10 v0 := 2 if v3 {} {} then v0 := 1 loop again",
+                x, operator, y
+            );
+            let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+            chip8.load_program(&compile(&source).unwrap());
+            for _ in 0..16 {
+                chip8.step().unwrap();
+            }
+            match chip8.get_registers()[0] {
+                1 => true,
+                2 => false,
+                v0 => panic!("V0 is {} after '{}'", v0, source),
+            }
+        };
+        for y in ["10", "v4"] {
+            for (x, operator, expected) in [
+                (3, "<", true),
+                (10, "<", false),
+                (20, "<", false),
+                (3, ">", false),
+                (10, ">", false),
+                (20, ">", true),
+                (3, "<=", true),
+                (10, "<=", true),
+                (20, "<=", false),
+                (3, ">=", false),
+                (10, ">=", true),
+                (20, ">=", true),
+                (10, "==", true),
+                (3, "==", false),
+                (10, "!=", false),
+                (3, "!=", true),
+            ] {
+                assert_eq!(holds(x, operator, y), expected, "{} {} {}", x, operator, y);
+            }
+        }
+    }
+
+    #[test]
+    fn metaprogramming() {
+        let source = "
+            :alias x v4
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 + 1 }
+            :macro step register amount { register += amount }
+            : main
+                step x SPEED
+                x := DOUBLE
+                :org 0x300
+            : far
+                :byte { far >> 8 }
+                jump far
+        ";
+        let rom = compile(source).unwrap();
+        assert_eq!(rom[..6], [0x12, 0x02, 0x74, 0x03, 0x64, 0x09]);
+        assert_eq!(rom.len(), 0x103);
+        assert_eq!(rom[0x100..], [0x03, 0x13, 0x00]);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| {
+            let error = compile(source).unwrap_err();
+            (error.line, error.column, error.message)
+        };
+        assert_eq!(error("clear").2, "the program is missing a 'main' label");
+        assert_eq!(
+            error(": main\n  jump nowhere"),
+            (2, 8, "undefined name 'nowhere'".to_string())
+        );
+        assert_eq!(error(": main v0 := 256").1, 14);
+        assert_eq!(error(": main\nloop\n  v0 += 1").0, 2);
+        assert_eq!(error(": main end").2, "end without begin");
+        assert_eq!(error(": main : main").1, 10);
+        assert_eq!(
+            error(": main\n:org 0x100\n:byte 1"),
+            (2, 6, "address 256 is out of range".to_string())
+        );
+    }
+}
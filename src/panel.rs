@@ -0,0 +1,241 @@
+use crate::memview::Glyphs;
+use crate::{draw_text, SCREEN_W, WHITE};
+use chip_8::chip8::Chip8;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, TextureCreator};
+use sdl2::ttf::Font;
+use sdl2::video::{Window, WindowContext};
+
+pub const PANEL_WIDTH: u32 = 240;
+const PANEL_X: i32 = (SCREEN_W - PANEL_WIDTH) as i32;
+const LINE_HEIGHT: i32 = 26;
+// The width of a character of a value, they're drawn one by one so digits line up
+const ADVANCE: i32 = 13;
+// Where the values go after the labels, in each of the two columns
+const VALUE_X: i32 = 40;
+const COLUMN_WIDTH: i32 = 112;
+const LABEL_COLOR: Color = Color::RGBA(140, 140, 140, 255);
+// Values that changed in the last step
+const CHANGED_COLOR: Color = Color::RGBA(255, 170, 0, 255);
+const KEY_DOWN_COLOR: Color = Color::RGBA(40, 140, 60, 255);
+// The keypad as it's laid out on the COSMAC VIP
+const KEYPAD: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// What the panel shows of the machine, to tell what changed
+#[derive(Clone, PartialEq, Eq)]
+struct Snapshot {
+    registers: [u8; 16],
+    index: u16,
+    pc: u16,
+    stack: Vec<u16>,
+    delay: u8,
+    sound: u8,
+}
+
+impl Snapshot {
+    fn new(chip8: &Chip8) -> Self {
+        Self {
+            registers: *chip8.get_registers(),
+            index: chip8.get_index(),
+            pc: chip8.get_pc(),
+            stack: chip8.get_stack().to_vec(),
+            delay: chip8.get_delay_timer(),
+            sound: chip8.get_sound_timer(),
+        }
+    }
+}
+
+// The registers, stack, timers, keypad and the next instruction, down the right side
+pub struct RegisterPanel {
+    visible: bool,
+    // The machine as of the last update, and as of the one before it
+    last: Option<Snapshot>,
+    before: Option<Snapshot>,
+}
+
+impl RegisterPanel {
+    pub fn new() -> Self {
+        Self {
+            visible: true,
+            last: None,
+            before: None,
+        }
+    }
+
+    #[inline]
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    // Call it after every frame or step, the values that differ from the previous
+    // update are highlighted
+    pub fn update(&mut self, chip8: &Chip8) {
+        self.before = self.last.replace(Snapshot::new(chip8));
+    }
+
+    pub fn draw(
+        &self,
+        canvas: &mut Canvas<Window>,
+        font: &Font,
+        texture_creator: &TextureCreator<WindowContext>,
+        glyphs: &mut Glyphs,
+        chip8: &Chip8,
+    ) {
+        if !self.visible {
+            return;
+        }
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(20, 20, 20, 230));
+        canvas
+            .fill_rect(Rect::new(PANEL_X, 0, PANEL_WIDTH, 670))
+            .unwrap();
+        let now = Snapshot::new(chip8);
+        let before = self.before.as_ref().unwrap_or(&now);
+        let color = |changed: bool| if changed { CHANGED_COLOR } else { WHITE };
+        let mut row = 0;
+        let value = |canvas: &mut Canvas<Window>,
+                     glyphs: &mut Glyphs,
+                     (column, row): (i32, i32),
+                     label: &str,
+                     text: &str,
+                     color: Color| {
+            let x = PANEL_X + 8 + column * COLUMN_WIDTH;
+            let y = 8 + row * LINE_HEIGHT;
+            glyphs.draw(canvas, font, label, LABEL_COLOR, (x, y));
+            glyphs.draw_mono(canvas, font, text, color, (x + VALUE_X, y), ADVANCE);
+        };
+
+        value(
+            canvas,
+            glyphs,
+            (0, row),
+            "PC",
+            &format!("{:04X}", now.pc),
+            color(now.pc != before.pc),
+        );
+        value(
+            canvas,
+            glyphs,
+            (1, row),
+            "I",
+            &format!("{:04X}", now.index),
+            color(now.index != before.index),
+        );
+        row += 1;
+        // The instruction that runs next
+        let instruction = match chip8.peek() {
+            Ok(instruction) => instruction.to_string(),
+            Err(err) => err.to_string(),
+        };
+        let y = 8 + row * LINE_HEIGHT;
+        draw_text(
+            canvas,
+            font,
+            texture_creator,
+            &instruction,
+            WHITE,
+            (PANEL_X + 8, y),
+            LINE_HEIGHT as u32 - 4,
+        );
+        row += 1;
+
+        for x in 0..8 {
+            for column in 0..2 {
+                let register = x + column * 8;
+                let changed = now.registers[register] != before.registers[register];
+                value(
+                    canvas,
+                    glyphs,
+                    (column as i32, row),
+                    &format!("V{:X}", register),
+                    &format!("{:02X}", now.registers[register]),
+                    color(changed),
+                );
+            }
+            row += 1;
+        }
+
+        value(
+            canvas,
+            glyphs,
+            (0, row),
+            "DT",
+            &format!("{:3}", now.delay),
+            color(now.delay != before.delay),
+        );
+        value(
+            canvas,
+            glyphs,
+            (1, row),
+            "ST",
+            &format!("{:3}", now.sound),
+            color(now.sound != before.sound),
+        );
+        row += 1;
+
+        // The return addresses, the innermost last, in two columns of eight
+        value(
+            canvas,
+            glyphs,
+            (0, row),
+            "SP",
+            &format!("{:2}", now.stack.len()),
+            color(now.stack.len() != before.stack.len()),
+        );
+        row += 1;
+        for (depth, address) in now.stack.iter().enumerate() {
+            let changed = before.stack.get(depth) != Some(address);
+            let cell = ((depth / 8) as i32, row + (depth % 8) as i32);
+            let label = format!("{:X}", depth);
+            value(
+                canvas,
+                glyphs,
+                cell,
+                &label,
+                &format!("{:04X}", address),
+                color(changed),
+            );
+        }
+        row += 8;
+
+        // The keys held down are filled in
+        let keys = chip8.keyboard.get_keys();
+        for (line, keypad_row) in KEYPAD.iter().enumerate() {
+            for (column, key) in keypad_row.iter().enumerate() {
+                let x = PANEL_X + 8 + column as i32 * 30;
+                let y = 8 + (row + line as i32) * LINE_HEIGHT;
+                let cell = Rect::new(x, y, 28, LINE_HEIGHT as u32 - 2);
+                if keys & (1 << key) != 0 {
+                    canvas.set_draw_color(KEY_DOWN_COLOR);
+                    canvas.fill_rect(cell).unwrap();
+                }
+                canvas.set_draw_color(LABEL_COLOR);
+                canvas.draw_rect(cell).unwrap();
+                glyphs.draw(canvas, font, &format!("{:X}", key), WHITE, (x + 8, y));
+            }
+        }
+        row += 4;
+
+        let seed = format!("Seed {}", chip8.get_seed());
+        let y = 8 + row * LINE_HEIGHT;
+        draw_text(
+            canvas,
+            font,
+            texture_creator,
+            &seed,
+            LABEL_COLOR,
+            (PANEL_X + 8, y),
+            LINE_HEIGHT as u32 - 8,
+        );
+    }
+}
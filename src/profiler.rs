@@ -0,0 +1,315 @@
+use crate::chip8::Chip8;
+use crate::instruction::Instruction;
+use crate::symbols::SymbolMap;
+use crate::timing::{self, TimingMode};
+use crate::trace::Trace;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// How often the instruction at an address ran and what it cost
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressProfile {
+    pub instruction: Instruction,
+    pub hits: u64,
+    pub cycles: u64,
+}
+
+/// What a subroutine cost, by itself and with the subroutines it called
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubroutineProfile {
+    pub calls: u64,
+    pub self_cycles: u64,
+    pub total_cycles: u64,
+}
+
+// The last instruction seen, what it cost is known once the next one shows where it went
+struct Pending {
+    instruction: Instruction,
+    registers: [u8; 16],
+    pc: u16,
+    timing: TimingMode,
+}
+
+/// Counts how often every address runs and where the cycles go, by subroutine as 2NNN
+/// enters and 00EE leaves them. A cycle is an instruction, or a VIP machine cycle with
+/// VIP timing. Set it with `Chip8::set_tracer`, shared to read the results
+#[derive(Default)]
+pub struct Profiler {
+    addresses: BTreeMap<u16, AddressProfile>,
+    // The cycles spent with each chain of calls, outermost first
+    stacks: BTreeMap<Vec<u16>, u64>,
+    calls: BTreeMap<u16, u64>,
+    // The subroutines entered and not left yet, starting with the program itself
+    frames: Vec<u16>,
+    instructions: u64,
+    cycles: u64,
+    draw_instructions: u64,
+    draw_cycles: u64,
+    // How the last instruction was timed, for the report
+    vip_timing: bool,
+    pending: Option<Pending>,
+}
+
+fn name(address: u16, symbols: Option<&SymbolMap>) -> String {
+    symbols
+        .and_then(|symbols| symbols.label_at(address))
+        .map_or_else(|| format!("{:04X}", address), str::to_string)
+}
+
+#[inline]
+fn percent(part: u64, whole: u64) -> f64 {
+    part as f64 * 100.0 / whole.max(1) as f64
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    #[inline]
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The instructions and cycles that went to DXYN
+    #[inline]
+    pub fn draws(&self) -> (u64, u64) {
+        (self.draw_instructions, self.draw_cycles)
+    }
+
+    #[inline]
+    pub fn address(&self, address: u16) -> Option<&AddressProfile> {
+        self.addresses.get(&address)
+    }
+
+    /// The subroutines by their address, the program itself included
+    pub fn subroutines(&self) -> BTreeMap<u16, SubroutineProfile> {
+        let mut subroutines: BTreeMap<u16, SubroutineProfile> = BTreeMap::new();
+        for (stack, cycles) in &self.stacks {
+            if let Some(innermost) = stack.last() {
+                subroutines.entry(*innermost).or_default().self_cycles += cycles;
+            }
+            // A recursive subroutine only counts once
+            for address in stack.iter().collect::<BTreeSet<_>>() {
+                subroutines.entry(*address).or_default().total_cycles += cycles;
+            }
+        }
+        for (address, calls) in &self.calls {
+            subroutines.entry(*address).or_default().calls = *calls;
+        }
+        subroutines
+    }
+
+    // Books the last instruction, now that the PC after it is known
+    fn settle(&mut self, next_pc: Option<u16>) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let cycles = match pending.timing {
+            TimingMode::Instructions => 1,
+            TimingMode::Vip => {
+                let skipped = next_pc == Some(pending.pc.wrapping_add(4));
+                timing::vip_cycles(pending.instruction, &pending.registers, skipped) as u64
+            }
+        };
+        self.vip_timing = pending.timing == TimingMode::Vip;
+        self.instructions += 1;
+        self.cycles += cycles;
+        let address = self.addresses.entry(pending.pc).or_insert(AddressProfile {
+            instruction: pending.instruction,
+            hits: 0,
+            cycles: 0,
+        });
+        address.hits += 1;
+        address.cycles += cycles;
+        if let Instruction::Drw { .. } = pending.instruction {
+            self.draw_instructions += 1;
+            self.draw_cycles += cycles;
+        }
+        // A call is paid for by the caller and a return by the subroutine
+        *self.stacks.entry(self.frames.clone()).or_default() += cycles;
+        match pending.instruction {
+            Instruction::Call(address) => {
+                self.frames.push(address);
+                *self.calls.entry(address).or_default() += 1;
+            }
+            Instruction::Ret if self.frames.len() > 1 => {
+                self.frames.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// The totals, the subroutines by the cycles spent in them with what they called and
+    /// the addresses by their cycles. Labels name the subroutines when there are symbols
+    pub fn report(&self, symbols: Option<&SymbolMap>) -> String {
+        let mut report = String::new();
+        let unit = if self.vip_timing {
+            "VIP machine cycles"
+        } else {
+            "one per instruction"
+        };
+        let _ = writeln!(
+            report,
+            "Instructions: {}, cycles: {} ({})",
+            self.instructions, self.cycles, unit
+        );
+        let _ = writeln!(
+            report,
+            "DXYN: {} instructions ({:.1}%), {} cycles ({:.1}%)",
+            self.draw_instructions,
+            percent(self.draw_instructions, self.instructions),
+            self.draw_cycles,
+            percent(self.draw_cycles, self.cycles)
+        );
+
+        let _ = writeln!(
+            report,
+            "\n{:<24} {:>10} {:>12} {:>7} {:>12} {:>7}",
+            "Subroutine", "Calls", "Self", "Self%", "Total", "Total%"
+        );
+        let mut subroutines: Vec<_> = self.subroutines().into_iter().collect();
+        subroutines.sort_by_key(|(address, profile)| (Reverse(profile.total_cycles), *address));
+        for (address, profile) in subroutines {
+            let _ = writeln!(
+                report,
+                "{:<24} {:>10} {:>12} {:>6.1}% {:>12} {:>6.1}%",
+                name(address, symbols),
+                profile.calls,
+                profile.self_cycles,
+                percent(profile.self_cycles, self.cycles),
+                profile.total_cycles,
+                percent(profile.total_cycles, self.cycles)
+            );
+        }
+
+        let _ = writeln!(
+            report,
+            "\n{:<7} {:>10} {:>12} {:>7}  Instruction",
+            "Address", "Hits", "Cycles", "Cycles%"
+        );
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(address, profile)| (Reverse(profile.cycles), **address));
+        for (address, profile) in addresses {
+            let _ = writeln!(
+                report,
+                "{:04X}    {:>10} {:>12} {:>6.1}%  {}",
+                address,
+                profile.hits,
+                profile.cycles,
+                percent(profile.cycles, self.cycles),
+                profile.instruction
+            );
+        }
+        report
+    }
+
+    /// A line per chain of calls with the cycles spent in its innermost subroutine, like
+    /// `main;update;draw_score 1234`, the collapsed stacks that flame graph tools read
+    pub fn collapsed_stacks(&self, symbols: Option<&SymbolMap>) -> String {
+        let mut text = String::new();
+        for (stack, cycles) in &self.stacks {
+            let names: Vec<String> = stack
+                .iter()
+                .map(|address| name(*address, symbols))
+                .collect();
+            let _ = writeln!(text, "{} {}", names.join(";"), cycles);
+        }
+        text
+    }
+}
+
+impl Trace for Profiler {
+    fn trace(&mut self, chip8: &Chip8, instruction: Instruction) {
+        self.settle(Some(chip8.get_pc()));
+        if self.frames.is_empty() {
+            self.frames.push(chip8.program_range().start as u16);
+        }
+        // Loading a state can leave the machine with fewer calls than were followed
+        self.frames.truncate(chip8.get_stack().len() + 1);
+        self.pending = Some(Pending {
+            instruction,
+            registers: *chip8.get_registers(),
+            pc: chip8.get_pc(),
+            timing: chip8.get_timing(),
+        });
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.settle(None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::Monitor;
+    use crate::quirks::Quirks;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn profile() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        // main: CALL sub, DRW V0, V0, 1, JP 0x204. sub: LD V0, 1, RET
+        chip8.load_program(&[0x22, 0x06, 0xD0, 0x01, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE]);
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        chip8.set_tracer(Some(Box::new(profiler.clone())));
+        for _ in 0..8 {
+            chip8.step().unwrap();
+        }
+        chip8.set_tracer(None).unwrap().finish().unwrap();
+
+        let profiler = profiler.borrow();
+        assert_eq!((profiler.instructions(), profiler.cycles()), (8, 8));
+        assert_eq!(profiler.draws(), (1, 1));
+        assert_eq!(profiler.address(0x204).unwrap().hits, 4);
+        assert_eq!(profiler.address(0x20A), None);
+        let subroutines = profiler.subroutines();
+        let main = &subroutines[&0x200];
+        assert_eq!((main.calls, main.self_cycles, main.total_cycles), (0, 6, 8));
+        let sub = &subroutines[&0x206];
+        assert_eq!((sub.calls, sub.self_cycles, sub.total_cycles), (1, 2, 2));
+
+        let mut symbols = SymbolMap::new();
+        symbols.add_label("main", 0x200);
+        symbols.add_label("sub", 0x206);
+        assert_eq!(
+            profiler.collapsed_stacks(Some(&symbols)),
+            "main 6\nmain;sub 2\n"
+        );
+        assert_eq!(profiler.collapsed_stacks(None), "0200 6\n0200;0206 2\n");
+        let report = profiler.report(Some(&symbols));
+        assert!(report.contains("DXYN: 1 instructions (12.5%), 1 cycles (12.5%)"));
+        assert!(report.lines().any(|line| line.starts_with("main ")));
+    }
+
+    #[test]
+    fn vip_cycles() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        chip8.set_timing(TimingMode::Vip);
+        // SE V0, 0 skips the LD V1, 1 and JP 0x200 loops
+        chip8.load_program(&[0x30, 0x00, 0x61, 0x01, 0x12, 0x00]);
+        let mut profiler = Profiler::new();
+        for _ in 0..4 {
+            profiler.trace(&chip8, chip8.peek().unwrap());
+            chip8.step().unwrap();
+        }
+        profiler.finish().unwrap();
+        let skip = Instruction::SeVxByte { x: 0, byte: 0 };
+        let jump = Instruction::Jp(0x200);
+        let registers = [0; 16];
+        let skip_cycles = timing::vip_cycles(skip, &registers, true) as u64;
+        let jump_cycles = timing::vip_cycles(jump, &registers, false) as u64;
+        assert_eq!(profiler.address(0x200).unwrap().cycles, skip_cycles * 2);
+        assert_eq!(profiler.address(0x202), None);
+        assert_eq!(profiler.cycles(), (skip_cycles + jump_cycles) * 2);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// How FX55/FX65 leave the index register after a store/load
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexIncrement {
+    // I += X + 1, the original COSMAC VIP behaviour
+    XPlusOne,
+    // I += X, the CHIP-48 off-by-one
+    X,
+    // I is left untouched
+    Unchanged,
+}
+
+/// The behaviour of the instructions that differ between CHIP-8 implementations
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub shift_vy: bool,
+    // What FX55/FX65 do with I afterwards
+    pub index_increment: IndexIncrement,
+    // BXNN jumps to XNN + VX instead of BNNN jumping to NNN + V0
+    pub jump_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub vf_reset: bool,
+    // DXYN clips sprites at the screen edges instead of wrapping them around
+    pub clip_sprites: bool,
+    // DXYN waits for the vertical blank, so at most one sprite is drawn per frame
+    pub display_wait: bool,
+    // In hires mode DXYN sets VF to the number of rows that collided or were clipped at
+    // the bottom, like SUPER-CHIP 1.1, instead of a collision flag
+    pub collision_rows: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_vy: true,
+        index_increment: IndexIncrement::XPlusOne,
+        jump_vx: false,
+        vf_reset: true,
+        clip_sprites: true,
+        display_wait: true,
+        collision_rows: false,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_vy: false,
+        index_increment: IndexIncrement::X,
+        jump_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+        collision_rows: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_vy: false,
+        index_increment: IndexIncrement::Unchanged,
+        jump_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+        collision_rows: true,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_vy: true,
+        index_increment: IndexIncrement::XPlusOne,
+        jump_vx: false,
+        vf_reset: false,
+        clip_sprites: false,
+        display_wait: false,
+        collision_rows: false,
+    };
+
+    /// The named presets
+    pub const PRESETS: [(&'static str, Quirks); 4] = [
+        ("COSMAC VIP", Quirks::COSMAC_VIP),
+        ("CHIP-48", Quirks::CHIP_48),
+        ("SUPER-CHIP 1.1", Quirks::SUPER_CHIP),
+        ("XO-CHIP", Quirks::XO_CHIP),
+    ];
+
+    /// Returns the name of the preset these quirks match, if any
+    pub fn name(&self) -> Option<&'static str> {
+        Self::PRESETS
+            .iter()
+            .find(|(_, quirks)| quirks == self)
+            .map(|(name, _)| *name)
+    }
+}
+
+impl Quirks {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        let flags = [
+            self.shift_vy,
+            self.jump_vx,
+            self.vf_reset,
+            self.clip_sprites,
+            self.display_wait,
+            self.collision_rows,
+        ];
+        let bits = flags
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, flag)| bits | (*flag as u8) << i);
+        writer.u8(bits);
+        writer.u8(self.index_increment as u8);
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> Result<Quirks, StateError> {
+        let bits = reader.u8()?;
+        if bits >> 6 != 0 {
+            return Err(StateError::Invalid("quirks"));
+        }
+        let index_increment = match reader.u8()? {
+            0 => IndexIncrement::XPlusOne,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::Unchanged,
+            _ => return Err(StateError::Invalid("index increment quirk")),
+        };
+        Ok(Quirks {
+            shift_vy: bits & 1 != 0,
+            index_increment,
+            jump_vx: bits & 2 != 0,
+            vf_reset: bits & 4 != 0,
+            clip_sprites: bits & 8 != 0,
+            display_wait: bits & 16 != 0,
+            collision_rows: bits & 32 != 0,
+        })
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COSMAC_VIP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_names() {
+        assert_eq!(Quirks::CHIP_48.name(), Some("CHIP-48"));
+        assert_eq!(Quirks::default().name(), Some("COSMAC VIP"));
+        let custom = Quirks {
+            display_wait: false,
+            ..Quirks::COSMAC_VIP
+        };
+        assert_eq!(custom.name(), None);
+    }
+
+    #[test]
+    fn saving() {
+        for (_, quirks) in Quirks::PRESETS {
+            let mut writer = StateWriter::default();
+            quirks.save_state(&mut writer);
+            let mut reader = StateReader::new(&writer.bytes);
+            assert_eq!(Quirks::load_state(&mut reader), Ok(quirks));
+        }
+    }
+}
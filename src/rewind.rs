@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+/// A minute of frames at 60 FPS
+pub const DEFAULT_CAPACITY: usize = 60 * 60;
+
+/// The last frames of the machine as save states. Only the newest is kept whole,
+/// the older ones are run length encoded XOR deltas that lead back from it,
+/// so a frame that changes little costs a few bytes
+pub struct Rewind {
+    capacity: usize,
+    current: Option<Vec<u8>>,
+    // Each delta turns a state into the one before it, the newest is at the back
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            current: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Records the state of a new frame
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(current) = self.current.take() {
+            // States of another size can't be diffed, the history starts over
+            if current.len() == state.len() {
+                self.deltas.push_back(encode(&current, &state));
+                if self.deltas.len() > self.capacity {
+                    self.deltas.pop_front();
+                }
+            } else {
+                self.deltas.clear();
+            }
+        }
+        self.current = Some(state);
+    }
+
+    /// Goes back a frame and returns its state, the frames after it are forgotten
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let current = self.current.as_mut()?;
+        decode(&delta, current);
+        Some(current)
+    }
+
+    /// The number of frames that can be stepped back
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+    }
+
+    /// The bytes the history takes up
+    pub fn size(&self) -> usize {
+        let current = self.current.as_ref().map_or(0, |state| state.len());
+        current + self.deltas.iter().map(|delta| delta.len()).sum::<usize>()
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+// XORs the states and encodes the result as runs of unchanged bytes, each followed by
+// the changed bytes after it
+fn encode(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < old.len() {
+        let start = i;
+        while i < old.len() && old[i] == new[i] {
+            i += 1;
+        }
+        let unchanged = i - start;
+        let start = i;
+        while i < old.len() && old[i] != new[i] {
+            i += 1;
+        }
+        write_varint(&mut out, unchanged);
+        write_varint(&mut out, i - start);
+        out.extend(old[start..i].iter().zip(&new[start..i]).map(|(a, b)| a ^ b));
+    }
+    out
+}
+
+// Applies a delta made by encode
+fn decode(delta: &[u8], state: &mut [u8]) {
+    let mut position = 0;
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position);
+        let changed = read_varint(delta, &mut position);
+        for byte in &mut state[i..i + changed] {
+            *byte ^= delta[position];
+            position += 1;
+        }
+        i += changed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stepping_back() {
+        let mut rewind = Rewind::new(3);
+        let mut state = vec![0; 1000];
+        for frame in 0..5u8 {
+            state[frame as usize * 100] = frame + 1;
+            state[999] = frame;
+            rewind.push(state.clone());
+        }
+        assert_eq!(rewind.len(), 3);
+        assert!(rewind.size() < 1000 + 3 * 16);
+        let previous = rewind.step_back().unwrap().to_vec();
+        assert_eq!(previous[400], 0);
+        assert_eq!(previous[300], 4);
+        assert_eq!(previous[999], 3);
+        rewind.step_back();
+        let oldest = rewind.step_back().unwrap();
+        assert_eq!(oldest[100], 2);
+        assert_eq!(oldest[200], 0);
+        assert_eq!(oldest[999], 1);
+        assert!(rewind.step_back().is_none());
+    }
+
+    #[test]
+    fn deltas() {
+        let old = [1, 2, 3, 4, 5, 6];
+        let new = [1, 9, 9, 4, 5, 7];
+        let delta = encode(&old, &new);
+        let mut state = new;
+        decode(&delta, &mut state);
+        assert_eq!(state, old);
+        assert_eq!(encode(&old, &old), [6, 0]);
+    }
+}
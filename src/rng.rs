@@ -0,0 +1,258 @@
+/// Where CXNN gets its random bytes from. Generators are seeded, so a run can be repeated
+/// by giving the same seed again, and expose their state for save states
+pub trait Rng {
+    /// The next random byte. Memory is the machine's, for generators that mix it in
+    fn next_byte(&mut self, memory: &[u8]) -> u8;
+    /// Called by the 60 Hz interrupt, when the timers tick
+    fn tick(&mut self) {}
+    /// The seed the generator started from
+    fn seed(&self) -> u64;
+    /// Tells the generators apart in save states
+    fn id(&self) -> u8;
+    /// Everything needed to continue the sequence from where it is now
+    fn state(&self) -> u64;
+    fn restore(&mut self, seed: u64, state: u64);
+}
+
+/// The default generator, xorshift64*
+pub struct XorShift {
+    seed: u64,
+    state: u64,
+}
+
+impl XorShift {
+    pub const ID: u8 = 0;
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            state: Self::initial_state(seed),
+        }
+    }
+
+    // Xorshift gets stuck on 0, so the seed is scrambled with a splitmix64 step first
+    fn initial_state(seed: u64) -> u64 {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)).max(1)
+    }
+}
+
+impl Rng for XorShift {
+    fn next_byte(&mut self, _memory: &[u8]) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn restore(&mut self, seed: u64, state: u64) {
+        self.seed = seed;
+        self.state = state.max(1);
+    }
+}
+
+/// A generator in the spirit of the COSMAC VIP interpreter, which read bytes of its own
+/// code for CXNN. Every CXNN moves a pointer one byte further through the first page of
+/// the machine's memory, where the fonts are, and mixes the byte found there into the
+/// previous result. It doesn't give the values a VIP would, `VipRng` does, only a short
+/// period that depends on what's in memory
+pub struct MemoryRng {
+    seed: u64,
+    pointer: u8,
+    last: u8,
+}
+
+impl MemoryRng {
+    pub const ID: u8 = 1;
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            pointer: seed as u8,
+            last: (seed >> 8) as u8,
+        }
+    }
+}
+
+impl Rng for MemoryRng {
+    fn next_byte(&mut self, memory: &[u8]) -> u8 {
+        self.pointer = self.pointer.wrapping_add(1);
+        let byte = memory.get(self.pointer as usize).copied().unwrap_or(0);
+        self.last = self.last.wrapping_add(byte).rotate_right(1) ^ self.pointer;
+        self.last
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn state(&self) -> u64 {
+        (self.pointer as u64) << 8 | self.last as u64
+    }
+
+    fn restore(&mut self, seed: u64, state: u64) {
+        self.seed = seed;
+        self.pointer = (state >> 8) as u8;
+        self.last = state as u8;
+    }
+}
+
+// The second page of the COSMAC VIP's CHIP-8 interpreter, 0100 to 01FF, which CXNN
+// reads as its source of randomness. CXNN itself is at 01D8
+const VIP_INTERPRETER_PAGE: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x45, 0xA3, 0x98, 0x56, 0xD4, 0xF8, 0x81, 0xBC, 0xF8, 0x95, 0xAC, 0x22,
+    0xDC, 0x12, 0x56, 0xD4, 0x06, 0xB8, 0xD4, 0x06, 0xA8, 0xD4, 0x64, 0x0A, 0x01, 0xE6, 0x8A, 0xF4,
+    0xAA, 0x3B, 0x28, 0x9A, 0xFC, 0x01, 0xBA, 0xD4, 0xF8, 0x81, 0xBA, 0x06, 0xFA, 0x0F, 0xAA, 0x0A,
+    0xAA, 0xD4, 0xE6, 0x06, 0xBF, 0x93, 0xBE, 0xF8, 0x1B, 0xAE, 0x2A, 0x1A, 0xF8, 0x00, 0x5A, 0x0E,
+    0xF5, 0x3B, 0x4B, 0x56, 0x0A, 0xFC, 0x01, 0x5A, 0x30, 0x40, 0x4E, 0xF6, 0x3B, 0x3C, 0x9F, 0x56,
+    0x2A, 0x2A, 0xD4, 0x00, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x07, 0x5A, 0x87, 0xF3, 0x17, 0x1A,
+    0x3A, 0x5B, 0x12, 0xD4, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x0A, 0x57, 0x87, 0xF3, 0x17, 0x1A,
+    0x3A, 0x6B, 0x12, 0xD4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45, 0xA5, 0x86, 0xFA, 0x0F,
+    0xB5, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x82, 0x15, 0x15, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x88, 0xD4,
+    0x45, 0x07, 0x30, 0x8C, 0x45, 0x07, 0x30, 0x84, 0xE6, 0x62, 0x26, 0x45, 0xA3, 0x36, 0x88, 0xD4,
+    0x3E, 0x88, 0xD4, 0xF8, 0xF0, 0xA7, 0xE7, 0x45, 0xF4, 0xA5, 0x86, 0xFA, 0x0F, 0x3B, 0xB2, 0xFC,
+    0x01, 0xB5, 0xD4, 0x45, 0x56, 0xD4, 0x45, 0xE6, 0xF4, 0x56, 0xD4, 0x45, 0xFA, 0x0F, 0x3A, 0xC4,
+    0x07, 0x56, 0xD4, 0xAF, 0x22, 0xF8, 0xD3, 0x73, 0x8F, 0xF9, 0xF0, 0x52, 0xE6, 0x07, 0xD2, 0x56,
+    0xF8, 0xFF, 0xA6, 0xF8, 0x00, 0x7E, 0x56, 0xD4, 0x19, 0x89, 0xAE, 0x93, 0xBE, 0x99, 0xEE, 0xF4,
+    0x56, 0x76, 0xE6, 0xF4, 0xB9, 0x56, 0x45, 0xF2, 0x56, 0xD4, 0x45, 0xAA, 0x86, 0xFA, 0x0F, 0xBA,
+    0xD4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x4B, 0x00,
+];
+
+/// The COSMAC VIP interpreter's own CXNN. Its state is the 1802's R9, which the 60 Hz
+/// interrupt increments as well. CXNN increments it again, adds the interpreter byte at
+/// 01 R9.0 to R9.1, then adds that sum shifted right through the carry to the sum. The
+/// result is the new R9.1 and, masked with NN, VX. The seed is the starting R9
+pub struct VipRng {
+    seed: u64,
+    r9: u16,
+}
+
+impl VipRng {
+    pub const ID: u8 = 2;
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            r9: seed as u16,
+        }
+    }
+}
+
+impl Rng for VipRng {
+    fn next_byte(&mut self, _memory: &[u8]) -> u8 {
+        // INC R9, GLO R9, PLO RE, GHI R3, PHI RE, GHI R9, SEX RE, ADD
+        self.r9 = self.r9.wrapping_add(1);
+        let byte = VIP_INTERPRETER_PAGE[self.r9 as u8 as usize];
+        let (sum, carry) = ((self.r9 >> 8) as u8).overflowing_add(byte);
+        // STR R6, SHRC, SEX R6, ADD, PHI R9
+        let shifted = sum >> 1 | (carry as u8) << 7;
+        let result = shifted.wrapping_add(sum);
+        self.r9 = (result as u16) << 8 | self.r9 & 0xFF;
+        result
+    }
+
+    fn tick(&mut self) {
+        self.r9 = self.r9.wrapping_add(1);
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn state(&self) -> u64 {
+        self.r9 as u64
+    }
+
+    fn restore(&mut self, seed: u64, state: u64) {
+        self.seed = seed;
+        self.r9 = state as u16;
+    }
+}
+
+/// Creates the generator with the given id, as stored in save states
+pub fn from_id(id: u8, seed: u64) -> Option<Box<dyn Rng>> {
+    match id {
+        XorShift::ID => Some(Box::new(XorShift::new(seed))),
+        MemoryRng::ID => Some(Box::new(MemoryRng::new(seed))),
+        VipRng::ID => Some(Box::new(VipRng::new(seed))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeatable() {
+        let memory = [0x5A; 256];
+        for id in [XorShift::ID, MemoryRng::ID, VipRng::ID] {
+            let mut a = from_id(id, 42).unwrap();
+            let mut b = from_id(id, 42).unwrap();
+            let first: Vec<u8> = (0..64).map(|_| a.next_byte(&memory)).collect();
+            let second: Vec<u8> = (0..64).map(|_| b.next_byte(&memory)).collect();
+            assert_eq!(first, second);
+            assert!(first.iter().any(|byte| *byte != first[0]));
+
+            // Restoring continues the same sequence
+            let (seed, state) = (a.seed(), a.state());
+            let next = a.next_byte(&memory);
+            let mut c = from_id(id, 0).unwrap();
+            c.restore(seed, state);
+            assert_eq!(c.next_byte(&memory), next);
+            assert_eq!(c.seed(), 42);
+        }
+        let mut other = XorShift::new(43);
+        let mut xorshift = XorShift::new(42);
+        assert_ne!(
+            (0..8).map(|_| other.next_byte(&[])).collect::<Vec<_>>(),
+            (0..8).map(|_| xorshift.next_byte(&[])).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn vip() {
+        let sequence = |rng: &mut VipRng, len| (0..len).map(|_| rng.next_byte(&[])).collect();
+        // R9 = 0000 reads 0101 to 0103, which are zeros, before it gets to 0104
+        let mut rng = VipRng::new(0);
+        let bytes: Vec<u8> = sequence(&mut rng, 12);
+        assert_eq!(
+            bytes,
+            [0x00, 0x00, 0x00, 0x67, 0x8F, 0xBA, 0x98, 0x22, 0xA7, 0xBC, 0x34, 0xC2]
+        );
+        assert_eq!(rng.state(), 0xC20C);
+        let bytes: Vec<u8> = sequence(&mut VipRng::new(0x1234), 8);
+        assert_eq!(bytes, [0xF7, 0x8F, 0x4A, 0x97, 0xE7, 0x99, 0x0C, 0x86]);
+        // The interrupt moves R9 along, so CXNN reads 01D9 instead of 01D8
+        assert_eq!(VipRng::new(0xD7).next_byte(&[]), 0x25);
+        let mut rng = VipRng::new(0xD7);
+        rng.tick();
+        assert_eq!(rng.next_byte(&[]), 0xCD);
+        // R9.0 carries into R9.1 before the byte is added
+        let mut rng = VipRng::new(0x00FF);
+        assert_eq!(rng.next_byte(&[]), 0x01);
+        assert_eq!(rng.state(), 0x0100);
+    }
+}
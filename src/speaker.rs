@@ -5,20 +5,48 @@ pub struct SquareWave {
     pub phase_inc: f32,
     pub phase: f32,
     pub volume: f32,
+    // XO-CHIP 128 bit pattern played instead of the square wave, and its rate in bits per sample
+    pub pattern: Option<[u8; 16]>,
+    pub pattern_inc: f32,
+    pub freq: i32,
+}
+
+impl SquareWave {
+    // Pitch 64 plays the pattern at 4000 bits per second, every 48 steps doubles it
+    pub fn set_pattern(&mut self, pattern: Option<[u8; 16]>, pitch: u8) {
+        self.pattern = pattern;
+        self.pattern_inc = 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0) / self.freq as f32;
+    }
 }
 
 impl AudioCallback for SquareWave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+        match self.pattern {
+            // Play the pattern bit by bit, MSB first
+            Some(pattern) => {
+                for x in out.iter_mut() {
+                    let bit = self.phase as usize % 128;
+                    *x = if pattern[bit / 8] & (0x80 >> (bit % 8)) > 0 {
+                        self.volume
+                    } else {
+                        -self.volume
+                    };
+                    self.phase = (self.phase + self.pattern_inc) % 128.0;
+                }
+            }
+            // Generate a square wave
+            None => {
+                for x in out.iter_mut() {
+                    *x = if self.phase <= 0.5 {
+                        self.volume
+                    } else {
+                        -self.volume
+                    };
+                    self.phase = (self.phase + self.phase_inc) % 1.0;
+                }
+            }
         }
     }
 }
@@ -38,6 +66,9 @@ pub fn init_speaker(audio_subsystem: AudioSubsystem) -> AudioDevice<SquareWave>
                 phase_inc: 60.0 / spec.freq as f32,
                 phase: 0.0,
                 volume: 0.05,
+                pattern: None,
+                pattern_inc: 4000.0 / spec.freq as f32,
+                freq: spec.freq,
             }
         })
         .unwrap()
@@ -0,0 +1,211 @@
+use std::fmt;
+
+/// The first bytes of every save state
+pub const MAGIC: [u8; 4] = *b"C8ST";
+/// Bumped whenever the layout changes, states of other versions are rejected
+pub const VERSION: u16 = 6;
+
+/// Why a save state couldn't be loaded. The machine is left untouched when this happens
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    // Not a save state at all
+    BadMagic,
+    UnsupportedVersion { found: u16, expected: u16 },
+    // The state was saved while a different ROM was loaded
+    RomMismatch { found: u64, expected: u64 },
+    BadChecksum,
+    Truncated,
+    // A field holds a value the machine can't be in
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion { found, expected } => write!(
+                f,
+                "Save state version {} is not supported, expected {}",
+                found, expected
+            ),
+            StateError::RomMismatch { found, expected } => write!(
+                f,
+                "Save state is for another ROM (hash {:016X}, loaded {:016X})",
+                found, expected
+            ),
+            StateError::BadChecksum => write!(f, "Save state is corrupted (bad checksum)"),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(field) => write!(f, "Save state has an invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// 64-bit FNV-1a, used for the ROM hash and the checksum
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01B3);
+    }
+    hash
+}
+
+/// Appends little endian fields to a state
+#[derive(Default)]
+pub struct StateWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    #[inline]
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    #[inline]
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+/// Reads the fields of a state back in the order they were written
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    #[inline]
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    #[inline]
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    #[inline]
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    #[inline]
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// Wraps the body of a state in the header and the checksum.
+/// The layout is the magic, the version, the ROM hash, the body and a checksum of
+/// everything before it
+pub fn seal(rom_hash: u64, body: &[u8]) -> Vec<u8> {
+    let mut writer = StateWriter::default();
+    writer.bytes(&MAGIC);
+    writer.u16(VERSION);
+    writer.u64(rom_hash);
+    writer.bytes(body);
+    let checksum = fnv1a(&writer.bytes);
+    writer.u64(checksum);
+    writer.bytes
+}
+
+/// Checks the header and the checksum of a state and returns its body
+pub fn open(state: &[u8], rom_hash: u64) -> Result<&[u8], StateError> {
+    let mut reader = StateReader::new(state);
+    if reader.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(StateError::BadMagic);
+    }
+    let version = reader.u16()?;
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion {
+            found: version,
+            expected: VERSION,
+        });
+    }
+    let found = reader.u64()?;
+    let header = MAGIC.len() + 2 + 8;
+    if state.len() < header + 8 {
+        return Err(StateError::Truncated);
+    }
+    let (sealed, checksum) = state.split_at(state.len() - 8);
+    if fnv1a(sealed) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(StateError::BadChecksum);
+    }
+    if found != rom_hash {
+        return Err(StateError::RomMismatch {
+            found,
+            expected: rom_hash,
+        });
+    }
+    Ok(&sealed[header..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealing() {
+        let state = seal(0x1234, &[1, 2, 3]);
+        assert_eq!(open(&state, 0x1234).unwrap(), [1, 2, 3]);
+        assert_eq!(
+            open(&state, 0x4321),
+            Err(StateError::RomMismatch {
+                found: 0x1234,
+                expected: 0x4321
+            })
+        );
+        let mut corrupted = state.clone();
+        corrupted[15] ^= 1;
+        assert_eq!(open(&corrupted, 0x1234), Err(StateError::BadChecksum));
+        let mut old = state.clone();
+        old[4] = 0;
+        assert!(matches!(
+            open(&old, 0x1234),
+            Err(StateError::UnsupportedVersion { found: 0, .. })
+        ));
+        assert_eq!(open(b"RIFF", 0x1234), Err(StateError::BadMagic));
+        assert_eq!(open(&state[..10], 0x1234), Err(StateError::Truncated));
+    }
+}
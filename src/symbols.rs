@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The line of source the code at an address was assembled from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// None when the source didn't come from a file
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+/// The labels of a program and the source line of each statement, written by the
+/// assembler and the Octo compiler so debuggers can work with the source.
+///
+/// As a file, every line is either `label <address> <name>` or
+/// `line <address> <line> [file]`, addresses in hex
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    labels: BTreeMap<String, u16>,
+    lines: BTreeMap<u16, SourceLocation>,
+}
+
+/// Where the symbol map of a ROM is kept, next to it
+pub fn path_for(rom: &Path) -> PathBuf {
+    rom.with_extension("sym")
+}
+
+// Whether two names refer to the same source file. Debuggers send absolute paths
+// while the map holds them as they were given to the assembler
+fn same_file(map: Option<&str>, file: Option<&str>) -> bool {
+    match (map, file) {
+        (None, _) | (_, None) => true,
+        (Some(map), Some(file)) => {
+            let (map, file) = (Path::new(map), Path::new(file));
+            map == file || map.ends_with(file) || file.ends_with(map)
+        }
+    }
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_label(&mut self, name: &str, address: u16) {
+        self.labels.insert(name.to_string(), address);
+    }
+
+    /// Notes that the code at the address comes from the line, the first line given for
+    /// an address wins
+    pub fn add_line(&mut self, address: u16, file: Option<&str>, line: usize) {
+        self.lines.entry(address).or_insert_with(|| SourceLocation {
+            file: file.map(str::to_string),
+            line,
+        });
+    }
+
+    #[inline]
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied()
+    }
+
+    /// The labels in order of their names
+    pub fn labels(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.labels
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+    }
+
+    /// The label of the address, the first by name when there are several
+    pub fn label_at(&self, address: u16) -> Option<&str> {
+        self.labels()
+            .find(|(_, at)| *at == address)
+            .map(|(name, _)| name)
+    }
+
+    /// The closest label at or before the address
+    pub fn label_before(&self, address: u16) -> Option<&str> {
+        self.labels()
+            .filter(|(_, at)| *at <= address)
+            .max_by_key(|(_, at)| *at)
+            .map(|(name, _)| name)
+    }
+
+    /// The line of the statement the address is part of
+    pub fn location(&self, address: u16) -> Option<&SourceLocation> {
+        self.lines
+            .range(..=address)
+            .next_back()
+            .map(|(_, location)| location)
+    }
+
+    /// The address of the first statement on the line, or on the first line after it
+    /// with code, like a breakpoint set on a comment
+    pub fn address(&self, file: Option<&str>, line: usize) -> Option<u16> {
+        self.lines
+            .iter()
+            .filter(|(_, location)| location.line >= line)
+            .filter(|(_, location)| same_file(location.file.as_deref(), file))
+            .min_by_key(|(address, location)| (location.line, **address))
+            .map(|(address, _)| *address)
+    }
+}
+
+impl fmt::Display for SymbolMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, address) in &self.labels {
+            writeln!(f, "label {:04X} {}", address, name)?;
+        }
+        for (address, location) in &self.lines {
+            write!(f, "line {:04X} {}", address, location.line)?;
+            match &location.file {
+                Some(file) => writeln!(f, " {}", file)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SymbolMap {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut map = SymbolMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let invalid = || format!("line {}: invalid symbol '{}'", i + 1, line);
+            let mut parts = line.splitn(4, ' ');
+            let kind = parts.next().unwrap_or_default();
+            let address = parts
+                .next()
+                .and_then(|address| u16::from_str_radix(address, 16).ok());
+            match (kind, address, parts.next()) {
+                ("", _, _) => {}
+                ("label", Some(address), Some(name)) if parts.next().is_none() => {
+                    map.add_label(name, address)
+                }
+                ("line", Some(address), Some(number)) => {
+                    let number = number.parse().map_err(|_| invalid())?;
+                    map.add_line(address, parts.next(), number);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let mut map = SymbolMap::new();
+        map.add_label("main", 0x200);
+        map.add_label("loop", 0x204);
+        map.add_line(0x200, Some("src/game.8o"), 3);
+        map.add_line(0x202, Some("src/game.8o"), 4);
+        map.add_line(0x204, Some("src/game.8o"), 7);
+        map.add_line(0x206, Some("src/font.8o"), 1);
+        map.add_line(0x20A, Some("src/game.8o"), 8);
+
+        assert_eq!(map.label("loop"), Some(0x204));
+        assert_eq!(map.label_at(0x200), Some("main"));
+        assert_eq!(map.label_before(0x206), Some("loop"));
+        assert_eq!(map.label_before(0x1FF), None);
+        // 0x208 is the second word of the statement at 0x206
+        assert_eq!(map.location(0x208).unwrap().line, 1);
+        assert_eq!(map.location(0x1FE), None);
+        assert_eq!(map.address(Some("/home/me/src/game.8o"), 4), Some(0x202));
+        assert_eq!(map.address(Some("game.8o"), 5), Some(0x204));
+        assert_eq!(map.address(Some("font.8o"), 1), Some(0x206));
+        assert_eq!(map.address(Some("game.8o"), 9), None);
+
+        let text = map.to_string();
+        assert_eq!(text.parse::<SymbolMap>().unwrap(), map);
+        assert!("label 200".parse::<SymbolMap>().is_err());
+        assert!("line 200 x".parse::<SymbolMap>().is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::instruction::Instruction;
+use std::time::Duration;
+
+/// The rate the delay and sound timers count down at, and the frame rate of the machine
+pub const TIMER_HZ: u32 = 60;
+/// The length of one frame, a tick of the timers
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / TIMER_HZ as u64);
+/// How many instructions run per second unless the user picks a rate
+pub const DEFAULT_IPS: u32 = 700;
+// When the frontend falls further behind than this, e.g. while the window is dragged,
+// the missed frames are dropped instead of being run all at once
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+/// Turns wall-clock time into whole frames. Time that doesn't add up to a frame is
+/// carried over to the next call, so frames come at 60 Hz on average however often
+/// the frontend's loop runs
+#[derive(Default)]
+pub struct FrameClock {
+    lag: Duration,
+}
+
+impl FrameClock {
+    /// Adds the time since the last call and returns how many frames are due
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.lag = (self.lag + elapsed).min(MAX_LAG);
+        let mut frames = 0;
+        while self.lag >= FRAME {
+            self.lag -= FRAME;
+            frames += 1;
+        }
+        frames
+    }
+}
+
+/// Spreads an instruction rate over the frames. The rate rarely divides evenly by 60,
+/// so the remainder is carried and some frames run one instruction more than others
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InstructionBudget {
+    ips: u32,
+    remainder: u32,
+}
+
+impl InstructionBudget {
+    pub fn new(ips: u32) -> Self {
+        Self { ips, remainder: 0 }
+    }
+
+    #[inline]
+    pub fn ips(&self) -> u32 {
+        self.ips
+    }
+
+    /// The part of an instruction carried into the next frame, in 1/60ths
+    #[inline]
+    pub fn remainder(&self) -> u32 {
+        self.remainder
+    }
+
+    pub fn set_remainder(&mut self, remainder: u32) {
+        self.remainder = remainder % TIMER_HZ;
+    }
+
+    /// The number of instructions to run this frame
+    pub fn next_frame(&mut self) -> u32 {
+        let total = self.remainder + self.ips;
+        self.remainder = total % TIMER_HZ;
+        total / TIMER_HZ
+    }
+}
+
+impl Default for InstructionBudget {
+    fn default() -> Self {
+        Self::new(DEFAULT_IPS)
+    }
+}
+
+/// How the machine decides how much to run per frame
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimingMode {
+    /// A fixed number of instructions per second, whatever they are
+    #[default]
+    Instructions,
+    /// Every instruction costs what it took on the COSMAC VIP's interpreter
+    Vip,
+}
+
+/// The VIP's 1802 runs at 1.7609 MHz and takes 8 clocks per machine cycle
+pub const VIP_CYCLES_PER_SECOND: u32 = 1_760_900 / 8;
+/// The machine cycles in one frame of the VIP
+pub const VIP_FRAME_CYCLES: u32 = VIP_CYCLES_PER_SECOND / TIMER_HZ;
+/// What the 60 Hz interrupt takes out of every frame: the CDP1861 steals 8 cycles for
+/// each of the 128 lines it shows through DMA, and the interrupt routine counts down
+/// the timers
+pub const VIP_INTERRUPT_CYCLES: u32 = 128 * 8 + 46;
+// Every instruction goes through the interpreter's fetch and dispatch loop first
+const VIP_FETCH_CYCLES: u32 = 40;
+
+/// The machine cycles an instruction took on the VIP. The registers are the ones from
+/// before the instruction ran, and skipped tells whether a skip instruction skipped.
+/// The counts follow the interpreter's code paths, instructions the VIP doesn't have
+/// are given the cost of a cheap one
+pub fn vip_cycles(instruction: Instruction, registers: &[u8; 16], skipped: bool) -> u32 {
+    let skip = if skipped { 4 } else { 0 };
+    let execute = match instruction {
+        // The clear loop writes the 256 bytes of the display one by one
+        Instruction::Cls => 24 + 256 * 12,
+        Instruction::Ret => 10,
+        Instruction::Jp(_) => 12,
+        Instruction::Call(_) => 26,
+        Instruction::SeVxByte { .. } | Instruction::SneVxByte { .. } => 10 + skip,
+        Instruction::SeVxVy { .. } | Instruction::SneVxVy { .. } => 14 + skip,
+        Instruction::LdVxByte { .. } => 6,
+        Instruction::AddVxByte { .. } => 10,
+        // 8XYN assembles the ALU instruction in RAM and runs it from there
+        Instruction::LdVxVy { .. } => 12,
+        Instruction::Or { .. }
+        | Instruction::And { .. }
+        | Instruction::Xor { .. }
+        | Instruction::AddVxVy { .. }
+        | Instruction::Sub { .. }
+        | Instruction::Shr { .. }
+        | Instruction::Subn { .. }
+        | Instruction::Shl { .. } => 44,
+        Instruction::LdI(_) => 12,
+        Instruction::JpV0(_) => 22,
+        Instruction::Rnd { .. } => 36,
+        Instruction::Drw { x, y, n } => vip_draw_cycles(registers[x], registers[y], n),
+        Instruction::Skp(_) | Instruction::Sknp(_) => 14 + skip,
+        Instruction::LdVxDt(_) | Instruction::LdDtVx(_) | Instruction::LdStVx(_) => 6,
+        // Each poll of the keypad while waiting
+        Instruction::LdVxK(_) => 8,
+        Instruction::AddIVx(_) => 12,
+        Instruction::LdFVx(_) => 16,
+        // The digits are found by repeated subtraction, so bigger ones take longer
+        Instruction::LdBVx(x) => {
+            let value = registers[x] as u32;
+            84 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        }
+        Instruction::LdIVx(x) | Instruction::LdVxI(x) => 14 + 14 * (x as u32 + 1),
+        _ => 10,
+    };
+    VIP_FETCH_CYCLES + execute
+}
+
+// Every row of the sprite is shifted into place one bit at a time, then XORed into one
+// display byte, or two when it isn't aligned. Rows below the screen are clipped
+fn vip_draw_cycles(x: u8, y: u8, n: u8) -> u32 {
+    let shift = (x % 8) as u32;
+    let rows = (n as u32).min(32 - (y % 32) as u32);
+    let row = 12 + 4 * shift + if shift == 0 { 28 } else { 50 };
+    68 + rows * row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let mut clock = FrameClock::default();
+        assert_eq!(clock.advance(Duration::from_millis(10)), 0);
+        assert_eq!(clock.advance(Duration::from_millis(10)), 1);
+        let mut total = 1;
+        for _ in 0..98 {
+            total += clock.advance(Duration::from_millis(10));
+        }
+        assert_eq!(total, 60);
+        // A long stall doesn't make it run seconds of frames at once
+        assert_eq!(clock.advance(Duration::from_secs(5)), 15);
+    }
+
+    #[test]
+    fn budget() {
+        let mut budget = InstructionBudget::new(700);
+        let counts: Vec<u32> = (0..TIMER_HZ).map(|_| budget.next_frame()).collect();
+        assert_eq!(counts.iter().sum::<u32>(), 700);
+        assert!(counts.iter().all(|count| *count == 11 || *count == 12));
+        assert_eq!(budget.remainder(), 0);
+    }
+
+    #[test]
+    fn vip_costs() {
+        let mut registers = [0; 16];
+        let draw = Instruction::Drw { x: 0, y: 1, n: 5 };
+        let aligned = vip_cycles(draw, &registers, false);
+        registers[0] = 3;
+        let unaligned = vip_cycles(draw, &registers, false);
+        assert!(unaligned > aligned);
+        // Clipped rows cost nothing
+        registers[1] = 30;
+        assert!(vip_cycles(draw, &registers, false) < unaligned);
+
+        let skip = Instruction::SeVxByte { x: 0, byte: 0 };
+        assert_eq!(
+            vip_cycles(skip, &registers, true),
+            vip_cycles(skip, &registers, false) + 4
+        );
+        let bcd = Instruction::LdBVx(0);
+        registers[0] = 199;
+        assert!(vip_cycles(bcd, &registers, false) > VIP_FETCH_CYCLES + 84 + 16 * 18);
+    }
+}
@@ -0,0 +1,507 @@
+use crate::chip8::Chip8;
+use crate::instruction::Instruction;
+use crate::state;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+
+/// The first line of every trace file, naming the fields
+pub const HEADER: &str =
+    "# cycle pc opcode v0 v1 v2 v3 v4 v5 v6 v7 v8 v9 va vb vc vd ve vf i sp dt st mem instruction";
+// How many full files are kept when a trace rotates, as <file>.1 (the newest) to <file>.3
+const BACKUPS: usize = 3;
+// The fields before the instruction, which can have spaces in it
+const FIELDS: usize = 24;
+
+/// Shown every instruction before it runs, set with `Chip8::set_tracer`
+pub trait Trace {
+    fn trace(&mut self, chip8: &Chip8, instruction: Instruction);
+    /// Called when tracing ends, reports anything that went wrong along the way
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A shared tracer, so what it found can be read while it's set
+impl<T: Trace> Trace for Rc<RefCell<T>> {
+    fn trace(&mut self, chip8: &Chip8, instruction: Instruction) {
+        self.borrow_mut().trace(chip8, instruction);
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.borrow_mut().finish()
+    }
+}
+
+// Several tracers at once, in order. All of them are finished, the first error is reported
+impl Trace for Vec<Box<dyn Trace>> {
+    fn trace(&mut self, chip8: &Chip8, instruction: Instruction) {
+        for tracer in self.iter_mut() {
+            tracer.trace(chip8, instruction);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for tracer in self.iter_mut() {
+            let finished = tracer.finish();
+            if result.is_ok() {
+                result = finished;
+            }
+        }
+        result
+    }
+}
+
+/// The machine right before an instruction runs, a line of a trace.
+///
+/// As text the fields are separated by single spaces, in the order of `HEADER`: the
+/// cycle in decimal padded to 10 digits, then PC, opcode, V0 to VF, I, SP, DT, ST and a
+/// hash of the memory in upper case hex of fixed width, then the instruction as the
+/// disassembler writes it. The hash is the low 32 bits of FNV-1a over all of memory, so
+/// the first line that differs in it follows the instruction that wrote something
+/// different. The cycle counts the instructions since tracing started, including the ones that
+/// were filtered out. Lines starting with # are comments. The format stays the same
+/// so traces of different runs and versions can be compared line by line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceLine {
+    pub cycle: u64,
+    pub pc: u16,
+    // The first word of the instruction, F000 NNNN has a second one
+    pub opcode: u16,
+    pub registers: [u8; 16],
+    pub index: u16,
+    pub stack_pointer: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub memory: u32,
+    pub instruction: String,
+}
+
+impl TraceLine {
+    pub fn new(
+        cycle: u64,
+        chip8: &Chip8,
+        instruction: Instruction,
+        memory_hash: &mut MemoryHash,
+    ) -> Self {
+        let pc = chip8.get_pc();
+        let memory = chip8.get_memory();
+        let byte = |address: u16| memory.get(address as usize).copied().unwrap_or(0);
+        Self {
+            cycle,
+            pc,
+            opcode: u16::from_be_bytes([byte(pc), byte(pc.wrapping_add(1))]),
+            registers: *chip8.get_registers(),
+            index: chip8.get_index(),
+            stack_pointer: chip8.get_stack().len() as u8,
+            delay_timer: chip8.get_delay_timer(),
+            sound_timer: chip8.get_sound_timer(),
+            memory: memory_hash.hash(memory),
+            instruction: instruction.to_string(),
+        }
+    }
+}
+
+/// Hashes memory for the trace lines. Hashing all of it every instruction is slow, so
+/// it's only hashed again when it changed since the last line
+#[derive(Default)]
+pub struct MemoryHash {
+    memory: Vec<u8>,
+    hash: u32,
+}
+
+impl MemoryHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hash(&mut self, memory: &[u8]) -> u32 {
+        if self.memory != memory {
+            self.memory.clear();
+            self.memory.extend_from_slice(memory);
+            self.hash = state::fnv1a(memory) as u32;
+        }
+        self.hash
+    }
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:010} {:04X} {:04X}", self.cycle, self.pc, self.opcode)?;
+        for register in self.registers {
+            write!(f, " {:02X}", register)?;
+        }
+        write!(
+            f,
+            " {:04X} {:02X} {:02X} {:02X} {:08X} {}",
+            self.index,
+            self.stack_pointer,
+            self.delay_timer,
+            self.sound_timer,
+            self.memory,
+            self.instruction
+        )
+    }
+}
+
+impl FromStr for TraceLine {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid trace line '{}'", line);
+        let fields: Vec<&str> = line.splitn(FIELDS + 1, ' ').collect();
+        if fields.len() != FIELDS + 1 {
+            return Err(invalid());
+        }
+        let hex = |i: usize| u16::from_str_radix(fields[i], 16).map_err(|_| invalid());
+        let byte = |i: usize| u8::from_str_radix(fields[i], 16).map_err(|_| invalid());
+        let mut registers = [0; 16];
+        for (x, register) in registers.iter_mut().enumerate() {
+            *register = byte(3 + x)?;
+        }
+        Ok(Self {
+            cycle: fields[0].parse().map_err(|_| invalid())?,
+            pc: hex(1)?,
+            opcode: hex(2)?,
+            registers,
+            index: hex(19)?,
+            stack_pointer: byte(20)?,
+            delay_timer: byte(21)?,
+            sound_timer: byte(22)?,
+            memory: u32::from_str_radix(fields[23], 16).map_err(|_| invalid())?,
+            instruction: fields[FIELDS].to_string(),
+        })
+    }
+}
+
+// <file>.<n>, the nth older part of a rotated trace
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Writes a trace to a file. Only instructions in the PC ranges are written, all of them
+/// without any. With a maximum size, a full file is moved to <file>.1, the one there to
+/// <file>.2 and so on, and the trace goes on in a new file
+pub struct Tracer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    ranges: Vec<RangeInclusive<u16>>,
+    max_size: Option<u64>,
+    // Bytes in the current file
+    size: u64,
+    cycle: u64,
+    memory_hash: MemoryHash,
+    // The first write that failed, nothing is written after it
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut tracer = Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(File::create(path)?),
+            ranges: Vec::new(),
+            max_size: None,
+            size: 0,
+            cycle: 0,
+            memory_hash: MemoryHash::new(),
+            error: None,
+        };
+        tracer.write_line(HEADER)?;
+        Ok(tracer)
+    }
+
+    pub fn add_range(&mut self, range: RangeInclusive<u16>) {
+        self.ranges.push(range);
+    }
+
+    /// The size a file can grow to before the trace moves on to a new one
+    pub fn set_max_size(&mut self, max_size: Option<u64>) {
+        self.max_size = max_size;
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for n in (1..BACKUPS).rev() {
+            let from = backup_path(&self.path, n);
+            if from.exists() {
+                fs::rename(from, backup_path(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, backup_path(&self.path, 1))?;
+        self.file = BufWriter::new(File::create(&self.path)?);
+        self.size = 0;
+        self.write_line(HEADER)
+    }
+
+    fn record(&mut self, line: &TraceLine) -> io::Result<()> {
+        let line = line.to_string();
+        let full = self
+            .max_size
+            .is_some_and(|max| self.size + line.len() as u64 + 1 > max);
+        if full {
+            self.rotate()?;
+        }
+        self.write_line(&line)
+    }
+}
+
+impl Trace for Tracer {
+    fn trace(&mut self, chip8: &Chip8, instruction: Instruction) {
+        let cycle = self.cycle;
+        self.cycle += 1;
+        let pc = chip8.get_pc();
+        let wanted = self.ranges.is_empty() || self.ranges.iter().any(|r| r.contains(&pc));
+        if self.error.is_some() || !wanted {
+            return;
+        }
+        let line = TraceLine::new(cycle, chip8, instruction, &mut self.memory_hash);
+        if let Err(err) = self.record(&line) {
+            self.error = Some(err);
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.file.flush(),
+        }
+    }
+}
+
+/// The lines of a trace, without the comments
+pub fn read(reader: impl BufRead) -> impl Iterator<Item = Result<TraceLine, String>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            line.as_ref()
+                .map_or(true, |line| !line.is_empty() && !line.starts_with('#'))
+        })
+        .map(|(i, line)| {
+            let line = line.map_err(|err| err.to_string())?;
+            line.parse()
+                .map_err(|err| format!("line {}: {}", i + 1, err))
+        })
+}
+
+/// The fields two lines differ in, named as in `HEADER`. The instruction is left out, it
+/// follows from the opcode and is written differently by other emulators
+pub fn differences(expected: &TraceLine, actual: &TraceLine, timers: bool) -> Vec<&'static str> {
+    const REGISTERS: [&str; 16] = [
+        "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7", "v8", "v9", "va", "vb", "vc", "vd", "ve",
+        "vf",
+    ];
+    let mut fields = Vec::new();
+    let mut compare = |name, differs: bool| {
+        if differs {
+            fields.push(name);
+        }
+    };
+    compare("cycle", expected.cycle != actual.cycle);
+    compare("pc", expected.pc != actual.pc);
+    compare("opcode", expected.opcode != actual.opcode);
+    for (x, name) in REGISTERS.iter().enumerate() {
+        compare(name, expected.registers[x] != actual.registers[x]);
+    }
+    compare("i", expected.index != actual.index);
+    compare("sp", expected.stack_pointer != actual.stack_pointer);
+    if timers {
+        compare("dt", expected.delay_timer != actual.delay_timer);
+        compare("st", expected.sound_timer != actual.sound_timer);
+    }
+    compare("mem", expected.memory != actual.memory);
+    fields
+}
+
+/// Where two traces stop agreeing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The instructions before it, as the expected trace has them
+    pub context: Vec<TraceLine>,
+    pub expected: TraceLine,
+    /// None when the actual trace ended first
+    pub actual: Option<TraceLine>,
+    pub fields: Vec<&'static str>,
+}
+
+/// Finds the first line where the traces differ, with up to `context` lines before it.
+/// Lines are paired by cycle and the expected trace sets which cycles are compared, so
+/// it can be one written for some PC ranges only. The actual trace can go on for longer
+pub fn compare(
+    expected: impl IntoIterator<Item = Result<TraceLine, String>>,
+    actual: impl IntoIterator<Item = Result<TraceLine, String>>,
+    context: usize,
+    timers: bool,
+) -> Result<Option<Divergence>, String> {
+    let mut actual = actual.into_iter();
+    let mut before = VecDeque::with_capacity(context + 1);
+    for expected in expected {
+        let expected = expected?;
+        // The cycles the expected trace left out are skipped
+        let actual = loop {
+            match actual.next().transpose()? {
+                Some(line) if line.cycle < expected.cycle => continue,
+                line => break line,
+            }
+        };
+        let fields = match &actual {
+            Some(actual) => differences(&expected, actual, timers),
+            None => Vec::new(),
+        };
+        if actual.is_none() || !fields.is_empty() {
+            return Ok(Some(Divergence {
+                context: before.into(),
+                expected,
+                actual,
+                fields,
+            }));
+        }
+        before.push_back(expected);
+        if before.len() > context {
+            before.pop_front();
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::Monitor;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn line_format() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        // LD VA, 0x05, LD I, 0x300, CALL 0x208
+        chip8.load_program(&[0x6A, 0x05, 0xA3, 0x00, 0x22, 0x08]);
+        chip8.step().unwrap();
+        chip8.step().unwrap();
+        let line = TraceLine::new(2, &chip8, chip8.peek().unwrap(), &mut MemoryHash::new());
+        let text = line.to_string();
+        let memory = state::fnv1a(chip8.get_memory()) as u32;
+        assert_eq!(
+            text,
+            format!(
+                "0000000002 0204 2208 00 00 00 00 00 00 00 00 00 00 05 00 00 00 00 00 0300 00 00 00 {:08X} CALL 0x208",
+                memory
+            )
+        );
+        assert_eq!(text.split(' ').count(), HEADER.split(' ').count());
+        assert_eq!(text.parse::<TraceLine>().unwrap(), line);
+        assert!("0000000002 0204 2208".parse::<TraceLine>().is_err());
+    }
+
+    #[test]
+    fn filters_and_rotation() {
+        let dir = std::env::temp_dir().join(format!("chip8-trace-rotation-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.log");
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        // A loop of ADD V0, 1 and JP 0x200
+        chip8.load_program(&[0x70, 0x01, 0x12, 0x00]);
+        let mut tracer = Tracer::create(&path).unwrap();
+        tracer.add_range(0x200..=0x200);
+        // Room for the header and three lines, which are all as long
+        let line = TraceLine::new(0, &chip8, chip8.peek().unwrap(), &mut MemoryHash::new());
+        let line_size = line.to_string().len() as u64 + 1;
+        tracer.set_max_size(Some(HEADER.len() as u64 + 1 + line_size * 3));
+        chip8.set_tracer(Some(Box::new(tracer)));
+        for _ in 0..10 {
+            chip8.step().unwrap();
+        }
+        chip8.set_tracer(None).unwrap().finish().unwrap();
+
+        let lines = |path: &Path| -> Vec<TraceLine> {
+            let text = fs::read_to_string(path).unwrap();
+            assert_eq!(text.lines().next(), Some(HEADER));
+            text.lines().skip(1).map(|l| l.parse().unwrap()).collect()
+        };
+        // Only the ADDs, every other cycle, with the newest in the current file
+        let current = lines(&path);
+        assert_eq!(current.len(), 2);
+        assert_eq!(current[0].cycle, 6);
+        assert_eq!(current[1].registers[0], 4);
+        assert!(current.iter().all(|line| line.pc == 0x200));
+        let older = lines(&backup_path(&path, 1));
+        assert_eq!(older.iter().map(|l| l.cycle).collect::<Vec<_>>(), [0, 2, 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn comparison() {
+        let mut chip8 = Chip8::new(Monitor::new_default(), Quirks::default());
+        // LD V0, 0x10, SHR V0, ADD V0, 1, JP 0x202
+        chip8.load_program(&[0x60, 0x10, 0x80, 0x06, 0x70, 0x01, 0x12, 0x02]);
+        let mut lines = Vec::new();
+        let mut memory_hash = MemoryHash::new();
+        for cycle in 0..6 {
+            let instruction = chip8.peek().unwrap();
+            lines.push(TraceLine::new(cycle, &chip8, instruction, &mut memory_hash));
+            chip8.step().unwrap();
+        }
+        let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        let text = format!("{}\n{}", HEADER, text);
+        let parsed: Vec<_> = read(text.as_bytes()).collect::<Result<_, _>>().unwrap();
+        assert_eq!(parsed, lines);
+
+        let ok = |lines: &[TraceLine]| lines.iter().cloned().map(Ok).collect::<Vec<_>>();
+        assert_eq!(compare(ok(&lines), ok(&lines), 2, true), Ok(None));
+        // The shift went wrong, and the timers are left out
+        let mut changed = lines.clone();
+        changed[2].registers[0] = 0x01;
+        changed[2].delay_timer = 1;
+        let divergence = compare(ok(&lines), ok(&changed), 2, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.context, lines[..2]);
+        assert_eq!(divergence.expected, lines[2]);
+        assert_eq!(divergence.fields, ["v0"]);
+        // Writing memory changes the hash the lines after it have
+        let hash = memory_hash.hash(chip8.get_memory());
+        chip8.write_memory(0x300, 1);
+        assert_ne!(memory_hash.hash(chip8.get_memory()), hash);
+        let mut changed = lines.clone();
+        changed[3].memory ^= 1;
+        let divergence = compare(ok(&lines), ok(&changed), 2, true).unwrap().unwrap();
+        assert_eq!(divergence.fields, ["mem"]);
+        // The actual trace ending early diverges, going on for longer doesn't
+        let divergence = compare(ok(&lines), ok(&lines[..4]), 0, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!((divergence.expected.cycle, divergence.actual), (4, None));
+        assert!(divergence.context.is_empty());
+        assert_eq!(compare(ok(&lines[..4]), ok(&lines), 2, true), Ok(None));
+        // Lines are paired by cycle, one missing from the actual trace diverges
+        let filtered: Vec<_> = lines
+            .iter()
+            .filter(|line| line.pc == 0x204)
+            .cloned()
+            .collect();
+        assert_eq!(compare(ok(&filtered), ok(&lines), 2, true), Ok(None));
+        let divergence = compare(ok(&lines), ok(&filtered), 2, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.expected.cycle, 0);
+        assert_eq!(divergence.fields[..2], ["cycle", "pc"]);
+        let invalid = vec![Err("line 2: invalid trace line".to_string())];
+        assert!(compare(invalid, ok(&lines), 2, true).is_err());
+    }
+}